@@ -1,6 +1,136 @@
+use std::{env, fs, path::PathBuf};
+
+/// the major versions there is a pgN feature for
+const VERSIONS: std::ops::RangeInclusive<u32> = 11..=16;
+
 fn main() {
+    // passed on to dependent build scripts as
+    // `DEP_POSTGRES_HEADERS_SUPPORTED_VERSIONS`, so they can declare the same
+    // `pg_ge_N` cfgs
+    let supported: Vec<_> = VERSIONS.map(|v| v.to_string()).collect();
+    println!("cargo:supported_versions={}", supported.join(","));
+
+    // declared even without a version, so the compile_error in lib.rs is not
+    // joined by unexpected cfg warnings
+    let mut cfgs: Vec<_> = VERSIONS.map(|v| format!("pg_ge_{}", v)).collect();
+    cfgs.push("pg_version_selected".to_string());
+    println!("cargo:rustc-check-cfg=cfg({})", cfgs.join(", "));
+
+    // lib.rs reports a missing version feature, or several, more clearly
+    let version = match selected_version() {
+        Some(version) => version,
+        None => return,
+    };
+    let version_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("version.rs");
+    emit_version(version, version_path);
+
+    #[cfg(any(feature = "parse_headers", feature = "c_shim"))]
+    let pg_include = install::pg_include(version);
+
     #[cfg(feature = "parse_headers")]
-    parse_headers::main()
+    parse_headers::main(version, &pg_include);
+
+    #[cfg(feature = "c_shim")]
+    c_shim::main(&pg_include);
+}
+
+/// the major version selected by the pgN feature, if exactly one is enabled
+fn selected_version() -> Option<u32> {
+    let mut selected = VERSIONS
+        .filter(|version| env::var_os(format!("CARGO_FEATURE_PG{}", version)).is_some());
+    match (selected.next(), selected.next()) {
+        (Some(version), None) => Some(version),
+        _ => None,
+    }
+}
+
+/// Emit `pg_version_selected`, a `pg_ge_N` cfg for every supported version up
+/// to and including `version`, the `version` module, and the major version as
+/// the `DEP_POSTGRES_HEADERS_MAJOR_VERSION` of dependent build scripts.
+fn emit_version(version: u32, out_path: PathBuf) {
+    println!("cargo:rustc-cfg=pg_version_selected");
+    for v in VERSIONS.filter(|&v| v <= version) {
+        println!("cargo:rustc-cfg=pg_ge_{}", v);
+    }
+    println!("cargo:major_version={}", version);
+
+    fs::write(out_path, format!(
+        "/// the major version the bindings are for\n\
+        pub const PG_MAJORVERSION: &str = \"{0}\";\n\
+        /// the major version the bindings are for, as a number\n\
+        pub const PG_MAJORVERSION_NUM: u32 = {0};\n",
+        version,
+    )).expect("Couldn't write version!");
+}
+
+/// finding the headers of the postgres install to build against
+#[cfg(any(feature = "parse_headers", feature = "c_shim"))]
+mod install {
+    use std::env;
+    use std::process::Command;
+
+    /// the server include dir of the install, after making sure it is the
+    /// selected version
+    pub fn pg_include(version: u32) -> String {
+        let pg_config = env::var("PG_CONFIG").unwrap_or_else(|_| "pg_config".to_string());
+        println!("cargo:rerun-if-env-changed=PG_CONFIG");
+        println!("cargo:rerun-if-env-changed=PG_INCLUDE_PATH");
+
+        check_version(&pg_config, version);
+
+        include_dir(&pg_config)
+            .expect(concat!("Could not find postgres install\n",
+                "\teither set PG_INCLUDE_PATH to the Postgres install include dir, e.g. PG_INCLUDE_PATH=/var/lib/pgsql/include/server\n",
+                "\tor set PG_CONFIG to the path to `pg_config`",
+            ))
+    }
+
+    /// make sure the headers are for the selected version, if pg_config can
+    /// tell us which version they are
+    fn check_version(pg_config: &str, version: u32) {
+        if env::var_os("PG_INCLUDE_PATH").is_some() {
+            return
+        }
+        let out = match Command::new(pg_config).arg("--version").output() {
+            Ok(out) => out,
+            Err(..) => return,
+        };
+        // e.g. "PostgreSQL 12.4"
+        let installed = String::from_utf8(out.stdout).unwrap();
+        let major = installed.split_whitespace()
+            .nth(1)
+            .and_then(|v| v.split(|c: char| !c.is_ascii_digit()).next())
+            .and_then(|v| v.parse::<u32>().ok());
+        if let Some(major) = major {
+            assert_eq!(major, version,
+                "the pg{} feature is enabled, but {} is {}", version, pg_config, installed.trim());
+        }
+    }
+
+    fn include_dir(pg_config: &str) -> Result<String, env::VarError> {
+        env::var("PG_INCLUDE_PATH").or_else(|err| {
+            match Command::new(pg_config).arg("--includedir-server").output() {
+                Ok(out) => Ok(String::from_utf8(out.stdout).unwrap().trim().to_string()),
+                Err(..) => Err(err),
+            }
+        })
+    }
+}
+
+/// compiles shim.c, see the `shim` module
+#[cfg(feature = "c_shim")]
+mod c_shim {
+    pub fn main(pg_include: &str) {
+        println!("cargo:rerun-if-changed=shim.c");
+        println!("cargo:rerun-if-changed=shim.h");
+
+        cc::Build::new()
+            .file("shim.c")
+            .include(pg_include)
+            // the postgres headers are not warning-free
+            .warnings(false)
+            .compile("pgrs_shim");
+    }
 }
 
 #[cfg(feature = "parse_headers")]
@@ -8,21 +138,14 @@ mod parse_headers {
     //based on https://github.com/bluejekyll/pg-extend-rs/blob/a8d637ca83475905b4799fbd123455c97b949a4a/pg-extend/build.rs
     use std::collections::HashSet;
     use std::env;
-    use std::path::PathBuf;
-    use std::process::Command;
+    use std::path::{Path, PathBuf};
 
-    pub fn main() {
+    pub fn main(version: u32, pg_include: &str) {
         let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("generated.rs");
-        let pg_config = env::var("PG_CONFIG").unwrap_or_else(|_| "pg_config".to_string());
 
         // Re-run this if wrapper.h changes
         println!("cargo:rerun-if-changed=wrapper.h");
-
-        let pg_include = include_dir(&pg_config)
-            .expect(concat!("Could not find postgres install\n",
-                "\teither set PG_INCLUDE_PATH to the Postgres install include dir, e.g. PG_INCLUDE_PATH=/var/lib/pgsql/include/server\n",
-                "\tor set PG_CONFIG to the path to `pg_config`",
-            ));
+        println!("cargo:rerun-if-env-changed=UPDATE_CACHED_BINDINGS");
 
         // these cause duplicate definition problems on linux
         // see: https://github.com/rust-lang/rust-bindgen/issues/687
@@ -39,7 +162,7 @@ mod parse_headers {
             .collect(),
         );
 
-        let bindings = get_bindings(&pg_include) // Gets initial bindings that are OS-dependant
+        let bindings = get_bindings(pg_include) // Gets initial bindings that are OS-dependant
         // The input header we would like to generate
         // bindings for.
         .header("wrapper.h")
@@ -56,8 +179,54 @@ mod parse_headers {
 
         // Write the bindings to the $OUT_DIR/postgres.rs file.
         bindings
-            .write_to_file(out_path)
+            .write_to_file(&out_path)
             .expect("Couldn't write bindings!");
+
+        // refresh the bindings used when parse_headers is disabled
+        update_cached(&out_path, &cached_name(version));
+
+        #[cfg(feature = "c_shim")]
+        shim_bindings(pg_include);
+    }
+
+    /// Declarations for the functions in shim.c. They only use the types
+    /// from the main bindings, so the same declarations work for every
+    /// version.
+    #[cfg(feature = "c_shim")]
+    fn shim_bindings(pg_include: &str) {
+        let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("shim.rs");
+        println!("cargo:rerun-if-changed=shim.h");
+
+        bindgen::Builder::default()
+            .clang_arg(format!("-I{}", pg_include))
+            .header("shim.h")
+            .whitelist_function("pgrs_.*")
+            .whitelist_type("pgrs_.*")
+            .whitelist_recursively(false)
+            .rustfmt_bindings(true)
+            .layout_tests(false)
+            .generate()
+            .expect("Unable to generate shim bindings")
+            .write_to_file(&out_path)
+            .expect("Couldn't write shim bindings!");
+
+        update_cached(&out_path, "shim.rs");
+    }
+
+    fn update_cached(out_path: &Path, name: &str) {
+        if env::var_os("UPDATE_CACHED_BINDINGS").is_some() {
+            let cached = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap())
+                .join("src/cached")
+                .join(name);
+            std::fs::copy(out_path, &cached).expect("Couldn't update cached bindings!");
+        }
+    }
+
+    fn cached_name(version: u32) -> String {
+        match env::var("CARGO_CFG_TARGET_OS").unwrap().as_str() {
+            "macos" => format!("macos_pg{}.rs", version),
+            _ => format!("linux_glibc_pg{}.rs", version),
+        }
     }
 
     #[cfg(windows)]
@@ -155,15 +324,6 @@ mod parse_headers {
         bindgen::Builder::default().clang_arg(format!("-I{}", pg_include))
     }
 
-    fn include_dir(pg_config: &str) -> Result<String, env::VarError> {
-        env::var("PG_INCLUDE_PATH").or_else(|err| {
-            match Command::new(pg_config).arg("--includedir-server").output() {
-                Ok(out) => Ok(String::from_utf8(out.stdout).unwrap().trim().to_string()),
-                Err(..) => Err(err),
-            }
-        })
-    }
-
     #[derive(Debug)]
     struct IgnoreMacros(HashSet<String>);
 
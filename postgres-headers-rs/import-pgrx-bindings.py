@@ -0,0 +1,131 @@
+#!/usr/bin/env python3
+"""Import the bindings pgrx-pg-sys ships for a postgres version as cached
+bindings, for versions we have no install to run `generate.sh` or
+`UPDATE_CACHED_BINDINGS=1` against.
+
+    ./import-pgrx-bindings.py <pgrx-pg-sys source dir> <major version>
+
+writes src/cached/linux_glibc_pg<major version>.rs. pgrx-pg-sys is MIT
+licensed, and generates its bindings with bindgen from a superset of the
+headers in wrapper.h; this strips its own additions back out:
+
+* the `pg_guard` attribute on the extern block, and the `PgNode` and
+  `Display` impls on node types
+* `Datum`, `NullableDatum` and `Oid`, which it defines itself, are defined as
+  bindgen defines them
+* oid constants, which it wraps in `Oid(..)`, are plain `u32`s again
+* `NodeTag` is turned back into the `NodeTag_T_*` constants bindgen emits by
+  default
+* the `ereport()` functions and `pg_re_throw()`, which it hides, are declared
+  again with the signatures of the version
+"""
+
+import re
+import sys
+from pathlib import Path
+
+PGRX_VERSION = "0.11.4"
+
+HEADER = """\
+/* automatically generated by rust-bindgen, for pgrx-pg-sys {pgrx}, from the
+ * headers of {full}, built with --enable-cassert; imported with
+ * import-pgrx-bindings.py */
+
+pub type Datum = usize;
+pub type Oid = ::std::os::raw::c_uint;
+"""
+
+NULLABLE_DATUM = """\
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct NullableDatum {
+    pub value: Datum,
+    pub isnull: bool,
+}
+"""
+
+ELOG = """\
+extern "C" {{
+{start_finish}
+    pub fn errcode(sqlerrcode: ::std::os::raw::c_int) -> ::std::os::raw::c_int;
+    pub fn errmsg(fmt: *const ::std::os::raw::c_char, ...) -> ::std::os::raw::c_int;
+    pub fn errdetail(fmt: *const ::std::os::raw::c_char, ...) -> ::std::os::raw::c_int;
+    pub fn errhint(fmt: *const ::std::os::raw::c_char, ...) -> ::std::os::raw::c_int;
+    pub fn errcontext_msg(fmt: *const ::std::os::raw::c_char, ...) -> ::std::os::raw::c_int;
+    pub fn pg_re_throw();
+}}
+"""
+
+# errstart() and errfinish() changed signatures in 13
+START_FINISH_PRE_13 = """\
+    pub fn errstart(
+        elevel: ::std::os::raw::c_int,
+        filename: *const ::std::os::raw::c_char,
+        lineno: ::std::os::raw::c_int,
+        funcname: *const ::std::os::raw::c_char,
+        domain: *const ::std::os::raw::c_char,
+    ) -> bool;
+    pub fn errfinish(dummy: ::std::os::raw::c_int, ...);"""
+
+START_FINISH = """\
+    pub fn errstart(elevel: ::std::os::raw::c_int, domain: *const ::std::os::raw::c_char) -> bool;
+    pub fn errfinish(
+        filename: *const ::std::os::raw::c_char,
+        lineno: ::std::os::raw::c_int,
+        funcname: *const ::std::os::raw::c_char,
+    );"""
+
+
+def node_tag_constants(match):
+    variants = re.findall(r"^\s*(T_\w+) = (\d+),$", match.group(1), re.MULTILINE)
+    consts = "".join(
+        "pub const NodeTag_{}: NodeTag = {};\n".format(name, value) for name, value in variants
+    )
+    return consts + "pub type NodeTag = u32;\n"
+
+
+def convert(source, version):
+    # pgrx's own imports
+    source = re.sub(r"^use crate as pg_sys;\n", "", source, flags=re.MULTILINE)
+    source = re.sub(r"^#\[cfg\(any\([^]]*\)\)\]\nuse crate::NullableDatum;\n", "", source,
+                    flags=re.MULTILINE)
+    source = re.sub(r"^use crate::\{Datum, Oid, PgNode\};\n", "", source, flags=re.MULTILINE)
+    source = re.sub(r"^#\[pgrx_macros::pg_guard\]\n", "", source, flags=re.MULTILINE)
+    # the configure arguments of the pgrx install
+    source = re.sub(r"^pub const CONFIGURE_ARGS : .*\n", "", source, flags=re.MULTILINE)
+    source = re.sub(r"^impl pg_sys::(seal::Sealed|PgNode) for \w+ \{\}\n", "", source,
+                    flags=re.MULTILINE)
+    source = re.sub(
+        r"^impl ::core::fmt::Display for \w+ \{\n"
+        r"    fn fmt\(&self, f: &mut ::core::fmt::Formatter<'_>\) -> ::core::fmt::Result \{\n"
+        r"        self.display_node\(\).fmt\(f\)\n"
+        r"    \}\n"
+        r"\}\n",
+        "", source, flags=re.MULTILINE)
+    source = re.sub(r"^(pub const \w+): Oid = Oid\((\d+)\);$", r"\1: u32 = \2;", source,
+                    flags=re.MULTILINE)
+    source, tags = re.subn(
+        r"^#\[repr\(u32\)\]\n#\[non_exhaustive\]\n#\[derive\([^)]*\)\]\npub enum NodeTag \{\n(.*?)^\}\n",
+        node_tag_constants, source, flags=re.MULTILINE | re.DOTALL)
+    assert tags == 1, "expected one NodeTag enum"
+    for leftover in ("pgrx", "pg_sys::", "crate::"):
+        assert leftover not in source, "pgrx-specific code left: {}".format(leftover)
+
+    num = int(re.search(r"^pub const PG_VERSION_NUM: u32 = (\d+);$", source, re.MULTILINE).group(1))
+    full = "{}.{}".format(num // 10000, num % 10000)
+    header = HEADER.format(pgrx=PGRX_VERSION, full=full)
+    if version >= 12:
+        header += NULLABLE_DATUM
+    elog = ELOG.format(start_finish=START_FINISH if version >= 13 else START_FINISH_PRE_13)
+    return header + source + elog
+
+
+def main():
+    pgrx_dir, version = Path(sys.argv[1]), int(sys.argv[2])
+    source = (pgrx_dir / "src/include/pg{}.rs".format(version)).read_text()
+    out = Path(__file__).parent / "src/cached/linux_glibc_pg{}.rs".format(version)
+    out.write_text(convert(source, version))
+
+
+if __name__ == "__main__":
+    main()
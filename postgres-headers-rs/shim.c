@@ -0,0 +1,100 @@
+/*
+ * Non-inline wrappers for postgres macros and static inline functions,
+ * compiled with the c_shim feature. Each wrapper uses the API exactly as a C
+ * extension would, so they follow the headers across versions.
+ */
+#include "shim.h"
+
+/*
+ * Run callback(arg) within PG_TRY(), in an internal subtransaction. Returns
+ * NULL if it returned normally, or a copy of the error it raised, allocated
+ * in the memory context that was current when pgrs_try() was called. When
+ * the callback errors, the error is flushed and the subtransaction rolled
+ * back, releasing the locks, buffer pins and other resources the callback
+ * acquired, as PL/pgSQL does for an EXCEPTION block. The error is raised with
+ * a longjmp, so the callback must not have any Rust frames with destructors
+ * live when it is raised.
+ */
+ErrorData *
+pgrs_try(pgrs_callback callback, void *arg)
+{
+	MemoryContext oldcontext = CurrentMemoryContext;
+	ResourceOwner oldowner = CurrentResourceOwner;
+	ErrorData  *volatile error = NULL;
+
+	BeginInternalSubTransaction(NULL);
+	/* run the callback in the caller's context, not the subtransaction's */
+	MemoryContextSwitchTo(oldcontext);
+
+	PG_TRY();
+	{
+		callback(arg);
+
+		ReleaseCurrentSubTransaction();
+		MemoryContextSwitchTo(oldcontext);
+		CurrentResourceOwner = oldowner;
+	}
+	PG_CATCH();
+	{
+		/* CopyErrorData() must not allocate in ErrorContext */
+		MemoryContextSwitchTo(oldcontext);
+		error = CopyErrorData();
+		FlushErrorState();
+
+		RollbackAndReleaseCurrentSubTransaction();
+		MemoryContextSwitchTo(oldcontext);
+		CurrentResourceOwner = oldowner;
+	}
+	PG_END_TRY();
+
+	return error;
+}
+
+/*
+ * AllocSetContextCreate(), which is a macro that only accepts a string
+ * constant as the name. The name is not copied, so it must outlive the
+ * context.
+ */
+MemoryContext
+pgrs_AllocSetContextCreate(MemoryContext parent, const char *name,
+						   Size minContextSize, Size initBlockSize,
+						   Size maxBlockSize)
+{
+#if PG_VERSION_NUM >= 120000
+	return AllocSetContextCreateInternal(parent, name, minContextSize,
+										 initBlockSize, maxBlockSize);
+#else
+	return AllocSetContextCreateExtended(parent, name, minContextSize,
+										 initBlockSize, maxBlockSize);
+#endif
+}
+
+Datum
+pgrs_heap_getattr(HeapTuple tup, int attnum, TupleDesc tupleDesc, bool *isnull)
+{
+	return heap_getattr(tup, attnum, tupleDesc, isnull);
+}
+
+int
+pgrs_list_length(const List *list)
+{
+	return list_length(list);
+}
+
+void *
+pgrs_list_nth(const List *list, int n)
+{
+	return list_nth(list, n);
+}
+
+int
+pgrs_list_nth_int(const List *list, int n)
+{
+	return list_nth_int(list, n);
+}
+
+Oid
+pgrs_list_nth_oid(const List *list, int n)
+{
+	return list_nth_oid(list, n);
+}
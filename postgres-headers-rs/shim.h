@@ -0,0 +1,35 @@
+/*
+ * Non-inline wrappers for postgres macros and static inline functions that
+ * are too intricate to reimplement in Rust, see shim.c. The Rust
+ * declarations are generated from this file.
+ */
+#ifndef PGRS_SHIM_H
+#define PGRS_SHIM_H
+
+#include "postgres.h"
+#include "access/htup_details.h"
+#include "access/xact.h"
+#include "nodes/pg_list.h"
+#include "utils/memutils.h"
+#include "utils/resowner.h"
+
+/* the callback run by pgrs_try() */
+typedef void (*pgrs_callback) (void *arg);
+
+extern ErrorData *pgrs_try(pgrs_callback callback, void *arg);
+
+extern MemoryContext pgrs_AllocSetContextCreate(MemoryContext parent,
+												const char *name,
+												Size minContextSize,
+												Size initBlockSize,
+												Size maxBlockSize);
+
+extern Datum pgrs_heap_getattr(HeapTuple tup, int attnum, TupleDesc tupleDesc,
+							   bool *isnull);
+
+extern int	pgrs_list_length(const List *list);
+extern void *pgrs_list_nth(const List *list, int n);
+extern int	pgrs_list_nth_int(const List *list, int n);
+extern Oid	pgrs_list_nth_oid(const List *list, int n);
+
+#endif							/* PGRS_SHIM_H */
@@ -1,21 +1,59 @@
-
 #[cfg(target_os = "linux")]
 use std::os::raw::c_int;
 
-// TODO version selection
 // TODO should we have a separate linux_musl_pgN target?
-#[cfg(target_os = "linux")]
+#[cfg(all(target_os = "linux", feature = "pg11"))]
+pub use linux_glibc_pg11::*;
+
+#[cfg(all(target_os = "linux", feature = "pg12"))]
 pub use linux_glibc_pg12::*;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "linux", feature = "pg13"))]
+pub use linux_glibc_pg13::*;
+
+#[cfg(all(target_os = "linux", feature = "pg14"))]
+pub use linux_glibc_pg14::*;
+
+#[cfg(all(target_os = "linux", feature = "pg15"))]
+pub use linux_glibc_pg15::*;
+
+#[cfg(all(target_os = "linux", feature = "pg16"))]
+pub use linux_glibc_pg16::*;
+
+#[cfg(all(target_os = "macos", feature = "pg12"))]
 pub use macos_pg12::*;
 
-#[cfg(target_os = "linux")]
+// pg12's are generated with `UPDATE_CACHED_BINDINGS=1 cargo build --features
+// parse_headers,pg12`; the others are imported from pgrx-pg-sys with
+// import-pgrx-bindings.py until we regenerate them the same way
+#[cfg(all(target_os = "linux", feature = "pg11"))]
+mod linux_glibc_pg11;
+
+// bindgen's layout tests dereference null pointers
+#[cfg(all(target_os = "linux", feature = "pg12"))]
+#[allow(deref_nullptr)]
 mod linux_glibc_pg12;
 
-#[cfg(target_os = "macos")]
+#[cfg(all(target_os = "linux", feature = "pg13"))]
+mod linux_glibc_pg13;
+
+#[cfg(all(target_os = "linux", feature = "pg14"))]
+mod linux_glibc_pg14;
+
+#[cfg(all(target_os = "linux", feature = "pg15"))]
+mod linux_glibc_pg15;
+
+#[cfg(all(target_os = "linux", feature = "pg16"))]
+mod linux_glibc_pg16;
+
+#[cfg(all(target_os = "macos", feature = "pg12"))]
+#[allow(deref_nullptr)]
 mod macos_pg12;
 
+#[cfg(all(target_os = "macos", not(feature = "pg12")))]
+compile_error!("pg12 is the only version with cached bindings for macOS, \
+    enable the parse_headers feature to generate them from a local install");
+
 #[cfg(target_os = "linux")]
 extern "C" {
     #[link_name = "__sigsetjmp"]
@@ -0,0 +1,41 @@
+/* declarations for shim.h, written to match what bindgen generates for it;
+ * `UPDATE_CACHED_BINDINGS=1 cargo build --features parse_headers,c_shim`
+ * replaces them with its actual output */
+
+pub type pgrs_callback =
+    ::std::option::Option<unsafe extern "C" fn(arg: *mut ::std::os::raw::c_void)>;
+extern "C" {
+    pub fn pgrs_try(callback: pgrs_callback, arg: *mut ::std::os::raw::c_void) -> *mut ErrorData;
+}
+extern "C" {
+    pub fn pgrs_AllocSetContextCreate(
+        parent: MemoryContext,
+        name: *const ::std::os::raw::c_char,
+        minContextSize: Size,
+        initBlockSize: Size,
+        maxBlockSize: Size,
+    ) -> MemoryContext;
+}
+extern "C" {
+    pub fn pgrs_heap_getattr(
+        tup: HeapTuple,
+        attnum: ::std::os::raw::c_int,
+        tupleDesc: TupleDesc,
+        isnull: *mut bool,
+    ) -> Datum;
+}
+extern "C" {
+    pub fn pgrs_list_length(list: *const List) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn pgrs_list_nth(
+        list: *const List,
+        n: ::std::os::raw::c_int,
+    ) -> *mut ::std::os::raw::c_void;
+}
+extern "C" {
+    pub fn pgrs_list_nth_int(list: *const List, n: ::std::os::raw::c_int) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn pgrs_list_nth_oid(list: *const List, n: ::std::os::raw::c_int) -> Oid;
+}
@@ -1,16 +1,71 @@
 #![allow(nonstandard_style)]
 #![allow(improper_ctypes)]
 
-#[cfg(not(feature = "parse_headers"))]
+#[cfg(not(any(
+    feature = "pg11",
+    feature = "pg12",
+    feature = "pg13",
+    feature = "pg14",
+    feature = "pg15",
+    feature = "pg16",
+)))]
+compile_error!("one of the pg11, pg12, pg13, pg14, pg15 or pg16 features must be enabled");
+
+#[cfg(any(
+    all(feature = "pg11", any(feature = "pg12", feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16")),
+    all(feature = "pg12", any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16")),
+    all(feature = "pg13", any(feature = "pg14", feature = "pg15", feature = "pg16")),
+    all(feature = "pg14", any(feature = "pg15", feature = "pg16")),
+    all(feature = "pg15", feature = "pg16"),
+))]
+compile_error!("only one of the pg11, pg12, pg13, pg14, pg15 or pg16 features may be enabled");
+
+// everything else is only compiled once a version is selected, which the
+// build script marks with `pg_version_selected`, so the errors above are not
+// buried under the ones bindings for no, or several, versions would cause
+
+/// The postgres version the bindings are for. The build script also sets a
+/// `pg_ge_N` cfg for every major version `N` up to this one, e.g. `pg_ge_13`
+/// is set for 13 and later, and passes the major version on to the build
+/// scripts of dependent crates as `DEP_POSTGRES_HEADERS_MAJOR_VERSION`.
+#[cfg(pg_version_selected)]
+pub mod version {
+    include!(concat!(env!("OUT_DIR"), "/version.rs"));
+
+    /// the full version the bindings were generated from, e.g. `120003`
+    pub use crate::PG_VERSION_NUM;
+}
+
+/// Rust versions of the header macros and `static inline` functions that
+/// bindgen cannot translate, e.g. `VARSIZE()` and `CHECK_FOR_INTERRUPTS()`.
+#[cfg(pg_version_selected)]
+pub mod macros;
+
+/// Non-inline wrappers, compiled from `shim.c` with the `c_shim` feature,
+/// for the macros and `static inline` functions that are too intricate to
+/// reimplement in `macros`, e.g. `PG_TRY()` and `heap_getattr()`. They take
+/// the same arguments as the C versions, and are named with a `pgrs_` prefix.
+#[cfg(all(pg_version_selected, feature = "c_shim"))]
+pub mod shim {
+    use crate::*;
+
+    #[cfg(not(feature = "parse_headers"))]
+    include!("cached/shim.rs");
+
+    #[cfg(feature = "parse_headers")]
+    include!(concat!(env!("OUT_DIR"), "/shim.rs"));
+}
+
+#[cfg(all(pg_version_selected, not(feature = "parse_headers")))]
 pub use cached::*;
 
-#[cfg(feature = "parse_headers")]
+#[cfg(all(pg_version_selected, feature = "parse_headers"))]
 pub use generated::*;
 
-#[cfg(not(feature = "parse_headers"))]
+#[cfg(all(pg_version_selected, not(feature = "parse_headers")))]
 mod cached;
 
-#[cfg(feature = "parse_headers")]
+#[cfg(all(pg_version_selected, feature = "parse_headers"))]
 mod generated {
     #[cfg(target_os = "linux")]
     use std::os::raw::c_int;
@@ -0,0 +1,721 @@
+//! Rust versions of the macros and `static inline` functions from the
+//! postgres headers, which bindgen drops. They keep their C names, and take
+//! raw pointers where the C versions do, so they are as unsafe to call as the
+//! C versions are to use.
+//!
+//! The varlena macros are from `postgres.h` (`varatt.h` from 16 on), the
+//! `Datum` conversions from `postgres.h`, `MemoryContextSwitchTo()` from
+//! `palloc.h`, `GetMemoryChunkContext()` from `memutils.h` (before 16, when it
+//! stopped being inline), `list_nth()` from `pg_list.h` (from 13 on, when it
+//! became inline), `CHECK_FOR_INTERRUPTS()` from `miscadmin.h` and
+//! `HeapTupleGetDatum()` from `funcapi.h`.
+
+// the safety requirements are those of the C macros
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    mem::size_of,
+    os::raw::{c_char, c_int, c_void},
+    ptr::{self, addr_of, addr_of_mut},
+};
+
+use crate::{
+    pg_detoast_datum,
+    pg_detoast_datum_copy,
+    pg_detoast_datum_packed,
+    varatt_external,
+    varlena,
+    vartag_external_VARTAG_EXPANDED_RO,
+    vartag_external_VARTAG_EXPANDED_RW,
+    vartag_external_VARTAG_INDIRECT,
+    vartag_external_VARTAG_ONDISK,
+    CurrentMemoryContext,
+    Datum,
+    HeapTuple,
+    HeapTupleHeader,
+    List,
+    MemoryContext,
+    Oid,
+    Pointer,
+};
+
+extern "C" {
+    fn HeapTupleHeaderGetDatum(tuple: HeapTupleHeader) -> Datum;
+    fn ProcessInterrupts();
+}
+
+// signal handler flags became sig_atomic_t in 12
+#[cfg(pg_ge_12)]
+extern "C" {
+    static mut InterruptPending: crate::sig_atomic_t;
+}
+
+#[cfg(not(pg_ge_12))]
+extern "C" {
+    static mut InterruptPending: bool;
+}
+
+// chunk headers are no longer just the context from 16 on, and it is exported
+#[cfg(pg_ge_16)]
+extern "C" {
+    #[link_name = "GetMemoryChunkContext"]
+    fn get_memory_chunk_context(pointer: *mut c_void) -> MemoryContext;
+}
+
+// Datum conversions
+
+#[inline]
+pub fn DatumGetPointer(X: Datum) -> Pointer {
+    X as Pointer
+}
+
+#[inline]
+pub fn PointerGetDatum<T>(X: *const T) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetCString(X: Datum) -> *mut c_char {
+    X as *mut c_char
+}
+
+#[inline]
+pub fn CStringGetDatum(X: *const c_char) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetBool(X: Datum) -> bool {
+    X != 0
+}
+
+#[inline]
+pub fn BoolGetDatum(X: bool) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetInt16(X: Datum) -> i16 {
+    X as i16
+}
+
+/// sign-extends, like the C cast does
+#[inline]
+pub fn Int16GetDatum(X: i16) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetInt32(X: Datum) -> i32 {
+    X as i32
+}
+
+/// sign-extends, like the C cast does
+#[inline]
+pub fn Int32GetDatum(X: i32) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetInt64(X: Datum) -> i64 {
+    X as i64
+}
+
+#[inline]
+pub fn Int64GetDatum(X: i64) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetObjectId(X: Datum) -> Oid {
+    X as Oid
+}
+
+#[inline]
+pub fn ObjectIdGetDatum(X: Oid) -> Datum {
+    X as Datum
+}
+
+#[inline]
+pub fn DatumGetFloat4(X: Datum) -> f32 {
+    f32::from_bits(X as u32)
+}
+
+#[inline]
+pub fn Float4GetDatum(X: f32) -> Datum {
+    X.to_bits() as Datum
+}
+
+#[inline]
+pub fn DatumGetFloat8(X: Datum) -> f64 {
+    f64::from_bits(X as u64)
+}
+
+#[inline]
+pub fn Float8GetDatum(X: f64) -> Datum {
+    X.to_bits() as Datum
+}
+
+// varlena headers
+
+pub const VARHDRSZ: usize = size_of::<i32>();
+pub const VARHDRSZ_SHORT: usize = 1;
+pub const VARHDRSZ_EXTERNAL: usize = 2;
+pub const VARATT_SHORT_MAX: usize = 0x7F;
+/// the size of a compressed varlena's header, including the raw size
+pub const VARHDRSZ_COMPRESSED: usize = 2 * size_of::<u32>();
+
+#[inline]
+unsafe fn header_byte(PTR: *const varlena) -> u8 {
+    *(PTR as *const u8)
+}
+
+#[inline]
+unsafe fn header_word(PTR: *const varlena) -> u32 {
+    ptr::read_unaligned(PTR as *const u32)
+}
+
+#[cfg(target_endian = "little")]
+mod header {
+    pub const IS_4B_MASK: u8 = 0x01;
+    pub const IS_4B: u8 = 0x00;
+    pub const IS_4B_U_MASK: u8 = 0x03;
+    pub const IS_4B_U: u8 = 0x00;
+    pub const IS_4B_C: u8 = 0x02;
+    pub const IS_1B: u8 = 0x01;
+    pub const IS_1B_E: u8 = 0x01;
+
+    pub fn size_4b(header: u32) -> usize {
+        ((header >> 2) & 0x3FFF_FFFF) as usize
+    }
+
+    pub fn size_1b(header: u8) -> usize {
+        ((header >> 1) & 0x7F) as usize
+    }
+
+    pub fn header_4b(len: usize) -> u32 {
+        (len as u32) << 2
+    }
+
+    pub fn header_4b_c(len: usize) -> u32 {
+        ((len as u32) << 2) | 0x02
+    }
+
+    pub fn header_1b(len: usize) -> u8 {
+        ((len as u8) << 1) | 0x01
+    }
+}
+
+#[cfg(target_endian = "big")]
+mod header {
+    pub const IS_4B_MASK: u8 = 0x80;
+    pub const IS_4B: u8 = 0x00;
+    pub const IS_4B_U_MASK: u8 = 0xC0;
+    pub const IS_4B_U: u8 = 0x00;
+    pub const IS_4B_C: u8 = 0x40;
+    pub const IS_1B: u8 = 0x80;
+    pub const IS_1B_E: u8 = 0x80;
+
+    pub fn size_4b(header: u32) -> usize {
+        (header & 0x3FFF_FFFF) as usize
+    }
+
+    pub fn size_1b(header: u8) -> usize {
+        (header & 0x7F) as usize
+    }
+
+    pub fn header_4b(len: usize) -> u32 {
+        (len as u32) & 0x3FFF_FFFF
+    }
+
+    pub fn header_4b_c(len: usize) -> u32 {
+        ((len as u32) & 0x3FFF_FFFF) | 0x4000_0000
+    }
+
+    pub fn header_1b(len: usize) -> u8 {
+        (len as u8) | 0x80
+    }
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_4B(PTR: *const varlena) -> bool {
+    header_byte(PTR) & header::IS_4B_MASK == header::IS_4B
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_4B_U(PTR: *const varlena) -> bool {
+    header_byte(PTR) & header::IS_4B_U_MASK == header::IS_4B_U
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_4B_C(PTR: *const varlena) -> bool {
+    header_byte(PTR) & header::IS_4B_U_MASK == header::IS_4B_C
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_1B(PTR: *const varlena) -> bool {
+    header_byte(PTR) & header::IS_4B_MASK == header::IS_1B
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_1B_E(PTR: *const varlena) -> bool {
+    header_byte(PTR) == header::IS_1B_E
+}
+
+#[inline]
+pub unsafe fn VARATT_NOT_PAD_BYTE(PTR: *const varlena) -> bool {
+    header_byte(PTR) != 0
+}
+
+#[inline]
+pub unsafe fn VARSIZE_4B(PTR: *const varlena) -> usize {
+    header::size_4b(header_word(PTR))
+}
+
+#[inline]
+pub unsafe fn VARSIZE_1B(PTR: *const varlena) -> usize {
+    header::size_1b(header_byte(PTR))
+}
+
+#[inline]
+pub unsafe fn VARTAG_1B_E(PTR: *const varlena) -> u8 {
+    *(PTR as *const u8).add(1)
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE_4B(PTR: *mut varlena, len: usize) {
+    ptr::write_unaligned(PTR as *mut u32, header::header_4b(len))
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE_4B_C(PTR: *mut varlena, len: usize) {
+    ptr::write_unaligned(PTR as *mut u32, header::header_4b_c(len))
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE_1B(PTR: *mut varlena, len: usize) {
+    *(PTR as *mut u8) = header::header_1b(len)
+}
+
+#[inline]
+pub unsafe fn SET_VARTAG_1B_E(PTR: *mut varlena, tag: u8) {
+    *(PTR as *mut u8) = header::IS_1B_E;
+    *(PTR as *mut u8).add(1) = tag;
+}
+
+#[inline]
+pub unsafe fn VARDATA_4B(PTR: *const varlena) -> *mut c_char {
+    (PTR as *mut c_char).add(VARHDRSZ)
+}
+
+#[inline]
+pub unsafe fn VARDATA_4B_C(PTR: *const varlena) -> *mut c_char {
+    (PTR as *mut c_char).add(VARHDRSZ_COMPRESSED)
+}
+
+#[inline]
+pub unsafe fn VARDATA_1B(PTR: *const varlena) -> *mut c_char {
+    (PTR as *mut c_char).add(VARHDRSZ_SHORT)
+}
+
+#[inline]
+pub unsafe fn VARDATA_1B_E(PTR: *const varlena) -> *mut c_char {
+    (PTR as *mut c_char).add(VARHDRSZ_EXTERNAL)
+}
+
+/// the size of the data a compressed varlena decompresses to
+#[inline]
+pub unsafe fn VARDATA_COMPRESSED_GET_EXTSIZE(PTR: *const varlena) -> usize {
+    let info = ptr::read_unaligned((PTR as *const u32).add(1));
+    // from 14 on the top two bits hold the compression method
+    #[cfg(pg_ge_14)]
+    let info = info & 0x3FFF_FFFF;
+    info as usize
+}
+
+#[inline]
+pub unsafe fn VARSIZE(PTR: *const varlena) -> usize {
+    VARSIZE_4B(PTR)
+}
+
+#[inline]
+pub unsafe fn VARDATA(PTR: *const varlena) -> *mut c_char {
+    VARDATA_4B(PTR)
+}
+
+#[inline]
+pub unsafe fn VARSIZE_SHORT(PTR: *const varlena) -> usize {
+    VARSIZE_1B(PTR)
+}
+
+#[inline]
+pub unsafe fn VARDATA_SHORT(PTR: *const varlena) -> *mut c_char {
+    VARDATA_1B(PTR)
+}
+
+#[inline]
+pub unsafe fn VARTAG_EXTERNAL(PTR: *const varlena) -> u8 {
+    VARTAG_1B_E(PTR)
+}
+
+#[inline]
+pub unsafe fn VARDATA_EXTERNAL(PTR: *const varlena) -> *mut c_char {
+    VARDATA_1B_E(PTR)
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_COMPRESSED(PTR: *const varlena) -> bool {
+    VARATT_IS_4B_C(PTR)
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_EXTERNAL(PTR: *const varlena) -> bool {
+    VARATT_IS_1B_E(PTR)
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_EXTERNAL_ONDISK(PTR: *const varlena) -> bool {
+    VARATT_IS_EXTERNAL(PTR) && VARTAG_EXTERNAL(PTR) as u32 == vartag_external_VARTAG_ONDISK
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_EXTERNAL_INDIRECT(PTR: *const varlena) -> bool {
+    VARATT_IS_EXTERNAL(PTR) && VARTAG_EXTERNAL(PTR) as u32 == vartag_external_VARTAG_INDIRECT
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_EXTERNAL_EXPANDED(PTR: *const varlena) -> bool {
+    VARATT_IS_EXTERNAL(PTR) && VARTAG_IS_EXPANDED(VARTAG_EXTERNAL(PTR))
+}
+
+#[inline]
+pub unsafe fn VARATT_IS_SHORT(PTR: *const varlena) -> bool {
+    VARATT_IS_1B(PTR)
+}
+
+/// whether the varlena is compressed, external or has a short header, i.e.
+/// whether it must be detoasted before its data can be read with `VARDATA`
+#[inline]
+pub unsafe fn VARATT_IS_EXTENDED(PTR: *const varlena) -> bool {
+    !VARATT_IS_4B_U(PTR)
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE(PTR: *mut varlena, len: usize) {
+    SET_VARSIZE_4B(PTR, len)
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE_SHORT(PTR: *mut varlena, len: usize) {
+    SET_VARSIZE_1B(PTR, len)
+}
+
+#[inline]
+pub unsafe fn SET_VARSIZE_COMPRESSED(PTR: *mut varlena, len: usize) {
+    SET_VARSIZE_4B_C(PTR, len)
+}
+
+#[inline]
+pub unsafe fn SET_VARTAG_EXTERNAL(PTR: *mut varlena, tag: u8) {
+    SET_VARTAG_1B_E(PTR, tag)
+}
+
+#[inline]
+pub fn VARTAG_IS_EXPANDED(tag: u8) -> bool {
+    // VARTAG_EXPANDED_RO and VARTAG_EXPANDED_RW only differ in the lowest bit
+    tag as u32 & !1 == vartag_external_VARTAG_EXPANDED_RO
+}
+
+/// the size of the pointer stored in an external varlena with the tag
+///
+/// # Panics
+/// if the tag is not a known `vartag_external`
+#[inline]
+pub fn VARTAG_SIZE(tag: u8) -> usize {
+    match tag as u32 {
+        vartag_external_VARTAG_INDIRECT => size_of::<*mut varlena>(),
+        vartag_external_VARTAG_EXPANDED_RO | vartag_external_VARTAG_EXPANDED_RW =>
+            size_of::<*mut u8>(),
+        vartag_external_VARTAG_ONDISK => size_of::<varatt_external>(),
+        _ => panic!("unrecognized TOAST vartag {}", tag),
+    }
+}
+
+#[inline]
+pub unsafe fn VARSIZE_EXTERNAL(PTR: *const varlena) -> usize {
+    VARHDRSZ_EXTERNAL + VARTAG_SIZE(VARTAG_EXTERNAL(PTR))
+}
+
+/// the size of the varlena, including its header, whichever header it has
+#[inline]
+pub unsafe fn VARSIZE_ANY(PTR: *const varlena) -> usize {
+    if VARATT_IS_1B_E(PTR) {
+        VARSIZE_EXTERNAL(PTR)
+    } else if VARATT_IS_1B(PTR) {
+        VARSIZE_1B(PTR)
+    } else {
+        VARSIZE_4B(PTR)
+    }
+}
+
+/// the size of the varlena's data, for varlenas with either a 1- or 4-byte
+/// header
+#[inline]
+pub unsafe fn VARSIZE_ANY_EXHDR(PTR: *const varlena) -> usize {
+    if VARATT_IS_1B_E(PTR) {
+        VARSIZE_EXTERNAL(PTR) - VARHDRSZ_EXTERNAL
+    } else if VARATT_IS_1B(PTR) {
+        VARSIZE_1B(PTR) - VARHDRSZ_SHORT
+    } else {
+        VARSIZE_4B(PTR) - VARHDRSZ
+    }
+}
+
+/// the varlena's data, for varlenas with either a 1- or 4-byte header
+#[inline]
+pub unsafe fn VARDATA_ANY(PTR: *const varlena) -> *mut c_char {
+    if VARATT_IS_1B(PTR) {
+        VARDATA_1B(PTR)
+    } else {
+        VARDATA_4B(PTR)
+    }
+}
+
+// detoasting, from fmgr.h
+
+#[inline]
+pub unsafe fn PG_DETOAST_DATUM(datum: Datum) -> *mut varlena {
+    pg_detoast_datum(DatumGetPointer(datum) as *mut varlena)
+}
+
+#[inline]
+pub unsafe fn PG_DETOAST_DATUM_COPY(datum: Datum) -> *mut varlena {
+    pg_detoast_datum_copy(DatumGetPointer(datum) as *mut varlena)
+}
+
+#[inline]
+pub unsafe fn PG_DETOAST_DATUM_PACKED(datum: Datum) -> *mut varlena {
+    pg_detoast_datum_packed(DatumGetPointer(datum) as *mut varlena)
+}
+
+// the rest
+
+/// make `context` the `CurrentMemoryContext`, returning the previous one
+#[inline]
+pub unsafe fn MemoryContextSwitchTo(context: MemoryContext) -> MemoryContext {
+    ptr::replace(addr_of_mut!(CurrentMemoryContext), context)
+}
+
+/// the context a palloc'd chunk was allocated in
+#[cfg(not(pg_ge_16))]
+#[inline]
+pub unsafe fn GetMemoryChunkContext(pointer: *mut c_void) -> MemoryContext {
+    // the context is stored immediately before the chunk
+    *(pointer as *const MemoryContext).sub(1)
+}
+
+/// the context a palloc'd chunk was allocated in
+#[cfg(pg_ge_16)]
+#[inline]
+pub unsafe fn GetMemoryChunkContext(pointer: *mut c_void) -> MemoryContext {
+    get_memory_chunk_context(pointer)
+}
+
+#[inline]
+pub unsafe fn HeapTupleGetDatum(tuple: HeapTuple) -> Datum {
+    HeapTupleHeaderGetDatum((*tuple).t_data)
+}
+
+/// Handle any pending query cancel or backend termination; this will
+/// `longjmp` if there is one.
+#[inline]
+pub unsafe fn CHECK_FOR_INTERRUPTS() {
+    // a bool before 12, a sig_atomic_t after
+    if ptr::read_volatile(addr_of!(InterruptPending)) != Default::default() {
+        ProcessInterrupts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a MAXALIGN'd buffer to build varlenas in
+    fn buffer() -> [u64; 4] {
+        [0; 4]
+    }
+
+    fn as_varlena(buffer: &mut [u64; 4]) -> *mut varlena {
+        buffer.as_mut_ptr() as *mut varlena
+    }
+
+    fn bytes(buffer: &[u64; 4]) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, 32) }
+    }
+
+    #[test]
+    fn datum_conversions() {
+        assert_eq!(Int32GetDatum(-1), usize::MAX);
+        assert_eq!(DatumGetInt32(Int32GetDatum(-12)), -12);
+        assert_eq!(DatumGetInt32(0x1_0000_0005), 5);
+        assert_eq!(Int16GetDatum(-2), usize::MAX - 1);
+        assert_eq!(DatumGetInt64(Int64GetDatum(i64::MIN)), i64::MIN);
+        assert_eq!(BoolGetDatum(true), 1);
+        assert!(!DatumGetBool(0));
+        assert!(DatumGetBool(0x100));
+        assert_eq!(ObjectIdGetDatum(25), 25);
+        assert_eq!(Float8GetDatum(1.0), 0x3FF0_0000_0000_0000);
+        assert_eq!(DatumGetFloat8(Float8GetDatum(-2.5)), -2.5);
+        assert_eq!(Float4GetDatum(1.0), 0x3F80_0000);
+        assert_eq!(DatumGetFloat4(Float4GetDatum(0.5)), 0.5);
+        let value = 7u8;
+        assert_eq!(DatumGetPointer(PointerGetDatum(&value)) as *const u8, &value as *const u8);
+    }
+
+    #[test]
+    fn four_byte_headers() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            SET_VARSIZE(ptr, 10);
+            #[cfg(target_endian = "little")]
+            assert_eq!(&bytes(&buffer)[..4], &[0x28, 0, 0, 0]);
+            #[cfg(target_endian = "big")]
+            assert_eq!(&bytes(&buffer)[..4], &[0, 0, 0, 0x0A]);
+            assert!(VARATT_IS_4B(ptr) && VARATT_IS_4B_U(ptr));
+            assert!(!VARATT_IS_EXTENDED(ptr));
+            assert!(!VARATT_IS_COMPRESSED(ptr) && !VARATT_IS_SHORT(ptr));
+            assert_eq!(VARSIZE(ptr), 10);
+            assert_eq!(VARSIZE_ANY(ptr), 10);
+            assert_eq!(VARSIZE_ANY_EXHDR(ptr), 10 - VARHDRSZ);
+            assert_eq!(VARDATA(ptr) as usize - ptr as usize, VARHDRSZ);
+            assert_eq!(VARDATA_ANY(ptr), VARDATA(ptr));
+
+            // the largest size a varlena can have
+            SET_VARSIZE(ptr, 0x3FFF_FFFF);
+            assert_eq!(VARSIZE(ptr), 0x3FFF_FFFF);
+        }
+    }
+
+    #[test]
+    fn compressed_headers() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            SET_VARSIZE_COMPRESSED(ptr, 20);
+            #[cfg(target_endian = "little")]
+            assert_eq!(bytes(&buffer)[0], 0x52);
+            #[cfg(target_endian = "big")]
+            assert_eq!(&bytes(&buffer)[..4], &[0x40, 0, 0, 0x14]);
+            assert!(VARATT_IS_4B(ptr) && VARATT_IS_COMPRESSED(ptr));
+            assert!(!VARATT_IS_4B_U(ptr) && VARATT_IS_EXTENDED(ptr));
+            assert_eq!(VARSIZE(ptr), 20);
+
+            ptr::write_unaligned((ptr as *mut u32).add(1), 1000);
+            assert_eq!(VARDATA_COMPRESSED_GET_EXTSIZE(ptr), 1000);
+            assert_eq!(VARDATA_4B_C(ptr) as usize - ptr as usize, 8);
+        }
+    }
+
+    #[cfg(pg_ge_14)]
+    #[test]
+    fn compression_method_is_not_part_of_the_size() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            SET_VARSIZE_COMPRESSED(ptr, 20);
+            // lz4 is compression method 1
+            ptr::write_unaligned((ptr as *mut u32).add(1), 1000 | (1 << 30));
+            assert_eq!(VARDATA_COMPRESSED_GET_EXTSIZE(ptr), 1000);
+        }
+    }
+
+    #[test]
+    fn short_headers() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            SET_VARSIZE_SHORT(ptr, 5);
+            #[cfg(target_endian = "little")]
+            assert_eq!(bytes(&buffer)[0], 0x0B);
+            #[cfg(target_endian = "big")]
+            assert_eq!(bytes(&buffer)[0], 0x85);
+            assert!(VARATT_IS_1B(ptr) && VARATT_IS_SHORT(ptr));
+            assert!(!VARATT_IS_4B(ptr) && !VARATT_IS_EXTERNAL(ptr));
+            assert!(VARATT_IS_EXTENDED(ptr));
+            assert_eq!(VARSIZE_SHORT(ptr), 5);
+            assert_eq!(VARSIZE_ANY(ptr), 5);
+            assert_eq!(VARSIZE_ANY_EXHDR(ptr), 4);
+            assert_eq!(VARDATA_ANY(ptr) as usize - ptr as usize, VARHDRSZ_SHORT);
+
+            SET_VARSIZE_SHORT(ptr, VARATT_SHORT_MAX);
+            assert_eq!(VARSIZE_SHORT(ptr), VARATT_SHORT_MAX);
+        }
+    }
+
+    #[test]
+    fn external_headers() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            SET_VARTAG_EXTERNAL(ptr, vartag_external_VARTAG_ONDISK as u8);
+            #[cfg(target_endian = "little")]
+            assert_eq!(&bytes(&buffer)[..2], &[0x01, 18]);
+            #[cfg(target_endian = "big")]
+            assert_eq!(&bytes(&buffer)[..2], &[0x80, 18]);
+            assert!(VARATT_IS_EXTERNAL(ptr) && VARATT_IS_EXTERNAL_ONDISK(ptr));
+            assert!(VARATT_IS_1B(ptr) && VARATT_IS_EXTENDED(ptr));
+            assert!(!VARATT_IS_EXTERNAL_INDIRECT(ptr) && !VARATT_IS_EXTERNAL_EXPANDED(ptr));
+            // rawsize, extsize, valueid and toastrelid
+            assert_eq!(VARSIZE_EXTERNAL(ptr), 2 + 16);
+            assert_eq!(VARSIZE_ANY(ptr), 18);
+            assert_eq!(VARSIZE_ANY_EXHDR(ptr), 16);
+            assert_eq!(VARDATA_EXTERNAL(ptr) as usize - ptr as usize, VARHDRSZ_EXTERNAL);
+
+            SET_VARTAG_EXTERNAL(ptr, vartag_external_VARTAG_EXPANDED_RW as u8);
+            assert!(VARATT_IS_EXTERNAL_EXPANDED(ptr));
+            assert_eq!(VARSIZE_EXTERNAL(ptr), 2 + size_of::<*mut u8>());
+
+            SET_VARTAG_EXTERNAL(ptr, vartag_external_VARTAG_INDIRECT as u8);
+            assert!(VARATT_IS_EXTERNAL_INDIRECT(ptr));
+        }
+    }
+
+    #[test]
+    fn pad_bytes() {
+        let mut buffer = buffer();
+        let ptr = as_varlena(&mut buffer);
+        unsafe {
+            assert!(!VARATT_NOT_PAD_BYTE(ptr));
+            SET_VARSIZE_SHORT(ptr, 1);
+            assert!(VARATT_NOT_PAD_BYTE(ptr));
+        }
+    }
+
+    #[cfg(not(pg_ge_16))]
+    #[test]
+    fn chunk_context() {
+        let context = 0x1000 as MemoryContext;
+        let mut chunk = [context as usize, 0];
+        unsafe {
+            let pointer = chunk.as_mut_ptr().add(1) as *mut c_void;
+            assert_eq!(GetMemoryChunkContext(pointer), context);
+        }
+    }
+}
+
+/// the pointer in the `n`th cell of the list
+#[cfg(not(pg_ge_13))]
+#[inline]
+pub unsafe fn list_nth(list: *const List, n: c_int) -> *mut c_void {
+    crate::list_nth(list, n)
+}
+
+/// the pointer in the `n`th cell of the list
+#[cfg(pg_ge_13)]
+#[inline]
+pub unsafe fn list_nth(list: *const List, n: c_int) -> *mut c_void {
+    // lists are arrays of cells from 13 on
+    debug_assert!(!list.is_null() && n >= 0 && n < (*list).length);
+    (*(*list).elements.add(n as usize)).ptr_value
+}
@@ -0,0 +1,108 @@
+//! The SQL options of exported functions, given with `#[sql(...)]` and
+//! `#[pg_extern(...)]`. The attributes validate them when the extension is
+//! compiled, and `timescale-extension-sql` reads them back out of the source,
+//! so both parse them here.
+
+use syn::{spanned::Spanned, Error, Lit, Meta, NestedMeta};
+
+const VOLATILITIES: &[&str] = &["immutable", "stable", "volatile"];
+const PARALLEL_SAFETIES: &[&str] = &["parallel_safe", "parallel_restricted", "parallel_unsafe"];
+const STRICTNESS: &[&str] = &["strict", "called_on_null_input"];
+/// `<role> = "<aggregate name>"` marks the function as that part of an
+/// aggregate; a function may have several roles, e.g. both `sfunc` and `msfunc`
+const AGGREGATE_ROLES: &[&str] = &[
+    "sfunc",
+    "finalfunc",
+    "combinefunc",
+    "serialfunc",
+    "deserialfunc",
+    "msfunc",
+    "minvfunc",
+    "mfinalfunc",
+];
+/// on the `finalfunc` of an ordered-set aggregate, whose remaining arguments
+/// are the direct arguments of the aggregate
+const AGGREGATE_KINDS: &[&str] = &["ordered_set", "hypothetical"];
+
+#[derive(Clone, Debug, Default)]
+pub struct SqlOptions {
+    pub name: Option<String>,
+    pub schema: Option<String>,
+    /// `immutable`, `stable` or `volatile`
+    pub volatility: Option<String>,
+    /// `safe`, `restricted` or `unsafe`
+    pub parallel: Option<String>,
+    pub strict: Option<bool>,
+    /// `(role, aggregate name)`s, e.g. `("sfunc", "my_sum")`
+    pub aggregate_roles: Vec<(String, String)>,
+    /// `ordered_set` or `hypothetical`, given on the `finalfunc` of an
+    /// ordered-set aggregate
+    pub aggregate_kind: Option<String>,
+}
+
+impl SqlOptions {
+    /// try to parse `arg` as an SQL option, returning `Ok(false)` if it is not
+    /// one, so the caller can check its own options
+    pub fn parse_option(&mut self, arg: &NestedMeta) -> syn::Result<bool> {
+        match arg {
+            NestedMeta::Meta(Meta::Path(path)) => {
+                let ident = match path.get_ident() {
+                    Some(ident) => ident.to_string(),
+                    None => return Ok(false),
+                };
+                // the option already given in place of this one, if any
+                let existing = if VOLATILITIES.contains(&&*ident) {
+                    self.volatility.replace(ident)
+                } else if PARALLEL_SAFETIES.contains(&&*ident) {
+                    self.parallel.replace(ident["parallel_".len()..].to_string())
+                        .map(|parallel| format!("parallel_{}", parallel))
+                } else if STRICTNESS.contains(&&*ident) {
+                    self.strict.replace(ident == "strict")
+                        .map(|strict| STRICTNESS[!strict as usize].to_string())
+                } else if AGGREGATE_KINDS.contains(&&*ident) {
+                    self.aggregate_kind.replace(ident)
+                } else {
+                    return Ok(false)
+                };
+                match existing {
+                    Some(existing) => Err(Error::new(
+                        arg.span(),
+                        format!("conflicts with `{}`", existing),
+                    )),
+                    None => Ok(true),
+                }
+            },
+            NestedMeta::Meta(Meta::NameValue(name_value)) => {
+                let ident = match name_value.path.get_ident() {
+                    Some(ident) => ident.to_string(),
+                    None => return Ok(false),
+                };
+                let is_role = AGGREGATE_ROLES.contains(&&*ident);
+                if !is_role && ident != "name" && ident != "schema" {
+                    return Ok(false)
+                }
+                let value = match &name_value.lit {
+                    Lit::Str(s) if !s.value().is_empty() => s.value(),
+                    lit => return Err(Error::new(
+                        lit.span(),
+                        format!("`{}` must be a non-empty string", ident),
+                    )),
+                };
+                let duplicate = match &*ident {
+                    "name" => self.name.replace(value).is_some(),
+                    "schema" => self.schema.replace(value).is_some(),
+                    _ if self.aggregate_roles.iter().any(|(role, _)| *role == ident) => true,
+                    _ => {
+                        self.aggregate_roles.push((ident.clone(), value));
+                        false
+                    },
+                };
+                if duplicate {
+                    return Err(Error::new(arg.span(), format!("duplicate `{}`", ident)))
+                }
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+}
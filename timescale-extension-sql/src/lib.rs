@@ -0,0 +1,1022 @@
+//! Generates the install script for an extension from the functions it
+//! exports with `pg_fn!`, `pg_agg!`, `pg_window_fn!` and `#[pg_extern]`, and
+//! their `#[sql(...)]` metadata.
+//!
+//! Functions returning a `PgComposite` struct are declared with `OUT`
+//! parameters for its fields, and those returning an iterator of them as
+//! `RETURNS TABLE`, unless the struct has been mapped to a named composite
+//! type with `SqlGenerator::map_type()`. Base types defined with `pg_type!`
+//! are created, along with their I/O functions, before any other function,
+//! and their Rust name maps to the new SQL type. `SerdeDatum<T>`s map to the
+//! base type defined for them, if any, and to `bytea` otherwise.
+//!
+//! This works on the extension's source, not its compiled code, so it can be
+//! run from a build script or the command line without a postgres install:
+//! ```no_run
+//! # fn main() -> Result<(), timescale_extension_sql::Error> {
+//! timescale_extension_sql::SqlGenerator::new("my_extension", "0.1.0")
+//!     .map_type("MyType", "my_type")
+//!     .scan_dir("src")?
+//!     .write_to_dir("sql")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write},
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use syn::Type;
+
+pub use parse::{Argument, BaseType, Composite, Function, Options};
+pub use types::{SqlType, TypeMap};
+
+mod parse;
+mod types;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, syn::Error),
+    /// a function argument or return type with no known SQL type
+    UnknownType { function: String, ty: String },
+    /// an aggregate that is missing a required function or has an invalid one
+    Aggregate { aggregate: String, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            Error::Parse(path, err) => {
+                let start = err.span().start();
+                write!(f, "{}:{}:{}: {}", path.display(), start.line, start.column + 1, err)
+            },
+            Error::UnknownType { function, ty } => write!(f,
+                "no SQL type known for `{}` used by `{}`, add one with `SqlGenerator::map_type()`",
+                ty,
+                function,
+            ),
+            Error::Aggregate { aggregate, message } =>
+                write!(f, "invalid aggregate `{}`: {}", aggregate, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct SqlGenerator {
+    extension: String,
+    version: String,
+    types: TypeMap,
+    functions: Vec<Function>,
+    composites: HashMap<String, Composite>,
+    base_types: Vec<BaseType>,
+}
+
+impl SqlGenerator {
+    pub fn new(extension: &str, version: &str) -> Self {
+        SqlGenerator {
+            extension: extension.to_string(),
+            version: version.to_string(),
+            types: TypeMap::default(),
+            functions: vec![],
+            composites: HashMap::new(),
+            base_types: vec![],
+        }
+    }
+
+    /// map the Rust type named `rust` to the SQL type `sql`
+    pub fn map_type(&mut self, rust: &str, sql: &str) -> &mut Self {
+        self.types.insert(rust, sql);
+        self
+    }
+
+    /// collect the functions from every `.rs` file in `dir` and its
+    /// subdirectories, in path order
+    pub fn scan_dir(&mut self, dir: impl AsRef<Path>) -> Result<&mut Self, Error> {
+        let mut files = vec![];
+        collect_rust_files(dir.as_ref(), &mut files)?;
+        files.sort();
+        for file in files {
+            let source = fs::read_to_string(&file).map_err(|err| Error::Io(file.clone(), err))?;
+            self.scan_source(&source).map_err(|err| Error::Parse(file.clone(), err))?;
+        }
+        Ok(self)
+    }
+
+    /// collect the functions, composites and base types from a single source
+    /// file
+    pub fn scan_source(&mut self, source: &str) -> Result<&mut Self, syn::Error> {
+        let (functions, composites, base_types) = parse::parse_file(source)?;
+        self.functions.extend(functions);
+        self.composites.extend(composites.into_iter().map(|c| (c.name.clone(), c)));
+        for base_type in base_types {
+            // explicit mappings take precedence
+            if !self.types.contains(&base_type.name) {
+                self.types.insert(&base_type.name, &base_type_name(&base_type));
+            }
+            self.base_types.push(base_type);
+        }
+        Ok(self)
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    /// the contents of the install script
+    pub fn to_sql(&self) -> Result<String, Error> {
+        let mut sql = format!(
+            "-- generated by timescale-extension-sql for {} {}, do not edit\n",
+            self.extension,
+            self.version,
+        );
+        sql.push_str(&format!(
+            "\\echo Use \"CREATE EXTENSION {}\" to load this file. \\quit\n",
+            self.extension,
+        ));
+
+        // base types must exist before any function using them
+        for base_type in &self.base_types {
+            sql.push('\n');
+            self.write_base_type(&mut sql, base_type);
+        }
+
+        for function in &self.functions {
+            sql.push('\n');
+            self.write_function(&mut sql, function)?;
+        }
+
+        for (name, functions) in self.aggregates() {
+            sql.push('\n');
+            self.write_aggregate(&mut sql, name, &functions)?;
+        }
+        Ok(sql)
+    }
+
+    /// write the install script to `<dir>/<extension>--<version>.sql`,
+    /// returning the path written to
+    pub fn write_to_dir(&self, dir: impl AsRef<Path>) -> Result<PathBuf, Error> {
+        let sql = self.to_sql()?;
+        let path = dir.as_ref().join(format!("{}--{}.sql", self.extension, self.version));
+        fs::write(&path, sql).map_err(|err| Error::Io(path.clone(), err))?;
+        Ok(path)
+    }
+
+    fn write_function(&self, sql: &mut String, function: &Function) -> Result<(), Error> {
+        let mut args = vec![];
+        for arg in &function.args {
+            let ty = self.sql_type(function, &arg.ty)?;
+            match &arg.name {
+                Some(name) => args.push(format!("{} {}", name, ty.name)),
+                None => args.push(ty.name),
+            }
+        }
+        // deserialization functions take an unused internal argument, so that
+        // they may return internal
+        if is_role(function, "deserialfunc") {
+            args.push("internal".to_string())
+        }
+        // the final function of a hypothetical-set aggregate is also passed
+        // NULLs for the aggregated arguments
+        args.extend(self.hypothetical_args(function)?);
+        let ret = match &function.ret {
+            Some(ret) => match (types::set_item(ret), self.row_type(ret)) {
+                (Some(item), _) => match self.row_type(item) {
+                    Some(row) => format!("TABLE ({})", self.columns(function, row, "")?.join(", ")),
+                    None => format!("SETOF {}", self.sql_type(function, item)?.name),
+                },
+                (None, Some(row)) => {
+                    args.extend(self.columns(function, row, "OUT ")?);
+                    "record".to_string()
+                },
+                (None, None) => self.sql_type(function, ret)?.name,
+            },
+            None => "void".to_string(),
+        };
+
+        let options = &function.options;
+        let _ = write!(sql,
+            "CREATE OR REPLACE FUNCTION {}({}) RETURNS {}\nAS 'MODULE_PATHNAME', '{}'\nLANGUAGE C",
+            sql_name(function),
+            args.join(", "),
+            ret,
+            function.symbol,
+        );
+        if function.window {
+            sql.push_str(" WINDOW");
+        }
+        if let Some(volatility) = &options.volatility {
+            let _ = write!(sql, " {}", volatility.to_uppercase());
+        }
+        if self.is_strict(function)? {
+            sql.push_str(" STRICT");
+        }
+        if let Some(parallel) = &options.parallel {
+            let _ = write!(sql, " PARALLEL {}", parallel.to_uppercase());
+        }
+        sql.push_str(";\n");
+        Ok(())
+    }
+
+    /// the shell type, the I/O functions, then the type itself
+    fn write_base_type(&self, sql: &mut String, base_type: &BaseType) {
+        let name = self.types.get(&base_type.name).unwrap_or_else(|| base_type_name(base_type));
+        let schema = base_type.options.schema.as_ref()
+            .map(|schema| format!("{}.", schema))
+            .unwrap_or_default();
+        let _ = writeln!(sql, "CREATE TYPE {};", name);
+        let functions = [
+            (&base_type.input, "cstring", name.as_str()),
+            (&base_type.output, name.as_str(), "cstring"),
+            (&base_type.receive, "internal", name.as_str()),
+            (&base_type.send, name.as_str(), "bytea"),
+        ];
+        for (function, arg, ret) in &functions {
+            let _ = writeln!(sql,
+                "CREATE OR REPLACE FUNCTION {}{}({}) RETURNS {}\nAS 'MODULE_PATHNAME', '{}'\n\
+                LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;",
+                schema, function, arg, ret, function,
+            );
+        }
+        let _ = writeln!(sql,
+            "CREATE TYPE {} (\n    input = {}{},\n    output = {}{},\n    receive = {}{},\n    \
+            send = {}{},\n    internallength = variable,\n    storage = extended\n);",
+            name,
+            schema, base_type.input,
+            schema, base_type.output,
+            schema, base_type.receive,
+            schema, base_type.send,
+        );
+    }
+
+    /// the functions making up each aggregate, by aggregate name then role
+    fn aggregates(&self) -> BTreeMap<&str, BTreeMap<&str, &Function>> {
+        let mut aggregates: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for function in &self.functions {
+            for (role, aggregate) in &function.options.aggregate_roles {
+                aggregates.entry(&**aggregate).or_default().insert(&**role, function);
+            }
+        }
+        aggregates
+    }
+
+    fn write_aggregate(
+        &self,
+        sql: &mut String,
+        name: &str,
+        functions: &BTreeMap<&str, &Function>,
+    ) -> Result<(), Error> {
+        let error = |message: &str| Error::Aggregate {
+            aggregate: name.to_string(),
+            message: message.to_string(),
+        };
+
+        let sfunc = functions.get("sfunc").ok_or_else(|| error("missing sfunc"))?;
+        let state = sfunc.args.first().ok_or_else(|| error("sfunc has no state argument"))?;
+        let stype = self.sql_type(sfunc, &state.ty)?.name;
+        let mut args = vec![];
+        for arg in &sfunc.args[1..] {
+            args.push(self.sql_type(sfunc, &arg.ty)?.name)
+        }
+        if !functions.contains_key("finalfunc") && stype == "internal" {
+            return Err(error("an aggregate with an internal state needs a finalfunc"))
+        }
+        let serialize = functions.contains_key("serialfunc");
+        if serialize != functions.contains_key("deserialfunc") {
+            return Err(error("serialfunc and deserialfunc must be given together"))
+        }
+        if serialize && stype != "internal" {
+            return Err(error("only aggregates with an internal state can be serialized"))
+        }
+        if serialize && !functions.contains_key("combinefunc") {
+            return Err(error("a serialized aggregate needs a combinefunc"))
+        }
+
+        let mstype = match (functions.get("msfunc"), functions.get("minvfunc")) {
+            (Some(msfunc), Some(minvfunc)) => {
+                if self.is_strict(msfunc)? != self.is_strict(minvfunc)? {
+                    return Err(error("msfunc and minvfunc must both be strict or both not be"))
+                }
+                let state = msfunc.args.first()
+                    .ok_or_else(|| error("msfunc has no state argument"))?;
+                Some(self.sql_type(msfunc, &state.ty)?.name)
+            },
+            (None, None) => None,
+            _ => return Err(error("msfunc and minvfunc must be given together")),
+        };
+        match &mstype {
+            Some(mstype) if mstype == "internal" && !functions.contains_key("mfinalfunc") =>
+                return Err(error("a moving aggregate with an internal state needs an mfinalfunc")),
+            None if functions.contains_key("mfinalfunc") =>
+                return Err(error("mfinalfunc needs an msfunc and minvfunc")),
+            _ => {},
+        }
+
+        let kind = match functions.values().find(|f| f.options.aggregate_kind.is_some()) {
+            Some(function) if !is_role(function, "finalfunc") =>
+                return Err(error("ordered_set and hypothetical must be given on the finalfunc")),
+            Some(function) => function.options.aggregate_kind.as_deref(),
+            None => None,
+        };
+        if let Some(finalfunc) = functions.get("finalfunc").filter(|_| kind.is_some()) {
+            let moving = ["combinefunc", "serialfunc", "deserialfunc", "msfunc", "minvfunc", "mfinalfunc"];
+            if moving.iter().any(|role| functions.contains_key(role)) {
+                return Err(error("ordered-set aggregates cannot be combined or used as moving aggregates"))
+            }
+            let mut direct = vec![];
+            let mut direct_types = vec![];
+            for arg in finalfunc.args.iter().skip(1) {
+                let ty = self.sql_type(finalfunc, &arg.ty)?.name;
+                match &arg.name {
+                    Some(name) => direct.push(format!("{} {}", name, ty)),
+                    None => direct.push(ty.clone()),
+                }
+                direct_types.push(ty);
+            }
+            if kind == Some("hypothetical") {
+                if self.is_strict(finalfunc)? {
+                    return Err(error("the finalfunc of a hypothetical-set aggregate cannot be strict"))
+                }
+                if !direct_types.ends_with(&args) {
+                    return Err(error("the last direct arguments of a hypothetical-set aggregate must match its aggregated arguments"))
+                }
+            }
+            let order = format!("ORDER BY {}", args.join(", "));
+            args = if direct.is_empty() {
+                vec![order]
+            } else {
+                vec![format!("{} {}", direct.join(", "), order)]
+            };
+        }
+
+        let schema = sfunc.options.schema.as_ref()
+            .map(|schema| format!("{}.", schema))
+            .unwrap_or_default();
+        let _ = writeln!(sql, "CREATE AGGREGATE {}{}({}) (", schema, name, args.join(", "));
+        let mut parts = vec![
+            format!("    sfunc = {}", sql_name(sfunc)),
+            format!("    stype = {}", stype),
+        ];
+        for role in &["finalfunc", "combinefunc", "serialfunc", "deserialfunc", "msfunc", "minvfunc"] {
+            if let Some(function) = functions.get(role) {
+                parts.push(format!("    {} = {}", role, sql_name(function)));
+            }
+        }
+        if let Some(mstype) = mstype {
+            parts.push(format!("    mstype = {}", mstype));
+        }
+        if let Some(mfinalfunc) = functions.get("mfinalfunc") {
+            parts.push(format!("    mfinalfunc = {}", sql_name(mfinalfunc)));
+        }
+        if kind == Some("hypothetical") {
+            parts.push("    finalfunc_extra".to_string());
+            parts.push("    hypothetical".to_string());
+        }
+        if let Some(parallel) = aggregate_parallel_safety(functions.values()) {
+            parts.push(format!("    parallel = {}", parallel));
+        }
+        let _ = writeln!(sql, "{}\n);", parts.join(",\n"));
+        Ok(())
+    }
+
+    /// the `PgComposite` `ty` refers to, if it has not been mapped to a
+    /// named type
+    fn row_type(&self, ty: &Type) -> Option<&Composite> {
+        if self.types.sql_type(ty).is_some() {
+            return None
+        }
+        self.composites.get(&types::base_name(ty)?)
+    }
+
+    /// the columns of a row type, as `<prefix><name> <type>`
+    fn columns(&self, function: &Function, row: &Composite, prefix: &str)
+    -> Result<Vec<String>, Error> {
+        let mut columns = vec![];
+        for (i, field) in row.fields.iter().enumerate() {
+            let ty = self.sql_type(function, &field.ty)?.name;
+            match &field.name {
+                Some(name) => columns.push(format!("{}{} {}", prefix, name, ty)),
+                None => columns.push(format!("{}column{} {}", prefix, i + 1, ty)),
+            }
+        }
+        Ok(columns)
+    }
+
+    /// the types of the aggregated arguments `function` is passed as the
+    /// final function of a hypothetical-set aggregate, if it is one
+    fn hypothetical_args(&self, function: &Function) -> Result<Vec<String>, Error> {
+        if function.options.aggregate_kind.as_deref() != Some("hypothetical") {
+            return Ok(vec![])
+        }
+        let aggregate = function.options.aggregate_roles.iter()
+            .find(|(role, _)| role == "finalfunc")
+            .map(|(_, aggregate)| aggregate);
+        let sfunc = self.functions.iter().find(|f| {
+            f.options.aggregate_roles.iter().any(|(role, a)| role == "sfunc" && Some(a) == aggregate)
+        });
+        // a missing sfunc is reported along with the aggregate
+        let sfunc = match sfunc {
+            Some(sfunc) => sfunc,
+            None => return Ok(vec![]),
+        };
+        let mut args = vec![];
+        for arg in sfunc.args.iter().skip(1) {
+            args.push(self.sql_type(sfunc, &arg.ty)?.name)
+        }
+        Ok(args)
+    }
+
+    fn is_strict(&self, function: &Function) -> Result<bool, Error> {
+        if let Some(strict) = function.options.strict {
+            return Ok(strict)
+        }
+        // functions that cannot handle NULLs don't need to be called with them
+        for arg in &function.args {
+            if self.sql_type(function, &arg.ty)?.nullable {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    fn sql_type(&self, function: &Function, ty: &Type) -> Result<SqlType, Error> {
+        self.types.sql_type(ty).ok_or_else(|| Error::UnknownType {
+            function: function.symbol.clone(),
+            ty: types::display(ty),
+        })
+    }
+}
+
+/// the SQL name of a base type, by default its Rust name in lowercase
+fn base_type_name(base_type: &BaseType) -> String {
+    let name = base_type.options.name.clone()
+        .unwrap_or_else(|| base_type.name.to_lowercase());
+    match &base_type.options.schema {
+        Some(schema) => format!("{}.{}", schema, name),
+        None => name,
+    }
+}
+
+fn is_role(function: &Function, role: &str) -> bool {
+    function.options.aggregate_roles.iter().any(|(r, _)| r == role)
+}
+
+fn sql_name(function: &Function) -> String {
+    let name = function.options.name.as_ref().unwrap_or(&function.symbol);
+    match &function.options.schema {
+        Some(schema) => format!("{}.{}", schema, name),
+        None => name.clone(),
+    }
+}
+
+/// an aggregate is only as parallel safe as the least safe of its functions
+fn aggregate_parallel_safety<'a>(functions: impl Iterator<Item = &'a &'a Function>)
+-> Option<&'static str> {
+    let mut safety = Some("safe");
+    for function in functions {
+        match function.options.parallel.as_deref() {
+            Some("safe") => {},
+            Some("restricted") => safety = safety.map(|_| "restricted"),
+            _ => safety = None,
+        }
+    }
+    safety
+}
+
+fn collect_rust_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let entries = fs::read_dir(dir).map_err(|err| Error::Io(dir.to_path_buf(), err))?;
+    for entry in entries {
+        let path = entry.map_err(|err| Error::Io(dir.to_path_buf(), err))?.path();
+        if path.is_dir() {
+            collect_rust_files(&path, files)?
+        } else if path.extension().map(|ext| ext == "rs").unwrap_or(false) {
+            files.push(path)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(source: &str) -> Result<String, Error> {
+        let mut generator = SqlGenerator::new("test", "1.0");
+        generator.scan_source(source).unwrap();
+        generator.to_sql()
+    }
+
+    #[test]
+    fn functions() {
+        let sql = generate(r#"
+            pg_fn!{
+                /// docs
+                #[sql(immutable, parallel_safe)]
+                pub fn add(a: i32, b: i64) -> i64 {
+                    a as i64 + b
+                }
+
+                pub fn maybe(a: &str, b: Option<f64>; fcinfo) -> Result<Option<String>, PgError> {
+                    Ok(None)
+                }
+            }
+
+            #[pg_extern(stable, name = "renamed", schema = "s")]
+            fn attr(bytes: &[u8], _: Vec<u8>, fcinfo: &mut FunctionCallInfoData) {}
+
+            #[cfg(test)]
+            mod tests {
+                pg_fn!{
+                    pub fn not_installed() {}
+                }
+            }
+        "#).unwrap();
+        assert_eq!(sql, r#"-- generated by timescale-extension-sql for test 1.0, do not edit
+\echo Use "CREATE EXTENSION test" to load this file. \quit
+
+CREATE OR REPLACE FUNCTION add(a integer, b bigint) RETURNS bigint
+AS 'MODULE_PATHNAME', 'add'
+LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;
+
+CREATE OR REPLACE FUNCTION maybe(a text, b double precision) RETURNS text
+AS 'MODULE_PATHNAME', 'maybe'
+LANGUAGE C;
+
+CREATE OR REPLACE FUNCTION s.renamed(bytes bytea, bytea) RETURNS void
+AS 'MODULE_PATHNAME', 'attr'
+LANGUAGE C STABLE STRICT;
+"#);
+    }
+
+    #[test]
+    fn set_returning_functions() {
+        let sql = generate(r#"
+            pg_fn!{
+                pub fn series(a: i32, b: i32) -> impl Iterator<Item = i32> {
+                    a..b
+                }
+            }
+
+            #[pg_extern(immutable)]
+            fn words<'a>(a: &'a str) -> impl IntoIterator<Item = Option<&'a str>> + 'a {
+                a.split(' ').map(Some)
+            }
+        "#).unwrap();
+        assert!(sql.contains("FUNCTION series(a integer, b integer) RETURNS SETOF integer\n"), "{}", sql);
+        assert!(sql.contains("FUNCTION words(a text) RETURNS SETOF text\n"), "{}", sql);
+    }
+
+    #[test]
+    fn composites() {
+        let sql = generate(r#"
+            #[derive(PgComposite)]
+            pub struct Row {
+                name: String,
+                value: Option<i64>,
+            }
+
+            #[derive(Clone, timescale_extension_utils::PgComposite)]
+            struct Pair(i32, f64);
+
+            pg_fn!{
+                pub fn row(a: i32) -> Option<Row> {
+                    None
+                }
+
+                pub fn pairs(a: i32) -> impl Iterator<Item = Pair> {
+                    (0..a).map(|i| Pair(i, 0.0))
+                }
+            }
+        "#).unwrap();
+        assert!(sql.contains(
+            "FUNCTION row(a integer, OUT name text, OUT value bigint) RETURNS record\n"
+        ), "{}", sql);
+        assert!(sql.contains(
+            "FUNCTION pairs(a integer) RETURNS TABLE (column1 integer, column2 double precision)\n"
+        ), "{}", sql);
+
+        let mut generator = SqlGenerator::new("test", "1.0");
+        generator.map_type("Pair", "pair");
+        generator.scan_source(r#"
+            #[derive(PgComposite)]
+            struct Pair(i32, f64);
+
+            pg_fn!{ pub fn swap(p: Pair) -> Pair { p } }
+        "#).unwrap();
+        assert!(generator.to_sql().unwrap().contains("swap(p pair) RETURNS pair\n"));
+    }
+
+    #[test]
+    fn aggregates() {
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_sum", parallel_safe)]
+                pub fn my_sum_trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(finalfunc = "my_sum", parallel_safe)]
+                pub fn my_sum_final(state: Option<Pox<i64>>) -> Option<i64> {
+                    state.map(|s| *s)
+                }
+            }
+        "#).unwrap();
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE my_sum(bigint) (
+    sfunc = my_sum_trans,
+    stype = internal,
+    finalfunc = my_sum_final,
+    parallel = safe
+);
+"#), "{}", sql);
+        assert!(sql.contains(
+            "CREATE OR REPLACE FUNCTION my_sum_trans(state internal, val bigint) RETURNS internal\n\
+            AS 'MODULE_PATHNAME', 'my_sum_trans'\n\
+            LANGUAGE C PARALLEL SAFE;"
+        ), "{}", sql);
+    }
+
+    #[test]
+    fn arrays() {
+        let sql = generate(r#"
+            pg_fn!{
+                pub fn sum(a: PgArray<'_, Option<f64>>, b: Vec<u8>) -> Vec<Option<i64>> {
+                    vec![]
+                }
+            }
+        "#).unwrap();
+        assert!(sql.contains("sum(a double precision[], b bytea) RETURNS bigint[]\n"), "{}", sql);
+    }
+
+    #[test]
+    fn parallel_aggregates() {
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_avg", parallel_safe)]
+                pub fn my_avg_trans(state: Option<Pox<Avg>>, val: f64) -> Option<Pox<Avg>> {
+                    state
+                }
+
+                #[sql(finalfunc = "my_avg", parallel_safe)]
+                pub fn my_avg_final(state: Option<Pox<Avg>>) -> Option<f64> {
+                    None
+                }
+
+                #[sql(combinefunc = "my_avg", parallel_safe)]
+                pub fn my_avg_combine(a: Option<Pox<Avg>>, b: Option<Pox<Avg>>) -> Option<Pox<Avg>> {
+                    a
+                }
+
+                #[sql(serialfunc = "my_avg", parallel_safe)]
+                pub fn my_avg_serialize(state: Pox<Avg>) -> Vec<u8>;
+
+                #[sql(deserialfunc = "my_avg", parallel_safe)]
+                pub fn my_avg_deserialize(bytes: &[u8]) -> Pox<Avg>;
+            }
+        "#).unwrap();
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE my_avg(double precision) (
+    sfunc = my_avg_trans,
+    stype = internal,
+    finalfunc = my_avg_final,
+    combinefunc = my_avg_combine,
+    serialfunc = my_avg_serialize,
+    deserialfunc = my_avg_deserialize,
+    parallel = safe
+);
+"#), "{}", sql);
+        assert!(sql.contains(
+            "my_avg_combine(a internal, b internal) RETURNS internal\n\
+            AS 'MODULE_PATHNAME', 'my_avg_combine'\n\
+            LANGUAGE C PARALLEL SAFE;"
+        ), "{}", sql);
+        assert!(sql.contains("my_avg_serialize(state internal) RETURNS bytea\n"), "{}", sql);
+        assert!(sql.contains(
+            "my_avg_deserialize(bytes bytea, internal) RETURNS internal\n\
+            AS 'MODULE_PATHNAME', 'my_avg_deserialize'\n\
+            LANGUAGE C STRICT PARALLEL SAFE;"
+        ), "{}", sql);
+    }
+
+    #[test]
+    fn moving_aggregates() {
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_sum", msfunc = "my_sum")]
+                pub fn my_sum_trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(minvfunc = "my_sum")]
+                pub fn my_sum_inv(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(finalfunc = "my_sum", mfinalfunc = "my_sum")]
+                pub fn my_sum_final(state: Option<Pox<i64>>) -> Option<i64> {
+                    None
+                }
+            }
+        "#).unwrap();
+        // functions can be shared between the plain and moving aggregates
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE my_sum(bigint) (
+    sfunc = my_sum_trans,
+    stype = internal,
+    finalfunc = my_sum_final,
+    msfunc = my_sum_trans,
+    minvfunc = my_sum_inv,
+    mstype = internal,
+    mfinalfunc = my_sum_final
+);
+"#), "{}", sql);
+
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_sum")]
+                pub fn my_sum_trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(finalfunc = "my_sum")]
+                pub fn my_sum_final(state: Option<Pox<i64>>) -> Option<i64> {
+                    None
+                }
+
+                #[sql(msfunc = "my_sum")]
+                pub fn my_sum_mtrans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(minvfunc = "my_sum")]
+                pub fn my_sum_inv(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+                    state
+                }
+
+                #[sql(mfinalfunc = "my_sum")]
+                pub fn my_sum_mfinal(state: Option<Pox<i64>>) -> Option<i64> {
+                    None
+                }
+            }
+        "#).unwrap();
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE my_sum(bigint) (
+    sfunc = my_sum_trans,
+    stype = internal,
+    finalfunc = my_sum_final,
+    msfunc = my_sum_mtrans,
+    minvfunc = my_sum_inv,
+    mstype = internal,
+    mfinalfunc = my_sum_mfinal
+);
+"#), "{}", sql);
+
+        let err = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "agg", msfunc = "agg")]
+                pub fn trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> { state }
+            }
+        "#);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn ordered_set_aggregates() {
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "percentile")]
+                pub fn percentile_trans(state: Option<Pox<OrderedSetSort>>, value: Option<f64>; fcinfo)
+                -> Option<Pox<OrderedSetSort>> {
+                    state
+                }
+
+                #[sql(finalfunc = "percentile", ordered_set)]
+                pub fn percentile_final(state: Option<Pox<OrderedSetSort>>, fraction: f64)
+                -> Option<f64> {
+                    None
+                }
+            }
+        "#).unwrap();
+        assert!(sql.contains("percentile_final(state internal, fraction double precision) RETURNS double precision"), "{}", sql);
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE percentile(fraction double precision ORDER BY double precision) (
+    sfunc = percentile_trans,
+    stype = internal,
+    finalfunc = percentile_final
+);
+"#), "{}", sql);
+
+        // the final function is also passed the aggregated arguments
+        let sql = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_rank")]
+                pub fn my_rank_trans(state: Option<Pox<OrderedSetSort>>, value: Option<i64>; fcinfo)
+                -> Option<Pox<OrderedSetSort>> {
+                    state
+                }
+
+                #[sql(finalfunc = "my_rank", hypothetical)]
+                pub fn my_rank_final(state: Option<Pox<OrderedSetSort>>, value: Option<i64>) -> i64 {
+                    0
+                }
+            }
+        "#).unwrap();
+        assert!(sql.contains("my_rank_final(state internal, value bigint, bigint) RETURNS bigint"), "{}", sql);
+        assert!(sql.ends_with(r#"
+CREATE AGGREGATE my_rank(value bigint ORDER BY bigint) (
+    sfunc = my_rank_trans,
+    stype = internal,
+    finalfunc = my_rank_final,
+    finalfunc_extra,
+    hypothetical
+);
+"#), "{}", sql);
+
+        let err = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "my_rank")]
+                pub fn my_rank_trans(state: Option<Pox<OrderedSetSort>>, value: Option<i64>)
+                -> Option<Pox<OrderedSetSort>> { state }
+
+                #[sql(finalfunc = "my_rank", hypothetical)]
+                pub fn my_rank_final(state: Option<Pox<OrderedSetSort>>, value: Option<f64>) -> i64 { 0 }
+            }
+        "#);
+        assert!(err.is_err());
+
+        let err = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "agg", ordered_set)]
+                pub fn trans(state: Option<Pox<OrderedSetSort>>, v: f64) -> Option<Pox<OrderedSetSort>> { state }
+
+                #[sql(finalfunc = "agg")]
+                pub fn fin(state: Option<Pox<OrderedSetSort>>) -> Option<f64> { None }
+            }
+        "#);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn window_functions() {
+        let sql = generate(r#"
+            pg_window_fn!{
+                #[sql(immutable)]
+                pub fn locf(window: WindowObject, value: Option<f64>) -> Option<f64> {
+                    None
+                }
+
+                pub fn row_position(window: WindowObject) -> i64 {
+                    0
+                }
+            }
+        "#).unwrap();
+        assert!(sql.contains(r#"
+CREATE OR REPLACE FUNCTION locf(value double precision) RETURNS double precision
+AS 'MODULE_PATHNAME', 'locf'
+LANGUAGE C WINDOW IMMUTABLE;
+"#), "{}", sql);
+        assert!(sql.contains(r#"
+CREATE OR REPLACE FUNCTION row_position() RETURNS bigint
+AS 'MODULE_PATHNAME', 'row_position'
+LANGUAGE C WINDOW STRICT;
+"#), "{}", sql);
+    }
+
+    #[test]
+    fn base_types() {
+        let sql = generate(r#"
+            pg_type!{
+                #[sql(schema = "geo")]
+                Point2D {
+                    input = point2d_in,
+                    output = point2d_out,
+                    receive = point2d_recv,
+                    send = point2d_send,
+                }
+            }
+
+            pg_fn!{
+                pub fn point_x(point: Point2D) -> f64 { point.x }
+            }
+        "#).unwrap();
+        assert!(sql.contains(r#"
+CREATE TYPE geo.point2d;
+CREATE OR REPLACE FUNCTION geo.point2d_in(cstring) RETURNS geo.point2d
+AS 'MODULE_PATHNAME', 'point2d_in'
+LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;
+CREATE OR REPLACE FUNCTION geo.point2d_out(geo.point2d) RETURNS cstring
+AS 'MODULE_PATHNAME', 'point2d_out'
+LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;
+CREATE OR REPLACE FUNCTION geo.point2d_recv(internal) RETURNS geo.point2d
+AS 'MODULE_PATHNAME', 'point2d_recv'
+LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;
+CREATE OR REPLACE FUNCTION geo.point2d_send(geo.point2d) RETURNS bytea
+AS 'MODULE_PATHNAME', 'point2d_send'
+LANGUAGE C IMMUTABLE STRICT PARALLEL SAFE;
+CREATE TYPE geo.point2d (
+    input = geo.point2d_in,
+    output = geo.point2d_out,
+    receive = geo.point2d_recv,
+    send = geo.point2d_send,
+    internallength = variable,
+    storage = extended
+);
+"#), "{}", sql);
+        // the type is created before the functions using it
+        assert!(sql.find("CREATE TYPE geo.point2d (").unwrap() < sql.find("point_x").unwrap());
+        assert!(sql.contains("point_x(point geo.point2d) RETURNS double precision"), "{}", sql);
+
+        let mut generator = SqlGenerator::new("test", "1.0");
+        let err = generator.scan_source("pg_type!{ Point2D { input = point2d_in, output = point2d_out } }");
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn serde_types() {
+        let sql = generate(r#"
+            pg_type!{
+                SerdeDatum<Sketch> {
+                    input = sketch_in,
+                    output = sketch_out,
+                    receive = sketch_recv,
+                    send = sketch_send,
+                }
+            }
+
+            pg_fn!{
+                pub fn sketch_count(sketch: SerdeDatum<Sketch>, key: &str) -> Option<i64> { None }
+                pub fn sum(values: SerdeDatum<Vec<f64>>) -> f64 { 0.0 }
+            }
+        "#).unwrap();
+        assert!(sql.contains("CREATE TYPE sketch;"), "{}", sql);
+        assert!(sql.contains("sketch_in(cstring) RETURNS sketch"), "{}", sql);
+        assert!(sql.contains("sketch_count(sketch sketch, key text) RETURNS bigint"), "{}", sql);
+        // SerdeDatums that are not base types are stored as bytea
+        assert!(sql.contains("sum(values bytea) RETURNS double precision"), "{}", sql);
+    }
+
+    #[test]
+    fn cfgs() {
+        let sql = generate(r#"
+            #[cfg(not(test))]
+            pg_fn!{ pub fn installed() {} }
+
+            #[cfg(all(test, feature = "extra"))]
+            mod tests {
+                #[pg_extern]
+                fn not_installed() {}
+            }
+
+            #[cfg(feature = "extra")]
+            mod extra {
+                fn not_exported() {}
+            }
+        "#).unwrap();
+        assert!(sql.contains("FUNCTION installed()"), "{}", sql);
+        assert!(!sql.contains("not_installed"), "{}", sql);
+
+        let conditional = [
+            "#[cfg(feature = \"extra\")] pg_fn!{ pub fn f() {} }",
+            "pg_fn!{ #[cfg(any(test, unix))] pub fn f() {} }",
+            "#[cfg(feature = \"extra\")] mod extra { mod inner { #[pg_extern] fn f() {} } }",
+            "#[cfg(debug_assertions)] #[derive(PgComposite)] struct Row { a: i32 }",
+        ];
+        for source in conditional {
+            let err = SqlGenerator::new("test", "1.0").scan_source(source).err().unwrap();
+            assert!(err.to_string().contains("conditionally compiled"), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = generate("pg_fn!{ pub fn f(a: u128) {} }").unwrap_err();
+        assert!(matches!(err, Error::UnknownType { .. }), "{}", err);
+
+        let err = generate(r#"
+            pg_agg!{
+                #[sql(sfunc = "agg")]
+                pub fn trans(state: Option<Pox<i64>>) -> Option<Pox<i64>> { state }
+            }
+        "#).unwrap_err();
+        assert!(matches!(err, Error::Aggregate { .. }), "{}", err);
+
+        let mut generator = SqlGenerator::new("test", "1.0");
+        let err = generator.scan_source(r#"
+            #[sql(stable, volatile)]
+            #[pg_extern]
+            fn f() {}
+        "#).err().unwrap();
+        assert_eq!(err.to_string(), "conflicts with `stable`");
+
+        let mut generator = SqlGenerator::new("test", "1.0");
+        generator.map_type("u128", "numeric");
+        generator.scan_source("pg_fn!{ pub fn f(a: u128) {} }").unwrap();
+        assert!(generator.to_sql().unwrap().contains("f(a numeric) RETURNS void"));
+    }
+}
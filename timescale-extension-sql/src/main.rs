@@ -0,0 +1,46 @@
+use std::{env, process::exit};
+
+use timescale_extension_sql::SqlGenerator;
+
+const USAGE: &str = "usage: timescale-extension-sql <extension> <version> <source dir> [output dir] [--type <Rust type>=<SQL type>]...";
+
+fn main() {
+    let mut positional = vec![];
+    let mut types = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--type" => match args.next().as_ref().and_then(|t| t.split_once('=')) {
+                Some((rust, sql)) => types.push((rust.to_string(), sql.to_string())),
+                None => fail(USAGE),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return
+            },
+            _ => positional.push(arg),
+        }
+    }
+
+    let (extension, version, source, output) = match &*positional {
+        [extension, version, source] => (extension, version, source, "."),
+        [extension, version, source, output] => (extension, version, source, &**output),
+        _ => fail(USAGE),
+    };
+
+    let mut generator = SqlGenerator::new(extension, version);
+    for (rust, sql) in &types {
+        generator.map_type(rust, sql);
+    }
+    let written = generator.scan_dir(source)
+        .and_then(|generator| generator.write_to_dir(output));
+    match written {
+        Ok(path) => println!("wrote {}", path.display()),
+        Err(err) => fail(&err.to_string()),
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    exit(1)
+}
@@ -0,0 +1,491 @@
+//! find the functions exported by `pg_fn!`, `pg_agg!`, `pg_window_fn!` and
+//! `#[pg_extern]` in
+//! a source file, along with their `#[sql(...)]` metadata, the structs
+//! deriving `PgComposite`, and the base types defined with `pg_type!`
+
+use proc_macro2::Span;
+use quote::ToTokens;
+use syn::{
+    braced,
+    parenthesized,
+    parse::{Parse, ParseStream},
+    spanned::Spanned,
+    visit::{self, Visit},
+    Attribute,
+    Block,
+    FnArg,
+    Ident,
+    ItemFn,
+    ItemMacro,
+    ItemMod,
+    ItemStruct,
+    Meta,
+    NestedMeta,
+    Pat,
+    ReturnType,
+    Token,
+    Type,
+    Visibility,
+};
+
+pub use timescale_extension_sql_options::SqlOptions as Options;
+
+use crate::types;
+
+/// a function exported to postgres
+#[derive(Clone, Debug)]
+pub struct Function {
+    /// the symbol the function is exported as
+    pub symbol: String,
+    pub args: Vec<Argument>,
+    /// `None` for functions returning `()`
+    pub ret: Option<Type>,
+    pub options: Options,
+    /// whether the function is a window function
+    pub window: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Argument {
+    pub name: Option<String>,
+    pub ty: Type,
+}
+
+/// a struct deriving `PgComposite`
+#[derive(Clone, Debug)]
+pub struct Composite {
+    pub name: String,
+    /// `None` for the fields of tuple structs
+    pub fields: Vec<Argument>,
+}
+
+/// a custom base type defined with `pg_type!`
+#[derive(Clone, Debug)]
+pub struct BaseType {
+    /// the name of the Rust type
+    pub name: String,
+    pub input: String,
+    pub output: String,
+    pub receive: String,
+    pub send: String,
+    /// `name` and `schema` apply to the type
+    pub options: Options,
+}
+
+pub fn parse_file(source: &str) -> syn::Result<(Vec<Function>, Vec<Composite>, Vec<BaseType>)> {
+    let file = syn::parse_file(source)?;
+    let mut visitor = Visitor {
+        functions: vec![],
+        composites: vec![],
+        types: vec![],
+        cfg_module: None,
+        error: None,
+    };
+    visitor.visit_file(&file);
+    match visitor.error {
+        Some(err) => Err(err),
+        None => Ok((visitor.functions, visitor.composites, visitor.types)),
+    }
+}
+
+struct Visitor {
+    functions: Vec<Function>,
+    composites: Vec<Composite>,
+    types: Vec<BaseType>,
+    /// the outermost module we are within whose `#[cfg(...)]` we cannot
+    /// evaluate, if any
+    cfg_module: Option<Ident>,
+    error: Option<syn::Error>,
+}
+
+impl Visitor {
+    fn record<T>(&mut self, result: syn::Result<T>) -> Option<T> {
+        match result {
+            Ok(val) => Some(val),
+            Err(err) => {
+                if self.error.is_none() {
+                    self.error = Some(err)
+                }
+                None
+            },
+        }
+    }
+
+    /// Whether the item `name`, with `attrs`, is compiled outside of tests,
+    /// and so installed. We cannot evaluate cfgs other than `test`, such as
+    /// features, so items depending on them are rejected.
+    fn installed(&mut self, attrs: &[Attribute], name: &str, span: Span) -> bool {
+        let conditional = match (cfg_enabled(attrs), &self.cfg_module) {
+            (Some(false), _) => return false,
+            (Some(true), None) => return true,
+            (None, _) => format!("`{}` is", name),
+            (Some(true), Some(module)) => format!("`{}` is in `{}`, which is", name, module),
+        };
+        self.record::<()>(Err(syn::Error::new(span, format!(
+            "{} conditionally compiled on something other than #[cfg(test)], \
+            so whether it is installed cannot be told from the source",
+            conditional,
+        ))));
+        false
+    }
+}
+
+impl<'ast> Visit<'ast> for Visitor {
+    fn visit_item_mod(&mut self, module: &'ast ItemMod) {
+        match cfg_enabled(&module.attrs) {
+            // test-only functions are never installed
+            Some(false) => {},
+            Some(true) => visit::visit_item_mod(self, module),
+            // only an error if it exports anything, see `installed`
+            None => {
+                let outer = self.cfg_module.clone();
+                self.cfg_module.get_or_insert_with(|| module.ident.clone());
+                visit::visit_item_mod(self, module);
+                self.cfg_module = outer;
+            },
+        }
+    }
+
+    fn visit_item_macro(&mut self, mac: &'ast ItemMacro) {
+        let name = match mac.mac.path.segments.last() {
+            Some(segment) => segment.ident.to_string(),
+            None => return,
+        };
+        let exports = ["pg_type", "pg_fn", "pg_agg", "pg_window_fn"].contains(&&*name);
+        if !exports || !self.installed(&mac.attrs, &format!("{}!", name), mac.span()) {
+            return
+        }
+        if name == "pg_type" {
+            let types: Option<MacroTypes> = self.record(mac.mac.parse_body());
+            if let Some(types) = types {
+                for ty in types.0 {
+                    let ty_name = ty.ty.to_token_stream().to_string();
+                    if !self.installed(&ty.attrs, &ty_name, ty.ty.span()) {
+                        continue
+                    }
+                    let ty = self.record(ty.into_base_type());
+                    self.types.extend(ty)
+                }
+            }
+            return
+        }
+        let functions: Option<MacroFns> = self.record(mac.mac.parse_body());
+        if let Some(functions) = functions {
+            for function in functions.0 {
+                let symbol = function.name.to_string();
+                if !self.installed(&function.attrs, &symbol, function.name.span()) {
+                    continue
+                }
+                let mut function = match self.record(function.into_function()) {
+                    Some(function) => function,
+                    None => continue,
+                };
+                if name == "pg_window_fn" && !function.args.is_empty() {
+                    // the WindowObject is not an SQL argument
+                    function.args.remove(0);
+                    function.window = true;
+                }
+                self.functions.push(function)
+            }
+        }
+    }
+
+    fn visit_item_fn(&mut self, func: &'ast ItemFn) {
+        let is_extern = func.attrs.iter().any(|attr| attr_name(attr) == Some("pg_extern".into()));
+        let name = &func.sig.ident;
+        if is_extern && self.installed(&func.attrs, &name.to_string(), name.span()) {
+            let function = self.record(extern_function(func));
+            self.functions.extend(function)
+        }
+    }
+
+    fn visit_item_struct(&mut self, item: &'ast ItemStruct) {
+        if !derives(&item.attrs, "PgComposite")
+            || !self.installed(&item.attrs, &item.ident.to_string(), item.ident.span())
+        {
+            return
+        }
+        self.composites.push(Composite {
+            name: item.ident.to_string(),
+            fields: item.fields.iter()
+                .map(|field| Argument {
+                    name: field.ident.as_ref().map(|ident| ident.to_string()),
+                    ty: field.ty.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// the functions in a `pg_fn!`, `pg_agg!` or `pg_window_fn!` invocation
+struct MacroFns(Vec<MacroFn>);
+
+struct MacroFn {
+    attrs: Vec<Attribute>,
+    name: Ident,
+    args: Vec<(Ident, Type)>,
+    ret: Option<Type>,
+}
+
+impl Parse for MacroFns {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut functions = vec![];
+        while !input.is_empty() {
+            functions.push(input.parse()?)
+        }
+        Ok(MacroFns(functions))
+    }
+}
+
+impl Parse for MacroFn {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let _: Visibility = input.parse()?;
+        let _: Token![fn] = input.parse()?;
+        let name = input.parse()?;
+
+        let content;
+        parenthesized!(content in input);
+        let mut args = vec![];
+        while !content.is_empty() && !content.peek(Token![;]) {
+            let arg = content.parse()?;
+            let _: Token![:] = content.parse()?;
+            args.push((arg, content.parse()?));
+            if content.peek(Token![,]) {
+                let _: Token![,] = content.parse()?;
+            }
+        }
+        if content.peek(Token![;]) {
+            // the fcinfo is not an SQL argument
+            let _: Token![;] = content.parse()?;
+            let _: Ident = content.parse()?;
+        }
+
+        let ret = match input.parse()? {
+            ReturnType::Default => None,
+            ReturnType::Type(_, ty) => Some(*ty),
+        };
+        // `pg_agg!` serialization functions have no body
+        if input.peek(Token![;]) {
+            let _: Token![;] = input.parse()?;
+        } else {
+            let _: Block = input.parse()?;
+        }
+        Ok(MacroFn { attrs, name, args, ret })
+    }
+}
+
+impl MacroFn {
+    fn into_function(self) -> syn::Result<Function> {
+        let mut options = Options::default();
+        parse_sql_attrs(&self.attrs, &mut options)?;
+        Ok(Function {
+            symbol: self.name.to_string(),
+            args: self.args.into_iter()
+                .map(|(name, ty)| Argument { name: Some(name.to_string()), ty })
+                .collect(),
+            ret: self.ret,
+            options,
+            window: false,
+        })
+    }
+}
+
+/// the types in a `pg_type!` invocation
+struct MacroTypes(Vec<MacroType>);
+
+struct MacroType {
+    attrs: Vec<Attribute>,
+    ty: Type,
+    /// `(role, function)`, e.g. `(input, my_type_in)`
+    functions: Vec<(Ident, Ident)>,
+}
+
+impl Parse for MacroTypes {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut types = vec![];
+        while !input.is_empty() {
+            types.push(input.parse()?)
+        }
+        Ok(MacroTypes(types))
+    }
+}
+
+impl Parse for MacroType {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let attrs = input.call(Attribute::parse_outer)?;
+        let ty = input.parse()?;
+        let content;
+        braced!(content in input);
+        let mut functions = vec![];
+        while !content.is_empty() {
+            let role = content.parse()?;
+            let _: Token![=] = content.parse()?;
+            functions.push((role, content.parse()?));
+            if content.peek(Token![,]) {
+                let _: Token![,] = content.parse()?;
+            }
+        }
+        Ok(MacroType { attrs, ty, functions })
+    }
+}
+
+impl MacroType {
+    fn into_base_type(self) -> syn::Result<BaseType> {
+        let mut options = Options::default();
+        parse_sql_attrs(&self.attrs, &mut options)?;
+        let name = types::type_key(&self.ty).ok_or_else(|| syn::Error::new(
+            self.ty.span(),
+            "pg_type! expects a named type",
+        ))?;
+        // a SerdeDatum<T> is named after its T by default
+        if let Some(inner) = types::serde_inner(&self.ty).and_then(types::base_name) {
+            options.name.get_or_insert(inner.to_lowercase());
+        }
+        let function = |role: &str| {
+            self.functions.iter()
+                .find(|(r, _)| r == role)
+                .map(|(_, function)| function.to_string())
+                .ok_or_else(|| syn::Error::new(
+                    self.ty.span(),
+                    format!("pg_type! is missing the {} function", role),
+                ))
+        };
+        Ok(BaseType {
+            name,
+            input: function("input")?,
+            output: function("output")?,
+            receive: function("receive")?,
+            send: function("send")?,
+            options,
+        })
+    }
+}
+
+fn extern_function(func: &ItemFn) -> syn::Result<Function> {
+    let mut options = Options::default();
+    parse_sql_attrs(&func.attrs, &mut options)?;
+
+    let mut args = vec![];
+    for arg in &func.sig.inputs {
+        let arg = match arg {
+            FnArg::Typed(arg) => arg,
+            // rejected by #[pg_extern] itself
+            FnArg::Receiver(_) => continue,
+        };
+        if is_fcinfo(&arg.ty) {
+            continue
+        }
+        let name = match &*arg.pat {
+            Pat::Ident(pat) => Some(pat.ident.to_string()),
+            _ => None,
+        };
+        args.push(Argument { name, ty: (*arg.ty).clone() });
+    }
+
+    Ok(Function {
+        symbol: func.sig.ident.to_string(),
+        args,
+        ret: match &func.sig.output {
+            ReturnType::Default => None,
+            ReturnType::Type(_, ty) => Some((**ty).clone()),
+        },
+        options,
+        window: false,
+    })
+}
+
+fn parse_sql_attrs(attrs: &[Attribute], options: &mut Options) -> syn::Result<()> {
+    for attr in attrs {
+        match attr_name(attr).as_deref() {
+            Some("sql") | Some("pg_extern") => {},
+            _ => continue,
+        }
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            // a plain #[pg_extern]
+            _ => continue,
+        };
+        // already validated by the attributes themselves, so anything that
+        // is not an SQL option is one of #[pg_extern]'s own
+        for option in list.nested {
+            options.parse_option(&option)?;
+        }
+    }
+    Ok(())
+}
+
+fn attr_name(attr: &Attribute) -> Option<String> {
+    attr.path.segments.last().map(|segment| segment.ident.to_string())
+}
+
+fn derives(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter()
+        .filter(|attr| attr.path.is_ident("derive"))
+        .any(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) => list.nested.iter().any(|nested| match nested {
+                NestedMeta::Meta(Meta::Path(path)) => path.segments.last()
+                    .map(|segment| segment.ident == name)
+                    .unwrap_or(false),
+                _ => false,
+            }),
+            _ => false,
+        })
+}
+
+/// Whether items with `attrs` are compiled outside of tests: `Some(false)`
+/// under `#[cfg(test)]`, and `None` if it depends on any other cfg.
+fn cfg_enabled(attrs: &[Attribute]) -> Option<bool> {
+    all(attrs.iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .map(|attr| match attr.parse_meta() {
+            Ok(Meta::List(list)) if list.nested.len() == 1 => eval_cfg(&list.nested[0]),
+            _ => None,
+        }))
+}
+
+fn eval_cfg(predicate: &NestedMeta) -> Option<bool> {
+    let list = match predicate {
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("test") => return Some(false),
+        NestedMeta::Meta(Meta::List(list)) => list,
+        _ => return None,
+    };
+    if list.path.is_ident("all") {
+        all(list.nested.iter().map(eval_cfg))
+    } else if list.path.is_ident("any") {
+        any(list.nested.iter().map(eval_cfg))
+    } else if list.path.is_ident("not") && list.nested.len() == 1 {
+        eval_cfg(&list.nested[0]).map(|enabled| !enabled)
+    } else {
+        None
+    }
+}
+
+/// `Some(false)` if any of `cfgs` is, otherwise `None` if any is unknown
+fn all(cfgs: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    let mut result = Some(true);
+    for cfg in cfgs {
+        match cfg {
+            Some(false) => return Some(false),
+            None => result = None,
+            Some(true) => {},
+        }
+    }
+    result
+}
+
+fn any(cfgs: impl Iterator<Item = Option<bool>>) -> Option<bool> {
+    all(cfgs.map(|cfg| cfg.map(|enabled| !enabled))).map(|enabled| !enabled)
+}
+
+fn is_fcinfo(ty: &Type) -> bool {
+    match ty {
+        Type::Reference(reference) => match &*reference.elem {
+            Type::Path(path) => path.path.segments.last()
+                .map(|segment| segment.ident == "FunctionCallInfoData")
+                .unwrap_or(false),
+            _ => false,
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,194 @@
+//! mapping from Rust types to SQL types
+
+use std::collections::HashMap;
+
+use quote::ToTokens;
+use syn::{GenericArgument, PathArguments, Type, TypeParamBound};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SqlType {
+    pub name: String,
+    /// whether the Rust type can represent NULL, i.e. it is an `Option`
+    pub nullable: bool,
+}
+
+/// Maps Rust types to SQL types based on the name of the type, as we only have
+/// the source available, not the type information.
+#[derive(Clone, Debug)]
+pub struct TypeMap {
+    types: HashMap<String, String>,
+}
+
+impl Default for TypeMap {
+    fn default() -> Self {
+        let types = [
+            ("bool", "boolean"),
+            ("i8", "\"char\""),
+            ("i16", "smallint"),
+            ("i32", "integer"),
+            ("i64", "bigint"),
+            ("f32", "real"),
+            ("f64", "double precision"),
+            ("str", "text"),
+            ("String", "text"),
+            ("PgText", "text"),
+            ("Varlena", "bytea"),
+            ("CStr", "cstring"),
+            ("CString", "cstring"),
+            ("Pox", "internal"),
+        ];
+        TypeMap {
+            types: types.iter().map(|(r, s)| (r.to_string(), s.to_string())).collect(),
+        }
+    }
+}
+
+impl TypeMap {
+    /// map the Rust type with the given name, e.g. `MyType` to `sql`
+    pub fn insert(&mut self, rust: &str, sql: &str) {
+        self.types.insert(rust.to_string(), sql.to_string());
+    }
+
+    /// whether the Rust type with the given name has been mapped
+    pub fn contains(&self, rust: &str) -> bool {
+        self.types.contains_key(rust)
+    }
+
+    /// the SQL type the Rust type with the given name is mapped to
+    pub fn get(&self, rust: &str) -> Option<String> {
+        self.types.get(rust).cloned()
+    }
+
+    /// the SQL type for a Rust type, or `None` if there is no known mapping
+    pub fn sql_type(&self, ty: &Type) -> Option<SqlType> {
+        match ty {
+            Type::Reference(reference) => self.sql_type(&reference.elem),
+            Type::Paren(paren) => self.sql_type(&paren.elem),
+            Type::Group(group) => self.sql_type(&group.elem),
+            Type::Ptr(_) => Some(not_null("internal")),
+            Type::Slice(slice) if is_named(&slice.elem, "u8") => Some(not_null("bytea")),
+            Type::Tuple(tuple) if tuple.elems.is_empty() => Some(not_null("void")),
+            Type::Path(path) => {
+                let segment = path.path.segments.last()?;
+                let name = segment.ident.to_string();
+                let generic = first_generic(&segment.arguments);
+                match (&*name, generic) {
+                    ("Option", Some(inner)) => {
+                        let inner = self.sql_type(inner)?;
+                        Some(SqlType { name: inner.name, nullable: true })
+                    },
+                    // errors are reported as postgres ERRORs
+                    ("Result", Some(inner)) => self.sql_type(inner),
+                    // stored as bytea unless it is defined as a base type
+                    ("SerdeDatum", Some(inner)) => {
+                        let sql = serde_key(inner).and_then(|key| self.types.get(&key).cloned());
+                        Some(not_null(sql.as_deref().unwrap_or("bytea")))
+                    },
+                    ("Vec", Some(inner)) if is_named(inner, "u8") => Some(not_null("bytea")),
+                    ("Vec", Some(inner)) | ("PgArray", Some(inner)) => {
+                        let element = self.sql_type(inner)?;
+                        Some(not_null(&format!("{}[]", element.name)))
+                    },
+                    _ => self.types.get(&name).map(|sql| not_null(sql)),
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+/// the Rust type as written, for error messages
+pub fn display(ty: &Type) -> String {
+    ty.to_token_stream().to_string()
+}
+
+/// the name of the type `ty` refers to, ignoring references, `Option` and
+/// `Result`, e.g. `MyRow` for `Option<&MyRow>`
+pub fn base_name(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Reference(reference) => base_name(&reference.elem),
+        Type::Paren(paren) => base_name(&paren.elem),
+        Type::Group(group) => base_name(&group.elem),
+        Type::Path(path) => {
+            let segment = path.path.segments.last()?;
+            match (&*segment.ident.to_string(), first_generic(&segment.arguments)) {
+                ("Option", Some(inner)) | ("Result", Some(inner)) => base_name(inner),
+                (name, _) => Some(name.to_string()),
+            }
+        },
+        _ => None,
+    }
+}
+
+/// the name a Rust type is mapped by: the name of the type, except for
+/// `SerdeDatum<T>`s, which are mapped by `SerdeDatum<name of T>`
+pub fn type_key(ty: &Type) -> Option<String> {
+    match serde_inner(ty) {
+        Some(inner) => serde_key(inner),
+        None => base_name(ty),
+    }
+}
+
+/// the `T` of a `SerdeDatum<T>`
+pub fn serde_inner(ty: &Type) -> Option<&Type> {
+    match ty {
+        Type::Path(path) => {
+            let segment = path.path.segments.last()?;
+            if segment.ident != "SerdeDatum" {
+                return None
+            }
+            first_generic(&segment.arguments)
+        },
+        _ => None,
+    }
+}
+
+fn serde_key(inner: &Type) -> Option<String> {
+    base_name(inner).map(|name| format!("SerdeDatum<{}>", name))
+}
+
+/// the `T` of an `impl Iterator<Item = T>` or `impl IntoIterator<Item = T>`,
+/// which set-returning functions return
+pub fn set_item(ty: &Type) -> Option<&Type> {
+    let bounds = match ty {
+        Type::ImplTrait(ty) => &ty.bounds,
+        _ => return None,
+    };
+    bounds.iter().find_map(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+            TypeParamBound::Lifetime(_) => return None,
+        };
+        if segment.ident != "Iterator" && segment.ident != "IntoIterator" {
+            return None
+        }
+        match &segment.arguments {
+            PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+                GenericArgument::Binding(binding) if binding.ident == "Item" => Some(&binding.ty),
+                _ => None,
+            }),
+            _ => None,
+        }
+    })
+}
+
+fn not_null(name: &str) -> SqlType {
+    SqlType { name: name.to_string(), nullable: false }
+}
+
+fn first_generic(arguments: &PathArguments) -> Option<&Type> {
+    match arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+fn is_named(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(path) => path.path.is_ident(name),
+        _ => false,
+    }
+}
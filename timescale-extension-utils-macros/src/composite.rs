@@ -0,0 +1,123 @@
+//! `#[derive(PgComposite)]`
+
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::{
+    parse_quote,
+    spanned::Spanned,
+    Data,
+    DeriveInput,
+    Error,
+    GenericParam,
+    Index,
+    Lifetime,
+    LifetimeDef,
+    Member,
+};
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        Data::Enum(data) => return Err(Error::new(
+            data.enum_token.span(),
+            "PgComposite can only be derived for structs",
+        )),
+        Data::Union(data) => return Err(Error::new(
+            data.union_token.span(),
+            "PgComposite can only be derived for structs",
+        )),
+    };
+    for param in &input.generics.params {
+        match param {
+            GenericParam::Lifetime(_) => {},
+            GenericParam::Type(_) | GenericParam::Const(_) => return Err(Error::new(
+                param.span(),
+                "PgComposite cannot be derived for structs generic over types or constants",
+            )),
+        }
+    }
+
+    let mut names = vec![];
+    let mut members = vec![];
+    let mut pushes = vec![];
+    let mut gets = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        names.push(match &field.ident {
+            Some(ident) => ident.to_string(),
+            None => i.to_string(),
+        });
+        // point type errors at the field
+        pushes.push(quote_spanned!(field.ty.span()=> row.push(self.#member);));
+        gets.push(quote_spanned!(field.ty.span()=> row.get()));
+        members.push(member);
+    }
+
+    let name = &input.ident;
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    // fields borrow from the row, which must outlive all of the struct's
+    // lifetimes
+    let row = Lifetime::new("'row", Span::call_site());
+    let mut generics = input.generics.clone();
+    let lifetimes: Vec<_> = generics.lifetimes().map(|def| def.lifetime.clone()).collect();
+    generics.params.insert(0, GenericParam::Lifetime(LifetimeDef::new(row.clone())));
+    let predicates = &mut generics.make_where_clause().predicates;
+    for lifetime in lifetimes {
+        predicates.push(parse_quote!(#row: #lifetime));
+    }
+    let (row_impl_generics, _, row_where_clause) = generics.split_for_impl();
+    let (impl_generics, _, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #row_impl_generics ::timescale_extension_utils::composite::PgComposite<#row>
+        for #name #ty_generics #row_where_clause {
+            const FIELDS: &'static [&'static str] = &[#(#names),*];
+
+            #[allow(unused_variables)]
+            fn to_row(self, row: &mut ::timescale_extension_utils::composite::RowBuilder) {
+                #(#pushes)*
+            }
+
+            #[allow(unused_variables)]
+            fn from_row(row: &mut ::timescale_extension_utils::composite::RowReader<#row>) -> Self {
+                Self { #(#members: #gets),* }
+            }
+        }
+
+        impl #row_impl_generics ::timescale_extension_utils::datum::FromDatum<#row>
+        for #name #ty_generics #row_where_clause {
+            unsafe fn from_datum(datum: ::timescale_extension_utils::pg_sys::Datum) -> Self {
+                ::timescale_extension_utils::composite::from_datum(datum)
+            }
+        }
+
+        impl #impl_generics ::timescale_extension_utils::datum::ToDatum
+        for #name #ty_generics #where_clause {
+            fn to_datum(self) -> ::timescale_extension_utils::pg_sys::Datum {
+                ::timescale_extension_utils::composite::to_datum(self)
+            }
+
+            fn to_datum_as(
+                self,
+                row_type: &mut ::timescale_extension_utils::composite::RowType,
+            ) -> ::timescale_extension_utils::pg_sys::Datum {
+                ::timescale_extension_utils::composite::to_datum_as(self, row_type)
+            }
+
+            fn to_columns(
+                self,
+                row_type: &mut ::timescale_extension_utils::composite::RowType,
+                values: &mut [::timescale_extension_utils::pg_sys::Datum],
+                nulls: &mut [bool],
+            ) {
+                ::timescale_extension_utils::composite::to_columns(self, row_type, values, nulls)
+            }
+        }
+
+        // composites are checked against the row type of their column instead
+        impl #impl_generics ::timescale_extension_utils::datum::TypeOid
+        for #name #ty_generics #where_clause {}
+    })
+}
@@ -0,0 +1,234 @@
+//! `#[derive(FlatSerialize)]`
+
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote, quote_spanned};
+use syn::{
+    spanned::Spanned,
+    Data,
+    DeriveInput,
+    Error,
+    ExprPath,
+    GenericParam,
+    Index,
+    Lifetime,
+    Lit,
+    Member,
+    Meta,
+    NestedMeta,
+    Type,
+};
+
+enum Kind {
+    Scalar,
+    Slice,
+    Str,
+    Vec,
+    String,
+}
+
+pub fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        Data::Enum(data) => return Err(Error::new(
+            data.enum_token.span(),
+            "FlatSerialize can only be derived for structs",
+        )),
+        Data::Union(data) => return Err(Error::new(
+            data.union_token.span(),
+            "FlatSerialize can only be derived for structs",
+        )),
+    };
+    let mut lifetimes = vec![];
+    for param in &input.generics.params {
+        match param {
+            GenericParam::Lifetime(def) => lifetimes.push(&def.lifetime),
+            GenericParam::Type(_) | GenericParam::Const(_) => return Err(Error::new(
+                param.span(),
+                "FlatSerialize cannot be derived for structs generic over types or constants",
+            )),
+        }
+    }
+    if lifetimes.len() > 1 {
+        return Err(Error::new(
+            lifetimes[1].span(),
+            "FlatSerialize cannot be derived for structs with more than one lifetime",
+        ))
+    }
+    let Options { version, read_version } = options(&input)?;
+
+    let mut members = vec![];
+    let mut locals = vec![];
+    let mut writes = vec![];
+    let mut reads = vec![];
+    let mut tail_writes = vec![];
+    let mut tail_reads = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(Index::from(i)),
+        };
+        let local = format_ident!("field{}", i);
+        let len = format_ident!("field{}_len", i);
+        // point type errors at the field
+        let span = field.ty.span();
+        match kind(&field.ty) {
+            Kind::Scalar => {
+                writes.push(quote_spanned!(span=> writer.scalar(self.#member);));
+                reads.push(quote_spanned!(span=> let #local = reader.scalar();));
+            },
+            Kind::Slice => {
+                writes.push(quote_spanned!(span=> writer.tail_len(self.#member.len());));
+                reads.push(quote_spanned!(span=> let #len = reader.tail_len();));
+                tail_writes.push(quote_spanned!(span=> writer.tail(self.#member);));
+                tail_reads.push(quote_spanned!(span=> let #local = reader.tail(#len);));
+            },
+            Kind::Str => {
+                writes.push(quote_spanned!(span=> writer.tail_len(self.#member.len());));
+                reads.push(quote_spanned!(span=> let #len = reader.tail_len();));
+                tail_writes.push(quote_spanned!(span=> writer.tail_str(self.#member);));
+                tail_reads.push(quote_spanned!(span=> let #local = reader.tail_str(#len);));
+            },
+            Kind::Vec => {
+                writes.push(quote_spanned!(span=> writer.tail_len(self.#member.len());));
+                reads.push(quote_spanned!(span=> let #len = reader.tail_len();));
+                tail_writes.push(quote_spanned!(span=> writer.tail(&self.#member[..]);));
+                tail_reads.push(quote_spanned!(span=> let #local = reader.tail(#len).to_vec();));
+            },
+            Kind::String => {
+                writes.push(quote_spanned!(span=> writer.tail_len(self.#member.len());));
+                reads.push(quote_spanned!(span=> let #len = reader.tail_len();));
+                tail_writes.push(quote_spanned!(span=> writer.tail_str(&self.#member);));
+                tail_reads.push(quote_spanned!(span=> let #local = reader.tail_str(#len).to_owned();));
+            },
+        }
+        members.push(member);
+        locals.push(local);
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    // the tails borrow from the datum for the struct's lifetime, if it has one
+    let (trait_generics, lifetime) = match lifetimes.first() {
+        Some(&lifetime) => (quote!(#impl_generics), lifetime.clone()),
+        None => {
+            let lifetime = Lifetime::new("'flat", Span::call_site());
+            (quote!(<#lifetime>), lifetime)
+        },
+    };
+    let read_version = read_version.map(|path| quote! {
+        fn read_version(
+            version: u32,
+            reader: &mut ::timescale_extension_utils::flat::FlatReader<#lifetime>,
+        ) -> Self {
+            #path(version, reader)
+        }
+    });
+    Ok(quote! {
+        impl #trait_generics ::timescale_extension_utils::flat::FlatSerialize<#lifetime>
+        for #name #ty_generics #where_clause {
+            const VERSION: u32 = #version;
+
+            #[allow(unused_variables)]
+            fn write(&self, writer: &mut ::timescale_extension_utils::flat::FlatWriter) {
+                #(#writes)*
+                #(#tail_writes)*
+            }
+
+            #[allow(unused_variables)]
+            fn read(reader: &mut ::timescale_extension_utils::flat::FlatReader<#lifetime>) -> Self {
+                #(#reads)*
+                #(#tail_reads)*
+                Self { #(#members: #locals),* }
+            }
+
+            #read_version
+        }
+
+        impl #trait_generics ::timescale_extension_utils::datum::FromDatum<#lifetime>
+        for #name #ty_generics #where_clause {
+            unsafe fn from_datum(datum: ::timescale_extension_utils::pg_sys::Datum) -> Self {
+                ::timescale_extension_utils::flat::from_datum(datum)
+            }
+        }
+
+        impl #impl_generics ::timescale_extension_utils::datum::ToDatum
+        for #name #ty_generics #where_clause {
+            fn to_datum(self) -> ::timescale_extension_utils::pg_sys::Datum {
+                ::timescale_extension_utils::flat::to_datum(&self)
+            }
+        }
+
+        // flat values can be stored in bytea as well as in their own types
+        impl #impl_generics ::timescale_extension_utils::datum::TypeOid
+        for #name #ty_generics #where_clause {}
+    })
+}
+
+fn kind(ty: &Type) -> Kind {
+    let reference = match ty {
+        Type::Reference(reference) if reference.mutability.is_none() => reference,
+        Type::Path(path) if path.qself.is_none() => {
+            // matched by name, like any derive must
+            return match path.path.segments.last() {
+                Some(last) if last.ident == "Vec" => Kind::Vec,
+                Some(last) if last.ident == "String" && last.arguments.is_empty() => Kind::String,
+                _ => Kind::Scalar,
+            }
+        },
+        _ => return Kind::Scalar,
+    };
+    match &*reference.elem {
+        Type::Slice(_) => Kind::Slice,
+        Type::Path(path) if path.qself.is_none() && path.path.is_ident("str") => Kind::Str,
+        _ => Kind::Scalar,
+    }
+}
+
+struct Options {
+    /// `version = N`, 1 by default
+    version: u32,
+    /// `read_version = "path"`, the function implementing
+    /// `FlatSerialize::read_version`
+    read_version: Option<ExprPath>,
+}
+
+/// the options from `#[flat_serialize(...)]`
+fn options(input: &DeriveInput) -> syn::Result<Options> {
+    let mut options = Options { version: 1, read_version: None };
+    for attr in &input.attrs {
+        if !attr.path.is_ident("flat_serialize") {
+            continue
+        }
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new(
+                meta.span(),
+                "expected #[flat_serialize(version = N, read_version = \"path\")]",
+            )),
+        };
+        for nested in &list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("version") => {
+                    match &nv.lit {
+                        Lit::Int(lit) => options.version = lit.base10_parse()?,
+                        lit => return Err(Error::new(lit.span(), "expected an integer version")),
+                    }
+                },
+                NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("read_version") => {
+                    match &nv.lit {
+                        Lit::Str(lit) => options.read_version = Some(lit.parse()?),
+                        lit => return Err(Error::new(
+                            lit.span(),
+                            "expected the path of a function as a string",
+                        )),
+                    }
+                },
+                nested => return Err(Error::new(
+                    nested.span(),
+                    "unknown flat_serialize option, expected `version` or `read_version`",
+                )),
+            }
+        }
+    }
+    Ok(options)
+}
@@ -0,0 +1,496 @@
+extern crate proc_macro;
+
+mod composite;
+mod flat;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Punct, Spacing, Span, TokenStream as TokenStream2, TokenTree};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+use syn::{
+    parse_macro_input,
+    spanned::Spanned,
+    AttributeArgs,
+    DeriveInput,
+    Error,
+    FnArg,
+    GenericArgument,
+    GenericParam,
+    ItemFn,
+    Meta,
+    NestedMeta,
+    Pat,
+    PathArguments,
+    ReturnType,
+    Type,
+    TypeParamBound,
+};
+use timescale_extension_sql_options::SqlOptions;
+
+/// Export an ordinary Rust function as a Postgres V1 function, with the same
+/// semantics as `pg_fn!`: arguments are converted with `FromOptionalDatum`,
+/// the return value with `ToOptionalDatum`, the body runs in
+/// `CurrentMemoryContext`, and panics are caught before they can unwind into
+/// postgres. The Rust function itself is left untouched, so it can still be
+/// called from Rust, and keeps its doc comments and attributes. The matching
+/// `pg_finfo_<name>` record is emitted as well.
+///
+/// Borrowed arguments such as `&str` point into the call's arguments, so they
+/// are only valid for the call and cannot be `'static`.
+///
+/// An argument of type `&mut FunctionCallInfoData` receives the raw `fcinfo`.
+///
+/// Functions returning `impl Iterator<Item = T>` or `impl IntoIterator<Item = T>`
+/// are set-returning functions, run using `srf::return_set`.
+///
+/// `#[pg_extern(aggregate)]` declares an aggregate transition, final or
+/// combine function, like `pg_agg!`: the first argument must be the
+/// `Option<Pox<State>>` state, as must the second of a combine function, and
+/// the body runs in the aggregate context.
+///
+/// Any of the options accepted by [`sql`](attr.sql.html) may be passed as well.
+/// ```ignore
+/// /// adds one
+/// #[pg_extern(immutable, parallel_safe)]
+/// pub fn add_one(a: i32) -> i32 {
+///     a + 1
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn pg_extern(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let func = parse_macro_input!(item as ItemFn);
+    let expanded = Options::parse(args).and_then(|options| expand_pg_extern(options, func));
+    match expanded {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// SQL metadata for a function exported by `pg_fn!`, `pg_agg!`,
+/// `pg_window_fn!` or `#[pg_extern]`, used by `timescale-extension-sql` to
+/// generate the `CREATE FUNCTION` and `CREATE AGGREGATE` statements for the
+/// extension. The attribute itself only checks that the options are valid.
+///
+/// - `immutable`, `stable` or `volatile`
+/// - `parallel_safe`, `parallel_restricted` or `parallel_unsafe`
+/// - `strict` or `called_on_null_input`, by default functions without any
+///   `Option` arguments are `STRICT`
+/// - `name = "..."` and `schema = "..."` to override the SQL name
+/// - `sfunc = "agg"`, `finalfunc = "agg"`, `combinefunc = "agg"`,
+///   `serialfunc = "agg"`, `deserialfunc = "agg"`, or for moving aggregates
+///   `msfunc = "agg"`, `minvfunc = "agg"` and `mfinalfunc = "agg"`, to use the
+///   function as that part of the aggregate `agg`; a function may have
+///   several roles
+/// - `ordered_set` or `hypothetical` on the `finalfunc` of an ordered-set or
+///   hypothetical-set aggregate, the final function's arguments after the
+///   state becoming the direct arguments of the aggregate, and the transition
+///   function's the aggregated, `WITHIN GROUP (ORDER BY ...)`, arguments
+/// ```ignore
+/// pg_agg!{
+///     #[sql(sfunc = "my_sum", parallel_safe)]
+///     pub fn my_sum_trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> { ... }
+///
+///     #[sql(finalfunc = "my_sum", parallel_safe)]
+///     pub fn my_sum_final(state: Option<Pox<i64>>) -> Option<i64> { ... }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn sql(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let mut options = SqlOptions::default();
+    for arg in &args {
+        match options.parse_option(arg) {
+            Ok(true) => {},
+            Ok(false) => return Error::new(arg.span(), "unknown sql option")
+                .to_compile_error()
+                .into(),
+            Err(err) => return err.to_compile_error().into(),
+        }
+    }
+    item
+}
+
+/// Map a struct to a Postgres row type, so it can be returned from and passed
+/// to functions, including as the rows of `RETURNS TABLE` functions. The
+/// fields map to the columns in order and must implement `TypeOid` along with
+/// `FromOptionalDatum` and `ToOptionalDatum`; see the `composite` module for
+/// how the fields are checked against the row type.
+/// ```ignore
+/// #[derive(PgComposite)]
+/// pub struct Pair {
+///     name: String,
+///     value: Option<i64>,
+/// }
+/// ```
+#[proc_macro_derive(PgComposite)]
+pub fn derive_pg_composite(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match composite::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Derive `FlatSerialize`, `FromDatum`, `ToDatum` and `TypeOid` for a struct
+/// stored as a flat varlena, see the `flat` module for the layout. Fields must
+/// be `FlatScalar`s, such as integers, floats, or arrays of them, or borrowed
+/// `&[T]` slices of them or `&str`s, which are read without copying. The
+/// struct may have at most one lifetime, which the borrowed fields must use.
+/// `Vec<T>` and `String` fields are stored exactly like `&[T]` and `&str`
+/// ones, but are copied when read, so a struct with owned fields in place of
+/// the borrowed ones can be used to build values, e.g. to return from a
+/// function, that are read back borrowing.
+/// `#[flat_serialize(version = N)]` sets the layout version, which must change
+/// whenever the fields do. Values stored with an older version are rejected,
+/// unless `read_version = "path"` names a function that reads them, with the
+/// signature of `FlatSerialize::read_version`.
+/// ```ignore
+/// #[derive(FlatSerialize)]
+/// #[flat_serialize(version = 2, read_version = "Summary::read_v1")]
+/// pub struct Summary<'a> {
+///     count: u64,
+///     sum: f64,
+///     buckets: &'a [u32],
+/// }
+///
+/// impl<'a> Summary<'a> {
+///     // version 1 had no sum
+///     fn read_v1(_version: u32, reader: &mut FlatReader<'a>) -> Self {
+///         let count = reader.scalar();
+///         let buckets_len = reader.tail_len();
+///         Summary { count, sum: f64::NAN, buckets: reader.tail(buckets_len) }
+///     }
+/// }
+/// ```
+#[proc_macro_derive(FlatSerialize, attributes(flat_serialize))]
+pub fn derive_flat_serialize(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    match flat::expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    aggregate: bool,
+}
+
+impl Options {
+    fn parse(args: AttributeArgs) -> syn::Result<Self> {
+        let mut options = Options::default();
+        // only validated here, see `sql`
+        let mut sql_options = SqlOptions::default();
+        for arg in args {
+            if sql_options.parse_option(&arg)? {
+                continue
+            }
+            match &arg {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("aggregate") => {
+                    options.aggregate = true
+                },
+                _ => return Err(Error::new(arg.span(), "unknown pg_extern option")),
+            }
+        }
+        Ok(options)
+    }
+}
+
+fn expand_pg_extern(options: Options, func: ItemFn) -> syn::Result<TokenStream2> {
+    validate_signature(&func)?;
+    let set_item = match &func.sig.output {
+        ReturnType::Type(_, ty) => set_item(ty),
+        ReturnType::Default => None,
+    };
+    if let (true, Some(item)) = (options.aggregate, set_item) {
+        return Err(Error::new(item.span(), "aggregate functions cannot return a set"))
+    }
+
+    let name = &func.sig.ident;
+    let name_str = name.to_string();
+    let wrapper = format_ident!("__pg_extern_{}", name);
+
+    let mut declarations = vec![];
+    let mut conversions = vec![];
+    let mut call_args = vec![];
+    for (i, arg) in func.sig.inputs.iter().enumerate() {
+        let arg = match arg {
+            FnArg::Typed(arg) => arg,
+            FnArg::Receiver(_) => unreachable!("rejected by validate_signature"),
+        };
+        if is_fcinfo(&arg.ty) {
+            call_args.push(quote_spanned!(arg.ty.span()=> &mut *fcinfo));
+            continue
+        }
+
+        let (var, arg_name) = match &*arg.pat {
+            Pat::Ident(pat) if pat.by_ref.is_none() && pat.subpat.is_none() =>
+                (format_ident!("{}", pat.ident), pat.ident.to_string()),
+            _ => (format_ident!("__pg_arg_{}", i), format!("${}", i + 1)),
+        };
+        declarations.push(quote!(let #var;));
+
+        let ty = &arg.ty;
+        // the first argument is the state, as is the second of combine functions
+        if options.aggregate && (i == 0 || is_state(ty)) {
+            conversions.push(quote_spanned! {ty.span()=>
+                let datum = args.next().expect("not enough arguments for aggregate state");
+                #var = <Option<*mut _> as ::timescale_extension_utils::datum::FromOptionalDatum>
+                    ::from_optional_datum(datum)
+                    .map(|p| ::timescale_extension_utils::palloc::Pox::from_raw_unchecked(p));
+            });
+            call_args.push(quote!(#var));
+            continue
+        }
+
+        let missing = format!("missing argument \"{}\"", arg_name);
+        let null = format!("NULL value for non-nullable argument \"{}\"", arg_name);
+        conversions.push(quote_spanned! {ty.span()=>
+            let datum = args.next().unwrap_or_else(|| {
+                ::timescale_extension_utils::elog!(
+                    ::timescale_extension_utils::elog::Level::Error, #missing);
+                unreachable!()
+            });
+            #var = ::timescale_extension_utils::datum::FromOptionalDatum
+                ::try_from_optional_datum(datum)
+                .unwrap_or_else(|| {
+                    ::timescale_extension_utils::elog!(
+                        ::timescale_extension_utils::elog::Level::Error, #null);
+                    unreachable!()
+                });
+        });
+        call_args.push(quote!(#var));
+    }
+
+    if options.aggregate && declarations.is_empty() {
+        return Err(Error::new(
+            func.sig.paren_token.span,
+            "aggregate functions must take the aggregate state as their first argument",
+        ))
+    }
+
+    let call = quote!(#name(#(#call_args),*));
+    let call = match &func.sig.unsafety {
+        Some(_) => quote!(unsafe { #call }),
+        None => call,
+    };
+    let convert_args = quote! {
+        #(#declarations)*
+        {
+            #[allow(unused_variables, unused_mut)]
+            let mut args = ::timescale_extension_utils::get_args(&*fcinfo);
+            #(#conversions)*
+        }
+    };
+    let to_datum = match (&func.sig.output, set_item) {
+        (ReturnType::Default, _) => quote!({ #convert_args #call; None }),
+        // the arguments are only converted on the first call of the set
+        (_, Some(item)) => quote_spanned! {item.span()=>
+            ::timescale_extension_utils::srf::return_set::<#item, _, _>(fcinfo, |fcinfo| {
+                #convert_args
+                #call
+            })
+        },
+        (ReturnType::Type(_, ty), None) => {
+            let ret = quote_spanned! {ty.span()=>
+                ::timescale_extension_utils::composite::returning(fcinfo, ret)
+            };
+            quote!({
+                #convert_args
+                let ret = #call;
+                #ret
+            })
+        },
+    };
+
+    let context = if options.aggregate {
+        let not_agg = format!("must call {} as an aggregate", name_str);
+        // we're outside of catch_unwind here, so we must longjmp directly
+        let pound = Punct::new('#', Spacing::Alone);
+        quote! {{
+            let mut agg_ctx: ::timescale_extension_utils::pg_sys::MemoryContext =
+                ::std::ptr::null_mut();
+            if ::timescale_extension_utils::pg_sys::AggCheckCallContext(fcinfo, &mut agg_ctx) == 0 {
+                ::timescale_extension_utils::elog!(#pound unguarded
+                    ::timescale_extension_utils::elog::Level::Error, #not_agg);
+            }
+            agg_ctx
+        }}
+    } else {
+        quote!(::timescale_extension_utils::pg_sys::CurrentMemoryContext)
+    };
+
+    Ok(quote! {
+        #func
+
+        ::timescale_extension_utils::pg_finfo!(#name);
+
+        #[doc(hidden)]
+        #[export_name = #name_str]
+        pub extern "C" fn #wrapper(
+            fcinfo: ::timescale_extension_utils::pg_sys::FunctionCallInfo,
+        ) -> ::timescale_extension_utils::pg_sys::Datum {
+            use ::std::panic::{catch_unwind, AssertUnwindSafe};
+            // use a direct deref since this must always be set, and we can't risk a panic
+            #[allow(unused_unsafe)]
+            unsafe {
+                let fcinfo = &mut *fcinfo;
+                let context = #context;
+                ::timescale_extension_utils::palloc::in_context(context, || {
+                    // guard against panics in the rust code so we don't unwind into pg
+                    let result: Result<Option<::timescale_extension_utils::pg_sys::Datum>, _> =
+                        catch_unwind(AssertUnwindSafe(|| #to_datum));
+                    match result {
+                        Ok(Some(datum)) => {
+                            ::timescale_extension_utils::FcInfo::set_return_null(fcinfo, false);
+                            datum
+                        },
+                        Ok(None) => {
+                            ::timescale_extension_utils::FcInfo::set_return_null(fcinfo, true);
+                            0
+                        },
+                        Err(err) => {
+                            ::timescale_extension_utils::FcInfo::set_return_null(fcinfo, true);
+                            ::timescale_extension_utils::handle_unwind(err)
+                        },
+                    }
+                })
+            }
+        }
+    })
+}
+
+/// reject the signatures we cannot generate a wrapper for, pointing at the
+/// offending part of the signature
+fn validate_signature(func: &ItemFn) -> syn::Result<()> {
+    let sig = &func.sig;
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(Error::new(asyncness.span(), "pg_extern functions cannot be async"))
+    }
+    if let Some(abi) = &sig.abi {
+        return Err(Error::new(abi.span(), "pg_extern functions must use the Rust ABI"))
+    }
+    if let Some(variadic) = &sig.variadic {
+        return Err(Error::new(variadic.span(), "pg_extern functions cannot be variadic"))
+    }
+    for param in &sig.generics.params {
+        match param {
+            GenericParam::Lifetime(_) => {},
+            GenericParam::Type(_) | GenericParam::Const(_) => return Err(Error::new(
+                param.span(),
+                "pg_extern functions cannot be generic over types or constants",
+            )),
+        }
+    }
+    for arg in &sig.inputs {
+        match arg {
+            FnArg::Receiver(receiver) => return Err(Error::new(
+                receiver.span(),
+                "pg_extern functions cannot take `self`",
+            )),
+            FnArg::Typed(arg) => {
+                if let Type::ImplTrait(ty) = &*arg.ty {
+                    return Err(Error::new(
+                        ty.span(),
+                        "pg_extern arguments must be concrete types that implement `FromOptionalDatum`",
+                    ))
+                }
+                if let Some(span) = static_lifetime(arg.ty.to_token_stream()) {
+                    return Err(Error::new(
+                        span,
+                        "pg_extern arguments borrow from the call and cannot be `'static`",
+                    ))
+                }
+            },
+        }
+    }
+    if let ReturnType::Type(_, ty) = &sig.output {
+        if let (Type::ImplTrait(ty), None) = (&**ty, set_item(ty)) {
+            return Err(Error::new(
+                ty.span(),
+                "pg_extern return values must be concrete types that implement `ToOptionalDatum`, \
+                or `impl Iterator<Item = T>` for set-returning functions",
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// the span of the first `'static` within `tokens`
+fn static_lifetime(tokens: TokenStream2) -> Option<Span> {
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(punct) if punct.as_char() == '\'' => match tokens.peek() {
+                Some(TokenTree::Ident(ident)) if ident == "static" => return Some(ident.span()),
+                _ => {},
+            },
+            TokenTree::Group(group) => if let Some(span) = static_lifetime(group.stream()) {
+                return Some(span)
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+/// the `T` of an `impl Iterator<Item = T>` or `impl IntoIterator<Item = T>`
+/// return type, i.e. a set-returning function
+fn set_item(ty: &Type) -> Option<&Type> {
+    let bounds = match ty {
+        Type::ImplTrait(ty) => &ty.bounds,
+        _ => return None,
+    };
+    bounds.iter().find_map(|bound| {
+        let segment = match bound {
+            TypeParamBound::Trait(bound) => bound.path.segments.last()?,
+            TypeParamBound::Lifetime(_) => return None,
+        };
+        if segment.ident != "Iterator" && segment.ident != "IntoIterator" {
+            return None
+        }
+        match &segment.arguments {
+            PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+                GenericArgument::Binding(binding) if binding.ident == "Item" => Some(&binding.ty),
+                _ => None,
+            }),
+            _ => None,
+        }
+    })
+}
+
+/// `Option<Pox<_>>` arguments of aggregate functions are states
+fn is_state(ty: &Type) -> bool {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last(),
+        _ => None,
+    };
+    let inner = match segment {
+        Some(segment) if segment.ident == "Option" => match &segment.arguments {
+            PathArguments::AngleBracketed(args) => args.args.first(),
+            _ => None,
+        },
+        _ => None,
+    };
+    match inner {
+        Some(GenericArgument::Type(Type::Path(path))) => path.path.segments.last()
+            .map(|segment| segment.ident == "Pox")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// `&mut FunctionCallInfoData` arguments get the raw fcinfo, like `; fcinfo`
+/// does for `pg_fn!`
+fn is_fcinfo(ty: &Type) -> bool {
+    let reference = match ty {
+        Type::Reference(reference) if reference.mutability.is_some() => reference,
+        _ => return false,
+    };
+    match &*reference.elem {
+        Type::Path(path) => path.path.segments.last()
+            .map(|segment| segment.ident == "FunctionCallInfoData")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
@@ -0,0 +1,28 @@
+// Re-emit the `pg_ge_N` cfgs of postgres-headers-rs, so this crate can gate
+// on version ranges too, and pass the supported versions and the major
+// version on to the build scripts of extensions as
+// `DEP_TIMESCALE_EXTENSION_UTILS_SUPPORTED_VERSIONS` and
+// `DEP_TIMESCALE_EXTENSION_UTILS_MAJOR_VERSION`. Extensions can set the same
+// cfgs for themselves with a build script like this one.
+use std::env;
+
+fn main() {
+    let versions = env::var("DEP_POSTGRES_HEADERS_SUPPORTED_VERSIONS")
+        .expect("postgres-headers-rs did not pass on its supported versions");
+    let supported: Vec<u32> = versions.split(',')
+        .map(|v| v.parse().expect("invalid postgres major version"))
+        .collect();
+    let cfgs: Vec<_> = supported.iter().map(|v| format!("pg_ge_{}", v)).collect();
+    println!("cargo:rustc-check-cfg=cfg({})", cfgs.join(", "));
+    println!("cargo:supported_versions={}", versions);
+
+    // postgres-headers-rs reports a missing version feature
+    let version: u32 = match env::var("DEP_POSTGRES_HEADERS_MAJOR_VERSION") {
+        Ok(version) => version.parse().expect("invalid postgres major version"),
+        Err(..) => return,
+    };
+    for v in supported.into_iter().filter(|&v| v <= version) {
+        println!("cargo:rustc-cfg=pg_ge_{}", v);
+    }
+    println!("cargo:major_version={}", version);
+}
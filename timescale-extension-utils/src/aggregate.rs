@@ -0,0 +1,265 @@
+//! support for aggregates, see `pg_agg!`
+
+use std::{
+    marker::PhantomData,
+    mem,
+    os::raw::c_char,
+    ptr,
+};
+
+use crate::{
+    datum::{format_type, FromOptionalDatum, ToOptionalDatum, TypeOid},
+    elog::{Level::Error, SqlState},
+    guard_pg,
+    palloc::Pox,
+    pg_sys::{self, Datum, Oid},
+    srf::work_mem,
+    FunctionCallInfoData,
+};
+
+/// The serialization of an aggregate's `internal` state, used to send partial
+/// aggregates between parallel workers. `pg_agg!` serialization and
+/// deserialization functions are implemented with it.
+pub trait SerializableState: Sized {
+    fn serialize(&self) -> Vec<u8>;
+
+    /// the state serialized as `bytes`, which were produced by `serialize`
+    fn deserialize(bytes: &[u8]) -> Self;
+}
+
+// aggkind values from pg_aggregate.h, which the bindings do not include
+const AGGKIND_ORDERED_SET: c_char = b'o' as c_char;
+const AGGKIND_HYPOTHETICAL: c_char = b'h' as c_char;
+
+extern "C" {
+    fn exprType(expr: *const pg_sys::Node) -> Oid;
+    fn exprCollation(expr: *const pg_sys::Node) -> Oid;
+}
+
+/// The `Aggref` of the aggregate call `fcinfo` is part of, or `None` when
+/// called outside of an aggregate. For ordered-set aggregates its `args` are
+/// the aggregated arguments, and its `aggdirectargs` the direct ones.
+pub fn aggref(fcinfo: &mut FunctionCallInfoData) -> Option<&pg_sys::Aggref> {
+    unsafe { pg_sys::AggGetAggref(fcinfo).as_ref() }
+}
+
+/// The aggregated input of an ordered-set aggregate, sorted as requested by
+/// its `WITHIN GROUP (ORDER BY ...)` clause. Intended to be the `internal`
+/// state of the aggregate: the transition function creates it on its first
+/// call and `put`s each aggregated value, and the final function, which
+/// receives the direct arguments, reads the values back out in order.
+///
+/// The underlying tuplesort lives in the aggregate memory context, and is
+/// ended when the aggregate is shut down. Only a single `ORDER BY` column is
+/// supported.
+/// ```ignore
+/// pg_agg!{
+///     pub fn my_median_trans(state: Option<Pox<OrderedSetSort>>, value: Option<f64>; fcinfo)
+///     -> Option<Pox<OrderedSetSort>> {
+///         let mut state = state.unwrap_or_else(|| unsafe { OrderedSetSort::new(fcinfo) });
+///         if value.is_some() {
+///             state.put(value);
+///         }
+///         Some(state)
+///     }
+///
+///     pub fn my_median_final(state: Option<Pox<OrderedSetSort>>) -> Option<f64> {
+///         let mut state = state?;
+///         let middle = state.len().checked_sub(1)? / 2;
+///         state.sorted::<f64>().nth(middle)
+///     }
+/// }
+/// ```
+pub struct OrderedSetSort {
+    sort: *mut pg_sys::Tuplesortstate,
+    type_id: Oid,
+    len: usize,
+    sorted: bool,
+    random_access: bool,
+}
+
+impl OrderedSetSort {
+    /// Start sorting the aggregated argument of the ordered-set aggregate call
+    /// `fcinfo`, allocating the sort in the current memory context, which
+    /// should be the aggregate context.
+    ///
+    /// # Safety
+    /// `fcinfo` must be the `fcinfo` of the current transition function call
+    pub unsafe fn new(fcinfo: &mut FunctionCallInfoData) -> Pox<Self> {
+        let aggref = match aggref(fcinfo) {
+            Some(aggref) if aggref.aggkind == AGGKIND_ORDERED_SET
+                || aggref.aggkind == AGGKIND_HYPOTHETICAL => aggref,
+            _ => {
+                crate::ereport!(Error, SqlState::FeatureNotSupported,
+                    "ordered-set aggregate support function called in non-ordered-set-aggregate context");
+                unreachable!()
+            },
+        };
+        let order = aggref.aggorder;
+        if order.is_null() || (*order).length != 1 {
+            crate::ereport!(Error, SqlState::FeatureNotSupported,
+                "ordered-set aggregates can only sort by a single column");
+        }
+        let clause = guard_pg(|| pg_sys::macros::list_nth(order, 0));
+        let clause = &*(clause as *const pg_sys::SortGroupClause);
+        let entry = sort_entry(aggref.args, clause.tleSortGroupRef);
+        let expr = (*entry).expr as *const pg_sys::Node;
+        let type_id = guard_pg(|| exprType(expr));
+        let collation = guard_pg(|| exprCollation(expr));
+        // states shared between aggregates may have their final function
+        // called more than once, which requires rescanning the sort
+        let random_access = guard_pg(|| pg_sys::AggStateIsShared(fcinfo));
+        // the randomAccess flag became part of the sortopt flags in 15
+        #[cfg(not(pg_ge_15))]
+        let sort_options = random_access;
+        #[cfg(pg_ge_15)]
+        let sort_options = match random_access {
+            true => pg_sys::TUPLESORT_RANDOMACCESS as _,
+            false => pg_sys::TUPLESORT_NONE as _,
+        };
+        let sort = guard_pg(|| pg_sys::tuplesort_begin_datum(
+            type_id,
+            clause.sortop,
+            collation,
+            clause.nulls_first,
+            work_mem,
+            ptr::null_mut(),
+            sort_options,
+        ));
+        let state = Pox::new(OrderedSetSort { sort, type_id, len: 0, sorted: false, random_access });
+        let ptr = state.into_raw();
+        guard_pg(|| pg_sys::AggRegisterCallback(fcinfo, Some(end_sort), ptr as Datum));
+        Pox::from_raw_unchecked(ptr)
+    }
+
+    /// the number of values put into the sort
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add `value` to the sort. `NULL`s are sorted like any other value; most
+    /// ordered-set aggregates ignore them, which is up to the caller.
+    pub fn put<T: ToOptionalDatum + TypeOid>(&mut self, value: T) {
+        if self.sorted {
+            crate::ereport!(Error, SqlState::ObjectNotInPrerequisiteState,
+                "cannot add values to an ordered-set aggregate after it has been sorted");
+        }
+        self.check_type::<T>();
+        let datum = value.to_optional_datum();
+        unsafe {
+            guard_pg(|| pg_sys::tuplesort_putdatum(self.sort, datum.unwrap_or(0), datum.is_none()))
+        }
+        self.len += 1;
+    }
+
+    /// Sort the values, returning them in order. May be called more than once
+    /// only when postgres shares the state between aggregates.
+    pub fn sorted<'a, T: FromOptionalDatum<'a> + TypeOid>(&'a mut self) -> Sorted<'a, T> {
+        self.check_type::<T>();
+        if self.sort.is_null() {
+            crate::ereport!(Error, SqlState::ObjectNotInPrerequisiteState,
+                "ordered-set aggregate state used after the aggregate was shut down");
+        }
+        if self.sorted && !self.random_access {
+            crate::ereport!(Error, SqlState::ObjectNotInPrerequisiteState,
+                "ordered-set aggregate values can only be read once");
+        }
+        unsafe {
+            if self.sorted {
+                guard_pg(|| pg_sys::tuplesort_rescan(self.sort))
+            } else {
+                guard_pg(|| pg_sys::tuplesort_performsort(self.sort))
+            }
+        }
+        self.sorted = true;
+        Sorted { sort: self, _type: PhantomData }
+    }
+
+    fn check_type<T: TypeOid>(&self) {
+        match T::type_oid() {
+            Some(type_id) if type_id != self.type_id => {
+                let (expected, found) = (format_type(self.type_id), format_type(type_id));
+                crate::ereport!(Error, SqlState::DatatypeMismatch,
+                    "ordered-set aggregate sorts type {} but was used with type {}", expected, found);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// The values of an [`OrderedSetSort`], in sorted order.
+pub struct Sorted<'a, T> {
+    sort: &'a mut OrderedSetSort,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: FromOptionalDatum<'a>> Iterator for Sorted<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let mut value = 0;
+        let mut is_null = false;
+        // by-reference values are only copied on request since 16, and must
+        // be, as the sort may reuse their memory on the next fetch
+        #[cfg(not(pg_ge_16))]
+        let found = unsafe {
+            guard_pg(|| pg_sys::tuplesort_getdatum(
+                self.sort.sort,
+                true,
+                &mut value,
+                &mut is_null,
+                ptr::null_mut(),
+            ))
+        };
+        #[cfg(pg_ge_16)]
+        let found = unsafe {
+            guard_pg(|| pg_sys::tuplesort_getdatum(
+                self.sort.sort,
+                true,
+                true,
+                &mut value,
+                &mut is_null,
+                ptr::null_mut(),
+            ))
+        };
+        if !found {
+            return None
+        }
+        let datum = if is_null { None } else { Some(value) };
+        // by-reference values are copied into the current memory context
+        let value = unsafe { T::try_from_optional_datum(datum) }.unwrap_or_else(|| {
+            crate::ereport!(Error, SqlState::NullValueNotAllowed,
+                "NULL value in ordered-set aggregate read as non-nullable type");
+            unreachable!()
+        });
+        Some(value)
+    }
+}
+
+/// the `TargetEntry` in `args` with the sort group reference `sort_ref`
+unsafe fn sort_entry(args: *mut pg_sys::List, sort_ref: pg_sys::Index) -> *mut pg_sys::TargetEntry {
+    let len = if args.is_null() { 0 } else { (*args).length };
+    for i in 0..len {
+        let entry = guard_pg(|| pg_sys::macros::list_nth(args, i)) as *mut pg_sys::TargetEntry;
+        if (*entry).ressortgroupref == sort_ref {
+            return entry
+        }
+    }
+    crate::ereport!(Error, SqlState::InternalError,
+        "ORDER BY column of ordered-set aggregate not found in its arguments");
+    unreachable!()
+}
+
+/// end the sort of the `OrderedSetSort` passed as `arg` when the aggregate
+/// is shut down, which releases any temporary files it uses
+unsafe extern "C" fn end_sort(arg: Datum) {
+    let state = arg as *mut OrderedSetSort;
+    let sort = mem::replace(&mut (*state).sort, ptr::null_mut());
+    if !sort.is_null() {
+        pg_sys::tuplesort_end(sort)
+    }
+}
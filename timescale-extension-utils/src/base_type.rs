@@ -0,0 +1,82 @@
+//! custom base types, see `pg_type!`
+//!
+//! A type implementing [`PgType`] is stored as a varlena containing its
+//! binary, `send`, representation, so `receive` must accept anything `send`
+//! produces. `pg_type!` generates the type's input, output, receive and send
+//! functions, along with its `FromDatum` and `ToDatum` impls, from the trait.
+
+use std::{
+    ffi::{CStr, CString},
+    slice,
+};
+
+use crate::{
+    datum::{FromDatum, ToDatum},
+    elog::{Level::Error, SqlState},
+    pg_sys::{self, Datum},
+};
+
+/// The text and binary representations of a custom base type.
+pub trait PgType: Sized {
+    /// parse the text representation of a value, or describe why it is invalid
+    fn input(input: &CStr) -> Result<Self, String>;
+
+    /// the text representation of the value, which `input` must accept
+    fn output(&self) -> CString;
+
+    /// the binary representation of the value, which is also how it is stored
+    fn send(&self) -> Vec<u8>;
+
+    /// parse the binary representation of a value, or describe why it is
+    /// invalid
+    fn receive(bytes: &[u8]) -> Result<Self, String>;
+}
+
+/// Store `value` as a varlena containing its binary representation.
+pub fn to_datum<T: PgType>(value: &T) -> Datum {
+    value.send().to_datum()
+}
+
+/// Read a value stored by [`to_datum`].
+///
+/// # Safety
+/// `datum` must be a varlena stored by `to_datum::<T>`
+pub unsafe fn from_datum<T: PgType>(datum: Datum) -> T {
+    let bytes = <&[u8]>::from_datum(datum);
+    T::receive(bytes).unwrap_or_else(|message| {
+        crate::ereport!(Error, SqlState::DataCorrupted,
+            "invalid stored value of type {}: {}", std::any::type_name::<T>(), message);
+        unreachable!()
+    })
+}
+
+/// the body of the input function generated by `pg_type!`
+#[doc(hidden)]
+pub fn input<T: PgType>(input: &CStr) -> T {
+    T::input(input).unwrap_or_else(|message| {
+        crate::ereport!(Error, SqlState::InvalidTextRepresentation,
+            "invalid input syntax for type {}: {}", std::any::type_name::<T>(), message);
+        unreachable!()
+    })
+}
+
+/// the body of the receive function generated by `pg_type!`, which reads the
+/// rest of `buf`
+///
+/// # Safety
+/// `buf` must be the `StringInfo` passed to the receive function
+#[doc(hidden)]
+pub unsafe fn receive<T: PgType>(buf: *mut pg_sys::StringInfoData) -> T {
+    let buf = &mut *buf;
+    let bytes = slice::from_raw_parts(
+        buf.data.add(buf.cursor as usize) as *const u8,
+        (buf.len - buf.cursor) as usize,
+    );
+    let value = T::receive(bytes).unwrap_or_else(|message| {
+        crate::ereport!(Error, SqlState::InvalidBinaryRepresentation,
+            "invalid binary representation of type {}: {}", std::any::type_name::<T>(), message);
+        unreachable!()
+    });
+    buf.cursor = buf.len;
+    value
+}
@@ -0,0 +1,380 @@
+//! composite (row) values, see `#[derive(PgComposite)]`
+//!
+//! A struct deriving `PgComposite` converts to and from a row, field by field
+//! in declaration order. The fields are checked against the columns of the
+//! row type at runtime: the number of fields must match the number of
+//! (non-dropped) columns, and fields whose type has a `TypeOid` must match
+//! their column's type exactly.
+//!
+//! Building a row needs its row type, a [`RowType`], which is passed to
+//! `ToDatum::to_datum_as()`. When a composite is returned from a function the
+//! row type is the result type of the call, as determined by
+//! `get_call_result_type()`, which allows returning `RETURNS TABLE` and `OUT`
+//! parameter records as well as named composite types. Composites nested
+//! within composites use the type of their column, and SPI parameters the
+//! type they are given with `SpiArg::with_type()`.
+
+use std::{
+    any::type_name,
+    ffi::CStr,
+    marker::PhantomData,
+    mem,
+    os::raw::c_uint,
+    ptr,
+};
+
+use crate::{
+    datum::{format_type, FromOptionalDatum, ToOptionalDatum, TypeOid},
+    elog::{Level::Error, SqlState},
+    guard_pg,
+    palloc::in_context,
+    pg_sys::{self, macros::VARSIZE, Datum, MemoryContext, Oid, TupleDesc},
+    FunctionCallInfoData,
+};
+
+/// A Rust struct that maps to a Postgres row type. Usually derived with
+/// `#[derive(PgComposite)]`, which also implements `FromDatum`, `ToDatum` and
+/// `TypeOid` for the struct using [`from_datum`] and [`to_datum`]. Fields read
+/// from a row may borrow from it for `'a`; rows built to be returned should
+/// own their fields instead, using `String` or `Vec<T>` in place of `&'a str`
+/// or `&'a [T]`, as the row type only depends on the column types.
+pub trait PgComposite<'a>: Sized {
+    /// the names of the fields, in column order
+    const FIELDS: &'static [&'static str];
+
+    /// push each of the fields onto `row`, in column order
+    fn to_row(self, row: &mut RowBuilder);
+
+    /// read each of the fields from `row`, in column order
+    fn from_row(row: &mut RowReader<'a>) -> Self;
+}
+
+const INVALID_OID: Oid = 0;
+
+// TypeFuncClass from funcapi.h, which the bindings do not include
+type TypeFuncClass = c_uint;
+const TYPEFUNC_COMPOSITE: TypeFuncClass = 1;
+const TYPEFUNC_COMPOSITE_DOMAIN: TypeFuncClass = 2;
+
+extern "C" {
+    fn get_call_result_type(
+        fcinfo: pg_sys::FunctionCallInfo,
+        resultTypeId: *mut Oid,
+        resultTupleDesc: *mut TupleDesc,
+    ) -> TypeFuncClass;
+    fn BlessTupleDesc(tupdesc: TupleDesc) -> TupleDesc;
+    fn lookup_rowtype_tupdesc(type_id: Oid, typmod: i32) -> TupleDesc;
+}
+
+/// The row type to build a composite value as.
+pub struct RowType(Source);
+
+enum Source {
+    /// the result type of a call; its blessed descriptor is built in the
+    /// context the first time it is needed and then kept in the slot
+    Result(*mut FunctionCallInfoData, *mut TupleDesc, MemoryContext),
+    Type(Oid, i32),
+    Desc(TupleDesc),
+}
+
+impl RowType {
+    /// the composite type `type_id`, with the type modifier `typmod`, `-1`
+    /// if there is none; it is only looked up if a row is built
+    pub fn of_type(type_id: Oid, typmod: i32) -> Self {
+        RowType(Source::Type(type_id, typmod))
+    }
+
+    /// the row type described by `desc`
+    ///
+    /// # Safety
+    /// `desc` must remain valid for as long as this is used, and be blessed
+    /// if it describes a `record`
+    pub unsafe fn of_desc(desc: TupleDesc) -> Self {
+        RowType(Source::Desc(desc))
+    }
+
+    /// The result type of the function call `fcinfo`. The descriptor is
+    /// stored in `cache`, which must start out null, and is allocated in
+    /// `context`, so that it is only built once for as long as `cache` and
+    /// `context` live.
+    ///
+    /// # Safety
+    /// `fcinfo` must be the `fcinfo` of the current function call, and a
+    /// descriptor in `cache` must be one stored for the same `fcinfo` and
+    /// `context`
+    pub(crate) unsafe fn result(
+        fcinfo: *mut FunctionCallInfoData,
+        cache: &mut TupleDesc,
+        context: MemoryContext,
+    ) -> Self {
+        RowType(Source::Result(fcinfo, cache, context))
+    }
+
+    /// run `f` with the descriptor of the row type
+    unsafe fn with_desc<T>(&mut self, f: impl FnOnce(TupleDesc) -> T) -> T {
+        match self.0 {
+            Source::Result(fcinfo, cache, context) => {
+                if (*cache).is_null() {
+                    *cache = in_context(context, || result_tuple_desc(fcinfo));
+                }
+                f(*cache)
+            },
+            Source::Type(type_id, typmod) => {
+                let desc = guard_pg(|| lookup_rowtype_tupdesc(type_id, typmod));
+                let result = f(desc);
+                release_tuple_desc(desc);
+                result
+            },
+            Source::Desc(desc) => f(desc),
+        }
+    }
+}
+
+/// Convert `value`, the return value of the function call `fcinfo`, such
+/// that a composite uses the result type of the call. `pg_fn!`,
+/// `#[pg_extern]` and set-returning functions do this already.
+///
+/// # Safety
+/// `fcinfo` must be the `fcinfo` of the current function call
+pub unsafe fn returning<T: ToOptionalDatum>(fcinfo: *mut FunctionCallInfoData, value: T)
+-> Option<Datum> {
+    let mut desc = ptr::null_mut();
+    value.to_optional_datum_as(&mut RowType::result(fcinfo, &mut desc, pg_sys::CurrentMemoryContext))
+}
+
+/// whether the function call `fcinfo` returns a composite type
+pub(crate) unsafe fn returns_composite(fcinfo: *mut FunctionCallInfoData) -> bool {
+    let mut desc = ptr::null_mut();
+    let class = guard_pg(|| get_call_result_type(fcinfo, ptr::null_mut(), &mut desc));
+    class == TYPEFUNC_COMPOSITE || class == TYPEFUNC_COMPOSITE_DOMAIN
+}
+
+/// the blessed descriptor of the result type of the call `fcinfo`
+unsafe fn result_tuple_desc(fcinfo: *mut FunctionCallInfoData) -> TupleDesc {
+    let mut desc = ptr::null_mut();
+    let class = guard_pg(|| get_call_result_type(fcinfo, ptr::null_mut(), &mut desc));
+    if class != TYPEFUNC_COMPOSITE && class != TYPEFUNC_COMPOSITE_DOMAIN {
+        crate::ereport!(Error, SqlState::FeatureNotSupported,
+            "function returning record called in context that cannot accept type record");
+    }
+    guard_pg(|| BlessTupleDesc(desc))
+}
+
+/// `ReleaseTupleDesc()`
+unsafe fn release_tuple_desc(desc: TupleDesc) {
+    if (*desc).tdrefcount >= 0 {
+        guard_pg(|| pg_sys::DecrTupleDescRefCount(desc))
+    }
+}
+
+unsafe fn attributes<'a>(desc: TupleDesc) -> &'a [pg_sys::FormData_pg_attribute] {
+    (*desc).attrs.as_slice((*desc).natts as usize)
+}
+
+/// check that `desc` has a column for every field of `T`
+unsafe fn check_columns<'a, T: PgComposite<'a>>(desc: TupleDesc) {
+    let columns = attributes(desc).iter().filter(|att| !att.attisdropped).count();
+    if columns != T::FIELDS.len() {
+        crate::ereport!(Error, SqlState::DatatypeMismatch,
+            "row type has {} columns but {} has {} fields",
+            columns,
+            type_name::<T>(),
+            T::FIELDS.len(),
+        );
+    }
+}
+
+/// check that a field of type `T` can be stored in the column `att`
+unsafe fn check_type<T: TypeOid>(field: &str, att: &pg_sys::FormData_pg_attribute) {
+    match T::type_oid() {
+        Some(type_id) if type_id != att.atttypid => {
+            crate::ereport!(Error, SqlState::DatatypeMismatch,
+                "field \"{}\" has type {} but column \"{}\" has type {}",
+                field,
+                format_type(type_id),
+                CStr::from_ptr(att.attname.data.as_ptr()).to_string_lossy(),
+                format_type(att.atttypid),
+            );
+        },
+        _ => {},
+    }
+}
+
+/// the index of the next non-dropped column at or after `*column`, advancing
+/// `*column` past it
+unsafe fn next_column(desc: TupleDesc, column: &mut usize) -> usize {
+    let attrs = attributes(desc);
+    while attrs[*column].attisdropped {
+        *column += 1
+    }
+    *column += 1;
+    *column - 1
+}
+
+/// Builds the columns of a row from the fields of a `PgComposite`.
+pub struct RowBuilder<'r> {
+    desc: TupleDesc,
+    values: &'r mut [Datum],
+    nulls: &'r mut [bool],
+    column: usize,
+    field: usize,
+    fields: &'static [&'static str],
+}
+
+impl RowBuilder<'_> {
+    /// set the next column to `value`
+    pub fn push<T: ToOptionalDatum + TypeOid>(&mut self, value: T) {
+        unsafe {
+            let column = next_column(self.desc, &mut self.column);
+            let att = &attributes(self.desc)[column];
+            check_type::<T>(self.fields[self.field], att);
+            self.field += 1;
+            let datum = value.to_optional_datum_as(&mut RowType::of_type(att.atttypid, att.atttypmod));
+            if let Some(datum) = datum {
+                self.values[column] = datum;
+                self.nulls[column] = false;
+            }
+        }
+    }
+}
+
+/// Reads the columns of a row into the fields of a `PgComposite`.
+pub struct RowReader<'a> {
+    desc: TupleDesc,
+    values: Vec<Datum>,
+    nulls: Vec<bool>,
+    column: usize,
+    field: usize,
+    fields: &'static [&'static str],
+    _row: PhantomData<&'a ()>,
+}
+
+impl<'a> RowReader<'a> {
+    /// the value of the next column
+    pub fn get<T: FromOptionalDatum<'a> + TypeOid>(&mut self) -> T {
+        let field = self.fields[self.field];
+        self.field += 1;
+        let datum = unsafe {
+            let column = next_column(self.desc, &mut self.column);
+            check_type::<T>(field, &attributes(self.desc)[column]);
+            if self.nulls[column] { None } else { Some(self.values[column]) }
+        };
+        // the row outlives 'a, see from_datum()
+        unsafe { T::try_from_optional_datum(datum) }.unwrap_or_else(|| {
+            crate::ereport!(Error, SqlState::NullValueNotAllowed,
+                "NULL value for non-nullable field \"{}\"", field);
+            unreachable!()
+        })
+    }
+}
+
+/// `ToDatum::to_datum()` for composites, which cannot be built without a row
+/// type; this ERRORs, see [`to_datum_as`].
+pub fn to_datum<'a, T: PgComposite<'a>>(_value: T) -> Datum {
+    crate::ereport!(Error, SqlState::FeatureNotSupported,
+        "{} converted to a row without a row type, use ToDatum::to_datum_as()", type_name::<T>());
+    unreachable!()
+}
+
+/// Convert `value` to a row of the type `row_type`.
+pub fn to_datum_as<'a, T: PgComposite<'a>>(value: T, row_type: &mut RowType) -> Datum {
+    unsafe {
+        row_type.with_desc(|desc| {
+            let natts = (*desc).natts as usize;
+            let (mut values, mut nulls) = (vec![0; natts], vec![true; natts]);
+            build_row(value, desc, &mut values, &mut nulls);
+            guard_pg(|| {
+                let tuple = pg_sys::heap_form_tuple(desc, values.as_mut_ptr(), nulls.as_mut_ptr());
+                pg_sys::heap_copy_tuple_as_datum(tuple, desc)
+            })
+        })
+    }
+}
+
+/// Convert `value` to the columns of a row of the type `row_type`, without
+/// building the row itself, e.g. to store it in a tuplestore. `values` and
+/// `nulls` must have an entry for every column.
+pub fn to_columns<'a, T: PgComposite<'a>>(
+    value: T,
+    row_type: &mut RowType,
+    values: &mut [Datum],
+    nulls: &mut [bool],
+) {
+    unsafe { row_type.with_desc(|desc| build_row(value, desc, values, nulls)) }
+}
+
+unsafe fn build_row<'a, T: PgComposite<'a>>(
+    value: T,
+    desc: TupleDesc,
+    values: &mut [Datum],
+    nulls: &mut [bool],
+) {
+    check_columns::<T>(desc);
+    let natts = (*desc).natts as usize;
+    assert!(values.len() == natts && nulls.len() == natts, "row has {} columns", natts);
+    values.fill(0);
+    nulls.fill(true);
+    let mut row = RowBuilder {
+        desc,
+        values,
+        nulls,
+        column: 0,
+        field: 0,
+        fields: T::FIELDS,
+    };
+    value.to_row(&mut row);
+}
+
+/// Convert a row datum of any composite type to `T`.
+///
+/// # Safety
+/// `datum` must be a composite value that remains valid for `'a`
+pub unsafe fn from_datum<'a, T: PgComposite<'a>>(datum: Datum) -> T {
+    let (desc, values, nulls) = deform(datum);
+    check_columns::<T>(desc);
+    let mut row = RowReader {
+        desc,
+        values,
+        nulls,
+        column: 0,
+        field: 0,
+        fields: T::FIELDS,
+        _row: PhantomData,
+    };
+    let value = T::from_row(&mut row);
+    release_tuple_desc(desc);
+    value
+}
+
+/// the descriptor, values and nulls of the composite `datum`; the descriptor
+/// must be released with `release_tuple_desc()`
+unsafe fn deform(datum: Datum) -> (TupleDesc, Vec<Datum>, Vec<bool>) {
+    let header = guard_pg(|| pg_sys::pg_detoast_datum(datum as *mut pg_sys::varlena))
+        as pg_sys::HeapTupleHeader;
+    let fields = (*header).t_choice.t_datum;
+    let desc = guard_pg(|| lookup_rowtype_tupdesc(fields.datum_typeid, fields.datum_typmod));
+    let mut tuple = pg_sys::HeapTupleData {
+        t_len: VARSIZE(header as *const pg_sys::varlena) as u32,
+        t_self: mem::zeroed(),
+        t_tableOid: INVALID_OID,
+        t_data: header,
+    };
+    let natts = (*desc).natts as usize;
+    let mut values = vec![0; natts];
+    let mut nulls = vec![true; natts];
+    guard_pg(|| pg_sys::heap_deform_tuple(&mut tuple, desc, values.as_mut_ptr(), nulls.as_mut_ptr()));
+    (desc, values, nulls)
+}
+
+/// copy the column values and nulls of the composite `datum`, which must
+/// have exactly as many columns as there are `values` and `nulls`
+pub(crate) unsafe fn deform_into(datum: Datum, values: &mut [Datum], nulls: &mut [bool]) {
+    let (desc, row_values, row_nulls) = deform(datum);
+    release_tuple_desc(desc);
+    if row_values.len() != values.len() {
+        crate::ereport!(Error, SqlState::DatatypeMismatch,
+            "row has {} columns but {} were expected", row_values.len(), values.len());
+    }
+    values.copy_from_slice(&row_values);
+    nulls.copy_from_slice(&row_nulls);
+}
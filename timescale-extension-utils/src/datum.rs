@@ -1,33 +1,118 @@
 
-use std::mem::size_of;
+use std::{
+    ffi::{CStr, CString},
+    marker::PhantomData,
+    mem::size_of,
+    ops::Deref,
+    os::raw::{c_char, c_int},
+    ptr::{self, NonNull},
+    slice,
+    str,
+};
 
 use crate::{
+    composite::{self, RowType},
+    elog::PgError,
     palloc::Pox,
-    pg_sys::Datum
+    pg_sys::{
+        self,
+        macros::{
+            SET_VARSIZE,
+            VARATT_IS_COMPRESSED,
+            VARATT_IS_EXTERNAL,
+            VARDATA,
+            VARDATA_ANY,
+            VARHDRSZ,
+            VARSIZE_ANY_EXHDR,
+        },
+        Datum,
+    },
 };
 
-pub trait FromOptionalDatum: Sized {
-    fn from_optional_datum(datum: Option<Datum>) -> Self {
+/// Conversion from a datum that may be `NULL`, see [`FromDatum`].
+pub trait FromOptionalDatum<'a>: Sized {
+    /// # Safety
+    /// see [`FromDatum::from_datum`]
+    unsafe fn from_optional_datum(datum: Option<Datum>) -> Self {
         Self::try_from_optional_datum(datum).expect("tried to convert NULL into non-nullable value")
     }
-    fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self>;
+
+    /// `None` if `datum` is `NULL` and `Self` is not nullable
+    ///
+    /// # Safety
+    /// see [`FromDatum::from_datum`]
+    unsafe fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self>;
 }
 
 pub trait ToOptionalDatum {
     fn to_optional_datum(self) -> Option<Datum>;
+
+    /// see [`ToDatum::to_datum_as`]
+    fn to_optional_datum_as(self, row_type: &mut RowType) -> Option<Datum>
+    where Self: Sized {
+        let _ = row_type;
+        self.to_optional_datum()
+    }
+
+    /// see [`ToDatum::to_columns`]; `NULL` sets every column to `NULL`
+    fn to_optional_columns(self, row_type: &mut RowType, values: &mut [Datum], nulls: &mut [bool])
+    where Self: Sized {
+        match self.to_optional_datum_as(row_type) {
+            Some(datum) => unsafe { composite::deform_into(datum, values, nulls) },
+            None => {
+                values.fill(0);
+                nulls.fill(true);
+            },
+        }
+    }
 }
 
-pub trait FromDatum {
-    fn from_datum(datum: Datum) -> Self;
+/// Conversion from a non-`NULL` datum. Types that borrow from the datum, such
+/// as `&'a str`, implement it only for the lifetime they borrow for, so the
+/// borrow cannot outlive the value it points into. Converting is `unsafe`
+/// since nothing about a `Datum` says what, if anything, it points to: for
+/// the integer conversions a wrong datum is only a wrong value, for the
+/// borrowing ones it is a dangling reference.
+pub trait FromDatum<'a>: Sized {
+    /// # Safety
+    /// `datum` must be a valid value of a type the implementation converts
+    /// from, and anything it points to must remain valid for `'a`
+    unsafe fn from_datum(datum: Datum) -> Self;
 }
 
 pub trait ToDatum {
     fn to_datum(self) -> Datum;
+
+    /// Convert to a value of the SQL type `row_type`. Only composites need
+    /// their row type, every other type converts as with `to_datum()`.
+    fn to_datum_as(self, row_type: &mut RowType) -> Datum
+    where Self: Sized {
+        let _ = row_type;
+        self.to_datum()
+    }
+
+    /// Convert to the columns of a row of the type `row_type`, with an entry
+    /// in `values` and `nulls` for each. Composites set their fields
+    /// directly, anything else is converted to a row and taken apart.
+    fn to_columns(self, row_type: &mut RowType, values: &mut [Datum], nulls: &mut [bool])
+    where Self: Sized {
+        let datum = self.to_datum_as(row_type);
+        unsafe { composite::deform_into(datum, values, nulls) }
+    }
+}
+
+/// The SQL type a Rust type converts to, used to check the fields of a
+/// `PgComposite` against the columns of the row type. `None`, the default,
+/// accepts a column of any type.
+pub trait TypeOid {
+    fn type_oid() -> Option<pg_sys::Oid> {
+        None
+    }
 }
 
-impl<T: FromDatum> FromOptionalDatum for T {
-    fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self> {
-        datum.map(Self::from_datum)
+impl<'a, T: FromDatum<'a>> FromOptionalDatum<'a> for T {
+    unsafe fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self> {
+        datum.map(|datum| Self::from_datum(datum))
     }
 }
 
@@ -35,13 +120,21 @@ impl<T: ToDatum> ToOptionalDatum for T {
     fn to_optional_datum(self) -> Option<Datum> {
         Some(self.to_datum())
     }
+
+    fn to_optional_datum_as(self, row_type: &mut RowType) -> Option<Datum> {
+        Some(self.to_datum_as(row_type))
+    }
+
+    fn to_optional_columns(self, row_type: &mut RowType, values: &mut [Datum], nulls: &mut [bool]) {
+        self.to_columns(row_type, values, nulls)
+    }
 }
 
-impl<T: FromDatum> FromOptionalDatum for Option<T> {
-    fn from_optional_datum(datum: Option<Datum>) -> Self {
-        datum.map(<T as FromDatum>::from_datum)
+impl<'a, T: FromDatum<'a>> FromOptionalDatum<'a> for Option<T> {
+    unsafe fn from_optional_datum(datum: Option<Datum>) -> Self {
+        datum.map(|datum| T::from_datum(datum))
     }
-    fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self> {
+    unsafe fn try_from_optional_datum(datum: Option<Datum>) -> Option<Self> {
         Some(Self::from_optional_datum(datum))
     }
 }
@@ -50,16 +143,108 @@ impl<T: ToDatum> ToOptionalDatum for Option<T> {
     fn to_optional_datum(self) -> Option<Datum> {
         self.map(<T as ToDatum>::to_datum)
     }
+
+    fn to_optional_datum_as(self, row_type: &mut RowType) -> Option<Datum> {
+        self.map(|val| val.to_datum_as(row_type))
+    }
+
+    fn to_optional_columns(self, row_type: &mut RowType, values: &mut [Datum], nulls: &mut [bool]) {
+        match self {
+            Some(val) => val.to_columns(row_type, values, nulls),
+            None => {
+                values.fill(0);
+                nulls.fill(true);
+            },
+        }
+    }
+}
+
+/// `Err`s are reported as a Postgres ERROR, allowing `pg_fn!` bodies to return
+/// a `Result`; this does not return.
+impl<T: ToOptionalDatum, E: Into<PgError>> ToOptionalDatum for Result<T, E> {
+    fn to_optional_datum(self) -> Option<Datum> {
+        report_err(self).to_optional_datum()
+    }
+
+    fn to_optional_datum_as(self, row_type: &mut RowType) -> Option<Datum> {
+        report_err(self).to_optional_datum_as(row_type)
+    }
+
+    fn to_optional_columns(self, row_type: &mut RowType, values: &mut [Datum], nulls: &mut [bool]) {
+        report_err(self).to_optional_columns(row_type, values, nulls)
+    }
+}
+
+fn report_err<T, E: Into<PgError>>(result: Result<T, E>) -> T {
+    match result {
+        Ok(val) => val,
+        Err(err) => {
+            crate::ereport!(crate::elog::Level::Error, err.into());
+            unreachable!()
+        },
+    }
+}
+
+impl<T: TypeOid> TypeOid for Option<T> {
+    fn type_oid() -> Option<pg_sys::Oid> {
+        T::type_oid()
+    }
+}
+
+impl<T: TypeOid, E> TypeOid for Result<T, E> {
+    fn type_oid() -> Option<pg_sys::Oid> {
+        T::type_oid()
+    }
+}
+
+macro_rules! type_oids {
+    ($($typ:ty => $oid:expr),* $(,)?) => {
+        $(
+            impl TypeOid for $typ {
+                fn type_oid() -> Option<pg_sys::Oid> {
+                    Some($oid)
+                }
+            }
+        )*
+    };
 }
 
+type_oids!{
+    i8 => pg_sys::CHAROID,
+    i16 => pg_sys::INT2OID,
+    i32 => pg_sys::INT4OID,
+    i64 => pg_sys::INT8OID,
+    f32 => pg_sys::FLOAT4OID,
+    f64 => pg_sys::FLOAT8OID,
+    &str => pg_sys::TEXTOID,
+    String => pg_sys::TEXTOID,
+    PgText<'_> => pg_sys::TEXTOID,
+    &[u8] => pg_sys::BYTEAOID,
+    Vec<u8> => pg_sys::BYTEAOID,
+    Varlena<'_> => pg_sys::BYTEAOID,
+    &CStr => pg_sys::CSTRINGOID,
+    CString => pg_sys::CSTRINGOID,
+}
+
+// no SQL equivalent, so any column is accepted
+impl TypeOid for u8 {}
+impl TypeOid for u16 {}
+impl TypeOid for u32 {}
+impl TypeOid for u64 {}
+impl TypeOid for isize {}
+impl TypeOid for usize {}
+impl<T> TypeOid for *mut T {}
+impl<T> TypeOid for *const T {}
+impl<T> TypeOid for Pox<T> {}
+
 macro_rules! int_datum_convert {
     ($($typ:ty)*) => {
         $(
             // compile time assert that the the size of $typ is not larger
             // than that of datum
             const _: [(); 0 - !{ const ASSERT: bool = size_of::<$typ>() <= size_of::<Datum>(); ASSERT } as usize] = [];
-            impl FromDatum for $typ {
-                fn from_datum(datum: Datum) -> Self {
+            impl FromDatum<'_> for $typ {
+                unsafe fn from_datum(datum: Datum) -> Self {
                     datum as Self
                 }
             }
@@ -75,8 +260,8 @@ macro_rules! int_datum_convert {
 
 int_datum_convert!(i8 u8 i16 u16 i32 u32 i64 u64 isize usize);
 
-impl<T> FromDatum for *mut T {
-    fn from_datum(datum: Datum) -> Self {
+impl<T> FromDatum<'_> for *mut T {
+    unsafe fn from_datum(datum: Datum) -> Self {
         datum as Self
     }
 }
@@ -87,8 +272,8 @@ impl<T> ToDatum for *mut T {
     }
 }
 
-impl<T> FromDatum for *const T {
-    fn from_datum(datum: Datum) -> Self {
+impl<T> FromDatum<'_> for *const T {
+    unsafe fn from_datum(datum: Datum) -> Self {
         datum as Self
     }
 }
@@ -106,8 +291,8 @@ impl<T> ToDatum for Pox<T> {
 }
 
 
-impl FromDatum for f32 {
-    fn from_datum(datum: Datum) -> Self {
+impl FromDatum<'_> for f32 {
+    unsafe fn from_datum(datum: Datum) -> Self {
         f32::from_bits(datum as _)
     }
 }
@@ -121,8 +306,8 @@ impl ToDatum for f32 {
 // compile time assert that the the size of f64 is not larger than that of datum
 const _: [(); 0 - !{ const ASSERT: bool = size_of::<f64>() <= size_of::<Datum>(); ASSERT } as usize] = [];
 
-impl FromDatum for f64 {
-    fn from_datum(datum: Datum) -> Self {
+impl FromDatum<'_> for f64 {
+    unsafe fn from_datum(datum: Datum) -> Self {
         f64::from_bits(datum as _)
     }
 }
@@ -132,3 +317,425 @@ impl ToDatum for f64 {
         self.to_bits() as _
     }
 }
+
+/// A borrowed, detoasted `varlena`. Compressed and external values are
+/// detoasted when the datum is converted, so the contents can be read
+/// directly as bytes. Values with a short, 1-byte, header are not copied to
+/// expand it, like `pg_detoast_datum_packed()`, so `as_ptr()` may have either
+/// header. Detoasted data lives in the memory context that was current when
+/// it was converted, which is what `'a` stands for.
+pub struct Varlena<'a> {
+    ptr: NonNull<pg_sys::varlena>,
+    _lifetime: PhantomData<&'a [u8]>,
+}
+
+impl<'a> Varlena<'a> {
+    /// wrap a pointer to a varlena, detoasting it if needed
+    ///
+    /// # Safety
+    /// `ptr` must point to a valid varlena that outlives `'a`
+    pub unsafe fn from_raw(ptr: *mut pg_sys::varlena) -> Self {
+        let ptr = if VARATT_IS_COMPRESSED(ptr) || VARATT_IS_EXTERNAL(ptr) {
+            crate::guard_pg(|| pg_sys::pg_detoast_datum_packed(ptr))
+        } else {
+            ptr
+        };
+        Varlena {
+            ptr: NonNull::new(ptr).expect("NULL pointer for varlena"),
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// allocate a new varlena in `CurrentMemoryContext` containing `bytes`
+    pub fn new(bytes: &[u8]) -> Self {
+        unsafe {
+            let ptr = alloc_varlena(bytes);
+            Varlena {
+                ptr: NonNull::new_unchecked(ptr),
+                _lifetime: PhantomData,
+            }
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut pg_sys::varlena {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        unsafe {
+            let ptr = self.ptr.as_ptr();
+            slice::from_raw_parts(VARDATA_ANY(ptr) as *const u8, VARSIZE_ANY_EXHDR(ptr))
+        }
+    }
+}
+
+impl Deref for Varlena<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        self.as_bytes()
+    }
+}
+
+impl<'a> FromDatum<'a> for Varlena<'a> {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        Varlena::from_raw(datum as *mut pg_sys::varlena)
+    }
+}
+
+impl<'a> ToDatum for Varlena<'a> {
+    fn to_datum(self) -> Datum {
+        self.as_ptr() as Datum
+    }
+}
+
+/// A borrowed `text` value whose contents have been validated as UTF-8.
+pub struct PgText<'a>(Varlena<'a>);
+
+impl<'a> PgText<'a> {
+    /// allocate a new `text` in `CurrentMemoryContext`
+    pub fn new(s: &str) -> Self {
+        PgText(Varlena::new(s.as_bytes()))
+    }
+
+    pub fn as_ptr(&self) -> *mut pg_sys::text {
+        self.0.as_ptr()
+    }
+
+    pub fn as_str(&self) -> &'a str {
+        // validated on construction
+        unsafe { str::from_utf8_unchecked(self.0.as_bytes()) }
+    }
+}
+
+impl Deref for PgText<'_> {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl<'a> FromDatum<'a> for PgText<'a> {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        let varlena = Varlena::from_datum(datum);
+        if let Err(err) = str::from_utf8(varlena.as_bytes()) {
+            crate::elog!(crate::elog::Level::Error, "invalid UTF-8 in text value: {}", err);
+            unreachable!()
+        }
+        PgText(varlena)
+    }
+}
+
+impl<'a> ToDatum for PgText<'a> {
+    fn to_datum(self) -> Datum {
+        self.0.to_datum()
+    }
+}
+
+impl<'a> FromDatum<'a> for &'a str {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        PgText::from_datum(datum).as_str()
+    }
+}
+
+impl ToDatum for &str {
+    fn to_datum(self) -> Datum {
+        PgText::new(self).to_datum()
+    }
+}
+
+impl FromDatum<'_> for String {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        <&str>::from_datum(datum).to_owned()
+    }
+}
+
+impl ToDatum for String {
+    fn to_datum(self) -> Datum {
+        self.as_str().to_datum()
+    }
+}
+
+impl<'a> FromDatum<'a> for &'a [u8] {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        Varlena::from_datum(datum).as_bytes()
+    }
+}
+
+impl ToDatum for &[u8] {
+    fn to_datum(self) -> Datum {
+        Varlena::new(self).to_datum()
+    }
+}
+
+impl FromDatum<'_> for Vec<u8> {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        <&[u8]>::from_datum(datum).to_vec()
+    }
+}
+
+impl ToDatum for Vec<u8> {
+    fn to_datum(self) -> Datum {
+        self.as_slice().to_datum()
+    }
+}
+
+impl<'a> FromDatum<'a> for &'a CStr {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        CStr::from_ptr(datum as *const c_char)
+    }
+}
+
+impl ToDatum for CString {
+    fn to_datum(self) -> Datum {
+        // allocated with palloc, like any other cstring
+        self.into_raw() as Datum
+    }
+}
+
+/// Types that can be the elements of a Postgres array, i.e. those with a known
+/// element type. Notably `u8` is not, so `Vec<u8>` remains `bytea`.
+pub trait ArrayElement {
+    fn element_type() -> pg_sys::Oid;
+}
+
+impl<T: ArrayElement> ArrayElement for Option<T> {
+    fn element_type() -> pg_sys::Oid {
+        T::element_type()
+    }
+}
+
+macro_rules! array_elements {
+    ($($typ:ty => $oid:expr),* $(,)?) => {
+        $(
+            impl ArrayElement for $typ {
+                fn element_type() -> pg_sys::Oid {
+                    $oid
+                }
+            }
+        )*
+    };
+}
+
+array_elements!{
+    i8 => pg_sys::CHAROID,
+    i16 => pg_sys::INT2OID,
+    i32 => pg_sys::INT4OID,
+    i64 => pg_sys::INT8OID,
+    f32 => pg_sys::FLOAT4OID,
+    f64 => pg_sys::FLOAT8OID,
+    &str => pg_sys::TEXTOID,
+    String => pg_sys::TEXTOID,
+    PgText<'_> => pg_sys::TEXTOID,
+    &[u8] => pg_sys::BYTEAOID,
+    Vec<u8> => pg_sys::BYTEAOID,
+    Varlena<'_> => pg_sys::BYTEAOID,
+}
+
+/// the storage properties of the type `type_id`: length, by-value, alignment
+fn type_storage(type_id: pg_sys::Oid) -> (i16, bool, c_char) {
+    let (mut len, mut by_val, mut align) = (0, false, 0);
+    unsafe {
+        crate::guard_pg(|| pg_sys::get_typlenbyvalalign(type_id, &mut len, &mut by_val, &mut align))
+    };
+    (len, by_val, align)
+}
+
+/// the SQL name of the type `type_id`, for error messages
+pub(crate) fn format_type(type_id: pg_sys::Oid) -> String {
+    unsafe {
+        let name = crate::guard_pg(|| pg_sys::format_type_be(type_id));
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    }
+}
+
+fn array_type(element_type: pg_sys::Oid) -> pg_sys::Oid {
+    unsafe { crate::guard_pg(|| pg_sys::get_array_type(element_type)) }
+}
+
+/// A borrowed, detoasted array, which iterates over its elements in storage
+/// order. By-reference elements point into the array itself rather than being
+/// copied. Iterating as `Option<T>` yields `None` for `NULL` elements, while
+/// iterating as `T` raises an ERROR for them.
+pub struct PgArray<'a, T> {
+    ptr: NonNull<pg_sys::ArrayType>,
+    values: &'a [Datum],
+    nulls: &'a [bool],
+    next: usize,
+    _type: PhantomData<T>,
+}
+
+impl<'a, T: ArrayElement + FromOptionalDatum<'a>> PgArray<'a, T> {
+    /// wrap a pointer to an array, detoasting it if needed
+    ///
+    /// # Safety
+    /// `ptr` must point to a valid array that outlives `'a`
+    pub unsafe fn from_raw(ptr: *mut pg_sys::ArrayType) -> Self {
+        let ptr = crate::guard_pg(|| pg_sys::pg_detoast_datum(ptr as *mut pg_sys::varlena))
+            as *mut pg_sys::ArrayType;
+        let element_type = T::element_type();
+        if (*ptr).elemtype != element_type {
+            crate::ereport!(crate::elog::Level::Error, crate::elog::SqlState::DatatypeMismatch,
+                "cannot convert an array of {} to an array of {}",
+                format_type((*ptr).elemtype),
+                format_type(element_type),
+            );
+        }
+
+        let (len, by_val, align) = type_storage(element_type);
+        let mut values = ptr::null_mut();
+        let mut nulls = ptr::null_mut();
+        let mut count = 0;
+        crate::guard_pg(|| pg_sys::deconstruct_array(
+            ptr,
+            element_type,
+            len as _,
+            by_val,
+            align,
+            &mut values,
+            &mut nulls,
+            &mut count,
+        ));
+        let count = count as usize;
+        PgArray {
+            ptr: NonNull::new_unchecked(ptr),
+            values: if count == 0 { &[] } else { slice::from_raw_parts(values, count) },
+            nulls: if count == 0 { &[] } else { slice::from_raw_parts(nulls, count) },
+            next: 0,
+            _type: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> PgArray<'a, T> {
+    pub fn as_ptr(&self) -> *mut pg_sys::ArrayType {
+        self.ptr.as_ptr()
+    }
+
+    /// the number of dimensions, 0 for an empty array
+    pub fn ndim(&self) -> usize {
+        unsafe { (*self.ptr.as_ptr()).ndim as usize }
+    }
+
+    /// the length of each dimension, `ARR_DIMS`
+    pub fn dims(&self) -> &'a [i32] {
+        unsafe {
+            let dims = self.ptr.as_ptr().add(1) as *const i32;
+            slice::from_raw_parts(dims, self.ndim())
+        }
+    }
+
+    /// the lower bound of each dimension, `ARR_LBOUND`
+    pub fn lower_bounds(&self) -> &'a [i32] {
+        unsafe {
+            let dims = self.ptr.as_ptr().add(1) as *const i32;
+            slice::from_raw_parts(dims.add(self.ndim()), self.ndim())
+        }
+    }
+
+    /// the total number of elements, in all dimensions
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn has_nulls(&self) -> bool {
+        self.nulls.iter().any(|&null| null)
+    }
+}
+
+impl<'a, T: FromOptionalDatum<'a>> PgArray<'a, T> {
+    /// the element at `index` in storage order, ignoring the bounds
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.values.len() {
+            return None
+        }
+        let datum = if self.nulls[index] { None } else { Some(self.values[index]) };
+        // the elements are part of the array, which outlives 'a
+        let value = unsafe { T::try_from_optional_datum(datum) }.unwrap_or_else(|| {
+            crate::ereport!(crate::elog::Level::Error, crate::elog::SqlState::NullValueNotAllowed,
+                "NULL array element at index {} converted to a non-nullable value", index);
+            unreachable!()
+        });
+        Some(value)
+    }
+}
+
+impl<'a, T: FromOptionalDatum<'a>> Iterator for PgArray<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let value = self.get(self.next)?;
+        self.next += 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.values.len() - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T: FromOptionalDatum<'a>> ExactSizeIterator for PgArray<'a, T> {}
+
+impl<'a, T: ArrayElement + FromOptionalDatum<'a>> FromDatum<'a> for PgArray<'a, T> {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        PgArray::from_raw(datum as *mut pg_sys::ArrayType)
+    }
+}
+
+impl<'a, T: ArrayElement> TypeOid for PgArray<'a, T> {
+    fn type_oid() -> Option<pg_sys::Oid> {
+        Some(array_type(T::element_type()))
+    }
+}
+
+/// `Vec`s become one-dimensional arrays, with `None`s as `NULL` elements
+impl<T: ArrayElement + ToOptionalDatum> ToDatum for Vec<T> {
+    fn to_datum(self) -> Datum {
+        let element_type = T::element_type();
+        let (len, by_val, align) = type_storage(element_type);
+        let mut nulls = Vec::with_capacity(self.len());
+        let mut values: Vec<Datum> = self.into_iter()
+            .map(|value| {
+                let datum = value.to_optional_datum();
+                nulls.push(datum.is_none());
+                datum.unwrap_or(0)
+            })
+            .collect();
+        // empty arrays have no dimensions
+        let ndim = if values.is_empty() { 0 } else { 1 };
+        let mut dims = [values.len() as c_int];
+        let mut lower_bounds = [1];
+        let array = unsafe {
+            crate::guard_pg(|| pg_sys::construct_md_array(
+                values.as_mut_ptr(),
+                nulls.as_mut_ptr(),
+                ndim,
+                dims.as_mut_ptr(),
+                lower_bounds.as_mut_ptr(),
+                element_type,
+                len as _,
+                by_val,
+                align,
+            ))
+        };
+        array as Datum
+    }
+}
+
+impl<T: ArrayElement> TypeOid for Vec<T> {
+    fn type_oid() -> Option<pg_sys::Oid> {
+        Some(array_type(T::element_type()))
+    }
+}
+
+unsafe fn alloc_varlena(bytes: &[u8]) -> *mut pg_sys::varlena {
+    let len = bytes.len() + VARHDRSZ;
+    let ptr = crate::guard_pg(|| pg_sys::palloc(len as _)) as *mut pg_sys::varlena;
+    SET_VARSIZE(ptr, len);
+    ptr::copy_nonoverlapping(bytes.as_ptr(), VARDATA(ptr) as *mut u8, bytes.len());
+    ptr
+}
@@ -1,5 +1,6 @@
 
 use std::{
+    error::Error as StdError,
     ffi::CString,
     fmt,
     os::raw::{c_char, c_int}
@@ -50,50 +51,559 @@ macro_rules! elog {
     });
 }
 
+/// Report a `PgError`, or a message with an `SqlState`, at a given [`Level`].
+/// ```ignore
+/// ereport!(Error, SqlState::InvalidParameterValue, "bucket width must be positive, got {}", w);
+/// ereport!(Error, PgError::new(SqlState::DivisionByZero, "division by zero").hint("try not to"));
+/// ```
+///
+/// [`Level`]: enum.Level.html
+#[macro_export]
+macro_rules! ereport {
+    (#unguarded $lvl:expr, $err:expr) => ({
+        $crate::elog::__private_api_report(
+            $err,
+            $lvl,
+            &(
+                concat!(module_path!(), "\0") as *const str as *const ::std::os::raw::c_char,
+                concat!(file!(), "\0") as *const str as *const ::std::os::raw::c_char,
+                line!(),
+            ),
+        );
+    });
+    (#unguarded $lvl:expr, $code:expr, $($arg:tt)+) => ({
+        $crate::ereport!(#unguarded $lvl, $crate::elog::PgError::new($code, format!($($arg)+)))
+    });
+    ($lvl:expr, $err:expr) => ({
+        unsafe { $crate::guard_pg(|| $crate::ereport!(#unguarded $lvl, $err)) }
+    });
+    ($lvl:expr, $code:expr, $($arg:tt)+) => ({
+        unsafe { $crate::guard_pg(|| $crate::ereport!(#unguarded $lvl, $code, $($arg)+)) }
+    });
+}
+
+/// A Postgres error report: an `SqlState` and message, plus the optional
+/// fields `ereport()` can attach. Reporting one at `Level::Error` or above
+/// does not return.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PgError {
+    pub code: SqlState,
+    pub message: String,
+    // boxed so that `Result<T, PgError>` stays small
+    pub fields: Box<PgErrorFields>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PgErrorFields {
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    pub context: Option<String>,
+    pub schema: Option<String>,
+    pub table: Option<String>,
+    pub column: Option<String>,
+    pub datatype: Option<String>,
+    pub constraint: Option<String>,
+    pub internal_query: Option<String>,
+    pub internal_position: Option<u32>,
+}
+
+impl PgError {
+    pub fn new(code: SqlState, message: impl Into<String>) -> Self {
+        PgError {
+            code,
+            message: message.into(),
+            fields: Box::default(),
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.fields.detail = Some(detail.into());
+        self
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.fields.hint = Some(hint.into());
+        self
+    }
+
+    pub fn context(mut self, context: impl Into<String>) -> Self {
+        self.fields.context = Some(context.into());
+        self
+    }
+
+    pub fn schema(mut self, schema: impl Into<String>) -> Self {
+        self.fields.schema = Some(schema.into());
+        self
+    }
+
+    pub fn table(mut self, table: impl Into<String>) -> Self {
+        self.fields.table = Some(table.into());
+        self
+    }
+
+    pub fn column(mut self, column: impl Into<String>) -> Self {
+        self.fields.column = Some(column.into());
+        self
+    }
+
+    pub fn datatype(mut self, datatype: impl Into<String>) -> Self {
+        self.fields.datatype = Some(datatype.into());
+        self
+    }
+
+    pub fn constraint(mut self, constraint: impl Into<String>) -> Self {
+        self.fields.constraint = Some(constraint.into());
+        self
+    }
+
+    /// the query that caused the error, and optionally a 1-based character
+    /// position within it
+    pub fn internal_query(mut self, query: impl Into<String>, position: Option<u32>) -> Self {
+        self.fields.internal_query = Some(query.into());
+        self.fields.internal_position = position;
+        self
+    }
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(detail) = &self.fields.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for PgError {}
+
 // WARNING: this is not part of the crate's public API and is subject to change at any time
 #[doc(hidden)]
 pub fn __private_api_log(
     args: fmt::Arguments,
     level: Level,
-    &(module_path, file, line): &(*const c_char, *const c_char, u32),
+    location: &(*const c_char, *const c_char, u32),
 ) {
-    use std::sync::atomic::{compiler_fence, Ordering};
+    // If errstart returned false, the message won't be seen by anyone; logging will be skipped
+    if !errstart(level, location) {
+        return
+    }
 
-    #[cfg(not(any(feature="pg12", feature="pg13")))]
-    compile_error!("must activate either pg12 or pg13");
+    // At this point we format the passed format string `args`; if the log level is suppressed,
+    // no string processing needs to take place.
+    let c_msg = to_c_string(format!("{}", args));
+    unsafe {
+        let _msg_result = pg_sys::errmsg(PERCENT_S, c_msg.as_ptr());
+        errfinish(_msg_result, location);
+    }
+}
+
+// WARNING: this is not part of the crate's public API and is subject to change at any time
+#[doc(hidden)]
+pub fn __private_api_report(
+    err: PgError,
+    level: Level,
+    location: &(*const c_char, *const c_char, u32),
+) {
+    use std::ptr::null;
+
+    if !errstart(level, location) {
+        return
+    }
+
+    let PgError { code, message, fields } = err;
+    let PgErrorFields {
+        detail, hint, context, schema, table, column, datatype, constraint,
+        internal_query, internal_position,
+    } = *fields;
+    let message = to_c_string(message);
+    let detail = detail.map(to_c_string);
+    let hint = hint.map(to_c_string);
+    let context = context.map(to_c_string);
+    let internal_query = internal_query.map(to_c_string);
+    let fields = [
+        (pg_sys::PG_DIAG_SCHEMA_NAME, schema),
+        (pg_sys::PG_DIAG_TABLE_NAME, table),
+        (pg_sys::PG_DIAG_COLUMN_NAME, column),
+        (pg_sys::PG_DIAG_DATATYPE_NAME, datatype),
+        (pg_sys::PG_DIAG_CONSTRAINT_NAME, constraint),
+    ];
+    let fields: Vec<_> = fields.iter()
+        .filter_map(|(field, value)| value.clone().map(|v| (*field, to_c_string(v))))
+        .collect();
+
+    // every string is copied into the ErrorContext by the err* functions, so
+    // our own copies only need to outlive this block
+    unsafe {
+        let mut _msg_result = pg_sys::errcode(code.code());
+        _msg_result = pg_sys::errmsg(PERCENT_S, message.as_ptr());
+        if let Some(detail) = &detail {
+            _msg_result = pg_sys::errdetail(PERCENT_S, detail.as_ptr());
+        }
+        if let Some(hint) = &hint {
+            _msg_result = pg_sys::errhint(PERCENT_S, hint.as_ptr());
+        }
+        if let Some(context) = &context {
+            pg_sys::set_errcontext_domain(null());
+            _msg_result = pg_sys::errcontext_msg(PERCENT_S, context.as_ptr());
+        }
+        for (field, value) in &fields {
+            _msg_result = pg_sys::err_generic_string(*field as c_int, value.as_ptr());
+        }
+        if let Some(query) = &internal_query {
+            _msg_result = pg_sys::internalerrquery(query.as_ptr());
+            if let Some(position) = internal_position {
+                _msg_result = pg_sys::internalerrposition(position as c_int);
+            }
+        }
+        errfinish(_msg_result, location);
+    }
+}
+
+const LOG_DOMAIN: *const c_char = "RUST\0" as *const str as *const c_char;
+const PERCENT_S: *const c_char = "%s\0" as *const str as *const c_char;
 
+fn to_c_string(msg: String) -> CString {
+    CString::new(msg).or_else(
+        |_| CString::new("failed to convert msg to a CString, check extension code for incompatible `CString` messages")
+    ).expect("this should not fail: msg")
+}
+
+/// start a new error report, returns false if the message won't be seen by
+/// anyone, in which case the report must not be finished
+fn errstart(level: Level, &(_module_path, _file, _line): &(*const c_char, *const c_char, u32))
+-> bool {
     let errlevel: c_int = c_int::from(level);
-    let line = line as c_int;
-    const LOG_DOMAIN: *const c_char = "RUST\0" as *const str as *const c_char;
 
     // Rust has no "function name" macro, for now we use module path instead.
     // See: https://github.com/rust-lang/rfcs/issues/1743
-    // let do_log = unsafe {
-    //     crate::guard_pg(|| pg_sys::errstart(errlevel, file, line, module_path, LOG_DOMAIN))
-    // };
-
-    #[cfg(feature="pg12")]
-    let do_log = unsafe { pg_sys::errstart(errlevel, file, line, module_path, LOG_DOMAIN) };
+    #[cfg(not(pg_ge_13))]
+    let do_log = unsafe {
+        pg_sys::errstart(errlevel, _file, _line as c_int, _module_path, LOG_DOMAIN)
+    };
 
-    #[cfg(feature="pg13")]
+    #[cfg(pg_ge_13)]
     let do_log = unsafe { pg_sys::errstart(errlevel, LOG_DOMAIN) };
 
-    // If errstart returned false, the message won't be seen by anyone; logging will be skipped
-    if do_log {
-        // At this point we format the passed format string `args`; if the log level is suppressed,
-        // no string processing needs to take place.
-        let msg = format!("{}", args);
-        let c_msg = CString::new(msg).or_else(
-            |_| CString::new("failed to convert msg to a CString, check extension code for incompatible `CString` messages")
-        ).expect("this should not fail: msg");
-
-        unsafe {
-            compiler_fence(Ordering::SeqCst);
-            let _msg_result = pg_sys::errmsg(c_msg.as_ptr());
-            #[cfg(feature="pg12")]
-            pg_sys::errfinish(_msg_result);
-            #[cfg(feature="pg13")]
-            pg_sys::errfinish(file, line, module_path);
+    do_log
+}
+
+/// emit the error report started by `errstart`, at `Level::Error` and above
+/// this will longjmp
+unsafe fn errfinish(
+    _msg_result: c_int,
+    &(_module_path, _file, _line): &(*const c_char, *const c_char, u32),
+) {
+    use std::sync::atomic::{compiler_fence, Ordering};
+
+    compiler_fence(Ordering::SeqCst);
+    #[cfg(not(pg_ge_13))]
+    pg_sys::errfinish(_msg_result);
+    #[cfg(pg_ge_13)]
+    pg_sys::errfinish(_file, _line as c_int, _module_path);
+}
+
+macro_rules! sql_states {
+    ($($(#[$attr:meta])* $name:ident = $code:literal,)*) => {
+        /// The SQLSTATE error codes from `utils/errcodes.h`, see
+        /// <https://www.postgresql.org/docs/current/errcodes-appendix.html>
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum SqlState {
+            $($(#[$attr])* $name,)*
+        }
+
+        impl SqlState {
+            /// the five-character SQLSTATE
+            pub fn as_str(&self) -> &'static str {
+                match self {
+                    $(SqlState::$name => $code,)*
+                }
+            }
+
+            pub fn from_code(code: &str) -> Option<Self> {
+                match code {
+                    $($code => Some(SqlState::$name),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl SqlState {
+    /// the code in the packed format used by `errcode()`, equivalent to
+    /// postgres's `MAKE_SQLSTATE`
+    pub fn code(&self) -> c_int {
+        self.as_str()
+            .bytes()
+            .enumerate()
+            .map(|(i, c)| (((c - b'0') & 0x3F) as c_int) << (6 * i))
+            .sum()
+    }
+}
+
+impl fmt::Display for SqlState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+sql_states! {
+    SuccessfulCompletion = "00000",
+    Warning = "01000",
+    WarningDynamicResultSetsReturned = "0100C",
+    WarningImplicitZeroBitPadding = "01008",
+    WarningNullValueEliminatedInSetFunction = "01003",
+    WarningPrivilegeNotGranted = "01007",
+    WarningPrivilegeNotRevoked = "01006",
+    WarningStringDataRightTruncation = "01004",
+    WarningDeprecatedFeature = "01P01",
+    NoData = "02000",
+    NoAdditionalDynamicResultSetsReturned = "02001",
+    SqlStatementNotYetComplete = "03000",
+    ConnectionException = "08000",
+    ConnectionDoesNotExist = "08003",
+    ConnectionFailure = "08006",
+    ProtocolViolation = "08P01",
+    TriggeredActionException = "09000",
+    FeatureNotSupported = "0A000",
+    InvalidTransactionInitiation = "0B000",
+    LocatorException = "0F000",
+    InvalidGrantor = "0L000",
+    InvalidRoleSpecification = "0P000",
+    DiagnosticsException = "0Z000",
+    CaseNotFound = "20000",
+    CardinalityViolation = "21000",
+    DataException = "22000",
+    ArraySubscriptError = "2202E",
+    CharacterNotInRepertoire = "22021",
+    DatetimeFieldOverflow = "22008",
+    DivisionByZero = "22012",
+    ErrorInAssignment = "22005",
+    EscapeCharacterConflict = "2200B",
+    IndicatorOverflow = "22022",
+    IntervalFieldOverflow = "22015",
+    InvalidArgumentForLog = "2201E",
+    InvalidArgumentForNtileFunction = "22014",
+    InvalidArgumentForNthValueFunction = "22016",
+    InvalidArgumentForPowerFunction = "2201F",
+    InvalidArgumentForWidthBucketFunction = "2201G",
+    InvalidCharacterValueForCast = "22018",
+    InvalidDatetimeFormat = "22007",
+    InvalidEscapeCharacter = "22019",
+    InvalidEscapeOctet = "2200D",
+    InvalidEscapeSequence = "22025",
+    NonstandardUseOfEscapeCharacter = "22P06",
+    InvalidIndicatorParameterValue = "22010",
+    InvalidParameterValue = "22023",
+    InvalidPrecedingOrFollowingSize = "22013",
+    InvalidRegularExpression = "2201B",
+    InvalidRowCountInLimitClause = "2201W",
+    InvalidRowCountInResultOffsetClause = "2201X",
+    InvalidTablesampleArgument = "2202H",
+    InvalidTablesampleRepeat = "2202G",
+    InvalidTimeZoneDisplacementValue = "22009",
+    InvalidUseOfEscapeCharacter = "2200C",
+    MostSpecificTypeMismatch = "2200G",
+    NullValueNotAllowed = "22004",
+    NullValueNoIndicatorParameter = "22002",
+    NumericValueOutOfRange = "22003",
+    SequenceGeneratorLimitExceeded = "2200H",
+    StringDataLengthMismatch = "22026",
+    StringDataRightTruncation = "22001",
+    SubstringError = "22011",
+    TrimError = "22027",
+    UnterminatedCString = "22024",
+    ZeroLengthCharacterString = "2200F",
+    FloatingPointException = "22P01",
+    InvalidTextRepresentation = "22P02",
+    InvalidBinaryRepresentation = "22P03",
+    BadCopyFileFormat = "22P04",
+    UntranslatableCharacter = "22P05",
+    NotAnXmlDocument = "2200L",
+    InvalidXmlDocument = "2200M",
+    InvalidXmlContent = "2200N",
+    InvalidXmlComment = "2200S",
+    InvalidXmlProcessingInstruction = "2200T",
+    DuplicateJsonObjectKeyValue = "22030",
+    InvalidJsonText = "22032",
+    IntegrityConstraintViolation = "23000",
+    RestrictViolation = "23001",
+    NotNullViolation = "23502",
+    ForeignKeyViolation = "23503",
+    UniqueViolation = "23505",
+    CheckViolation = "23514",
+    ExclusionViolation = "23P01",
+    InvalidCursorState = "24000",
+    InvalidTransactionState = "25000",
+    ActiveSqlTransaction = "25001",
+    BranchTransactionAlreadyActive = "25002",
+    HeldCursorRequiresSameIsolationLevel = "25008",
+    InappropriateAccessModeForBranchTransaction = "25003",
+    InappropriateIsolationLevelForBranchTransaction = "25004",
+    NoActiveSqlTransactionForBranchTransaction = "25005",
+    ReadOnlySqlTransaction = "25006",
+    SchemaAndDataStatementMixingNotSupported = "25007",
+    NoActiveSqlTransaction = "25P01",
+    InFailedSqlTransaction = "25P02",
+    IdleInTransactionSessionTimeout = "25P03",
+    InvalidSqlStatementName = "26000",
+    TriggeredDataChangeViolation = "27000",
+    InvalidAuthorizationSpecification = "28000",
+    InvalidPassword = "28P01",
+    DependentPrivilegeDescriptorsStillExist = "2B000",
+    DependentObjectsStillExist = "2BP01",
+    InvalidTransactionTermination = "2D000",
+    SqlRoutineException = "2F000",
+    FunctionExecutedNoReturnStatement = "2F005",
+    ModifyingSqlDataNotPermitted = "2F002",
+    ProhibitedSqlStatementAttempted = "2F003",
+    ReadingSqlDataNotPermitted = "2F004",
+    InvalidCursorName = "34000",
+    ExternalRoutineException = "38000",
+    ContainingSqlNotPermitted = "38001",
+    ExternalModifyingSqlDataNotPermitted = "38002",
+    ExternalProhibitedSqlStatementAttempted = "38003",
+    ExternalReadingSqlDataNotPermitted = "38004",
+    ExternalRoutineInvocationException = "39000",
+    InvalidSqlstateReturned = "39001",
+    NullValueNotAllowedInExternalRoutine = "39004",
+    TriggerProtocolViolated = "39P01",
+    SrfProtocolViolated = "39P02",
+    EventTriggerProtocolViolated = "39P03",
+    SavepointException = "3B000",
+    InvalidSavepointSpecification = "3B001",
+    InvalidCatalogName = "3D000",
+    InvalidSchemaName = "3F000",
+    TransactionRollback = "40000",
+    TransactionIntegrityConstraintViolation = "40002",
+    SerializationFailure = "40001",
+    StatementCompletionUnknown = "40003",
+    DeadlockDetected = "40P01",
+    SyntaxErrorOrAccessRuleViolation = "42000",
+    SyntaxError = "42601",
+    InsufficientPrivilege = "42501",
+    CannotCoerce = "42846",
+    GroupingError = "42803",
+    WindowingError = "42P20",
+    InvalidRecursion = "42P19",
+    InvalidForeignKey = "42830",
+    InvalidName = "42602",
+    NameTooLong = "42622",
+    ReservedName = "42939",
+    DatatypeMismatch = "42804",
+    IndeterminateDatatype = "42P18",
+    CollationMismatch = "42P21",
+    IndeterminateCollation = "42P22",
+    WrongObjectType = "42809",
+    GeneratedAlways = "428C9",
+    UndefinedColumn = "42703",
+    UndefinedFunction = "42883",
+    UndefinedTable = "42P01",
+    UndefinedParameter = "42P02",
+    UndefinedObject = "42704",
+    DuplicateColumn = "42701",
+    DuplicateCursor = "42P03",
+    DuplicateDatabase = "42P04",
+    DuplicateFunction = "42723",
+    DuplicatePreparedStatement = "42P05",
+    DuplicateSchema = "42P06",
+    DuplicateTable = "42P07",
+    DuplicateAlias = "42712",
+    DuplicateObject = "42710",
+    AmbiguousColumn = "42702",
+    AmbiguousFunction = "42725",
+    AmbiguousParameter = "42P08",
+    AmbiguousAlias = "42P09",
+    InvalidColumnReference = "42P10",
+    InvalidColumnDefinition = "42611",
+    InvalidCursorDefinition = "42P11",
+    InvalidDatabaseDefinition = "42P12",
+    InvalidFunctionDefinition = "42P13",
+    InvalidPreparedStatementDefinition = "42P14",
+    InvalidSchemaDefinition = "42P15",
+    InvalidTableDefinition = "42P16",
+    InvalidObjectDefinition = "42P17",
+    WithCheckOptionViolation = "44000",
+    InsufficientResources = "53000",
+    DiskFull = "53100",
+    OutOfMemory = "53200",
+    TooManyConnections = "53300",
+    ConfigurationLimitExceeded = "53400",
+    ProgramLimitExceeded = "54000",
+    StatementTooComplex = "54001",
+    TooManyColumns = "54011",
+    TooManyArguments = "54023",
+    ObjectNotInPrerequisiteState = "55000",
+    ObjectInUse = "55006",
+    CantChangeRuntimeParam = "55P02",
+    LockNotAvailable = "55P03",
+    UnsafeNewEnumValueUsage = "55P04",
+    OperatorIntervention = "57000",
+    QueryCanceled = "57014",
+    AdminShutdown = "57P01",
+    CrashShutdown = "57P02",
+    CannotConnectNow = "57P03",
+    DatabaseDropped = "57P04",
+    SystemError = "58000",
+    IoError = "58030",
+    UndefinedFile = "58P01",
+    DuplicateFile = "58P02",
+    SnapshotTooOld = "72000",
+    ConfigFileError = "F0000",
+    LockFileExists = "F0001",
+    FdwError = "HV000",
+    PlpgsqlError = "P0000",
+    RaiseException = "P0001",
+    NoDataFound = "P0002",
+    TooManyRows = "P0003",
+    AssertFailure = "P0004",
+    InternalError = "XX000",
+    DataCorrupted = "XX001",
+    IndexCorrupted = "XX002",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sql_state_code() {
+        // MAKE_SQLSTATE('2','2','0','1','2')
+        assert_eq!(SqlState::DivisionByZero.code(), 2 + (2 << 6) + (1 << 18) + (2 << 24));
+        // letters are encoded relative to '0' as well
+        assert_eq!(SqlState::InternalError.code(), 40 + (40 << 6));
+        assert_eq!(SqlState::from_code("23505"), Some(SqlState::UniqueViolation));
+        assert_eq!(SqlState::from_code("zzzzz"), None);
+    }
+
+    #[test]
+    fn compile_test_ereport() {
+        if false {
+            crate::ereport!(Level::Error, SqlState::InvalidParameterValue, "bad value {}", 1);
+            crate::ereport!(Level::Error,
+                PgError::new(SqlState::CheckViolation, "check failed")
+                    .detail("value was 1")
+                    .hint("use 2")
+                    .table("foo")
+                    .constraint("foo_check"));
+            crate::elog!(Level::Notice, "value was {}", 1);
         }
     }
+
+    // the cached bindings declare errstart() and errfinish() by hand, since
+    // their signatures changed in 13; these are the ones elog.h declares
+    #[allow(dead_code)]
+    fn compile_test_errstart_errfinish() {
+        #[cfg(not(pg_ge_13))]
+        let _: (
+            unsafe extern "C" fn(c_int, *const c_char, c_int, *const c_char, *const c_char) -> bool,
+            unsafe extern "C" fn(c_int, ...),
+        ) = (pg_sys::errstart, pg_sys::errfinish);
+        #[cfg(pg_ge_13)]
+        let _: (
+            unsafe extern "C" fn(c_int, *const c_char) -> bool,
+            unsafe extern "C" fn(*const c_char, c_int, *const c_char),
+        ) = (pg_sys::errstart, pg_sys::errfinish);
+    }
 }
@@ -0,0 +1,126 @@
+//! access to a function call's `fcinfo` that does not depend on the postgres
+//! version, see [`FcInfo`]
+
+use crate::{
+    pg_sys::{self, Datum, Oid},
+    FunctionCallInfoData,
+};
+
+/// The fields of `FunctionCallInfoData` that extensions need, which are laid
+/// out differently depending on the postgres version: before 12 the
+/// arguments are stored in separate `arg` and `argnull` arrays, from 12 on in
+/// a single `args` array of `NullableDatum`s. Everything in this crate
+/// accesses the `fcinfo` through these, so that it compiles against either.
+pub trait FcInfo {
+    /// the number of arguments passed
+    fn nargs(&self) -> usize;
+
+    /// argument `n`, or `None` if it is `NULL`
+    ///
+    /// # Panics
+    /// if `n` is not less than `nargs()`
+    fn arg(&self, n: usize) -> Option<Datum>;
+
+    fn flinfo(&self) -> *mut pg_sys::FmgrInfo;
+
+    /// the node describing the context of the call, e.g. an `AggState` or a
+    /// `WindowObjectData`, or `NULL`
+    fn context(&self) -> *mut pg_sys::Node;
+
+    /// the node to return extra information in, e.g. a `ReturnSetInfo`, or
+    /// `NULL`
+    fn resultinfo(&self) -> *mut pg_sys::Node;
+
+    /// the collation the function should use
+    fn collation(&self) -> Oid;
+
+    /// whether the function returns `NULL`, which must be set by every call
+    fn set_return_null(&mut self, is_null: bool);
+}
+
+impl FcInfo for FunctionCallInfoData {
+    fn nargs(&self) -> usize {
+        self.nargs as usize
+    }
+
+    #[cfg(not(pg_ge_12))]
+    fn arg(&self, n: usize) -> Option<Datum> {
+        assert!(n < self.nargs(), "argument {} out of range", n);
+        if self.argnull[n] { None } else { Some(self.arg[n]) }
+    }
+
+    #[cfg(pg_ge_12)]
+    fn arg(&self, n: usize) -> Option<Datum> {
+        assert!(n < self.nargs(), "argument {} out of range", n);
+        let arg = unsafe { &self.args.as_slice(self.nargs())[n] };
+        if arg.isnull { None } else { Some(arg.value) }
+    }
+
+    fn flinfo(&self) -> *mut pg_sys::FmgrInfo {
+        self.flinfo
+    }
+
+    fn context(&self) -> *mut pg_sys::Node {
+        self.context
+    }
+
+    fn resultinfo(&self) -> *mut pg_sys::Node {
+        self.resultinfo
+    }
+
+    fn collation(&self) -> Oid {
+        self.fncollation
+    }
+
+    fn set_return_null(&mut self, is_null: bool) {
+        self.isnull = is_null
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an fcinfo with room for its arguments, which follow it from 12 on
+    #[repr(C)]
+    struct Call {
+        fcinfo: FunctionCallInfoData,
+        #[cfg(pg_ge_12)]
+        args: [pg_sys::NullableDatum; 2],
+    }
+
+    fn call(args: [Option<Datum>; 2]) -> Box<Call> {
+        let mut call: Box<Call> = Box::new(unsafe { std::mem::zeroed() });
+        call.fcinfo.nargs = 2;
+        for (n, arg) in args.iter().enumerate() {
+            #[cfg(not(pg_ge_12))]
+            {
+                call.fcinfo.arg[n] = arg.unwrap_or(0);
+                call.fcinfo.argnull[n] = arg.is_none();
+            }
+            #[cfg(pg_ge_12)]
+            {
+                let value = arg.unwrap_or(0);
+                call.args[n] = pg_sys::NullableDatum { value, isnull: arg.is_none() };
+            }
+        }
+        call
+    }
+
+    #[test]
+    fn args() {
+        let mut call = call([Some(42), None]);
+        let fcinfo = &mut call.fcinfo;
+        assert_eq!(fcinfo.nargs(), 2);
+        assert_eq!(fcinfo.arg(0), Some(42));
+        assert_eq!(fcinfo.arg(1), None);
+        fcinfo.set_return_null(true);
+        assert!(fcinfo.isnull);
+    }
+
+    #[test]
+    #[should_panic(expected = "argument 2 out of range")]
+    fn arg_out_of_range() {
+        call([Some(1), Some(2)]).fcinfo.arg(2);
+    }
+}
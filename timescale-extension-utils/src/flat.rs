@@ -0,0 +1,482 @@
+//! flat, zero-copy serialization of structs into varlenas, see
+//! `#[derive(FlatSerialize)]`
+//!
+//! A struct deriving `FlatSerialize` is stored as a varlena with the layout
+//!
+//! ```text
+//! varlena header     4 bytes
+//! layout version     u32
+//! fixed part         the fields in declaration order; a slice or str field
+//!                    is stored as its length, a u32
+//! tails              the contents of the slice and str fields, in
+//!                    declaration order
+//! ```
+//!
+//! `Vec<T>` and `String` fields are stored as slice and str fields are.
+//!
+//! Every value is aligned to its natural alignment relative to the start of
+//! the varlena, and is stored in native byte order, like Postgres's own
+//! on-disk formats. Since the varlena is read in place, reading a value only
+//! copies its fixed fields; its slice and str fields borrow from the datum.
+//! The datum is only copied if it needs detoasting, or if it is not
+//! MAXALIGN'd, as can happen to values stored in tuples.
+//!
+//! The version is checked whenever a value is read, so it must be bumped,
+//! with `#[flat_serialize(version = N)]`, whenever the fields change. Values
+//! stored with an older version are passed to
+//! [`FlatSerialize::read_version`], which rejects them as corrupted unless it
+//! is given, with `#[flat_serialize(read_version = "path")]`, a function that
+//! reads the old layout.
+
+use std::{
+    any::type_name,
+    convert::TryInto,
+    fmt,
+    mem::{align_of, size_of, size_of_val},
+    ptr,
+    slice,
+    str,
+};
+
+use crate::{
+    datum::{ToDatum, Varlena},
+    guard_pg,
+    pg_sys::{
+        self,
+        macros::{VARHDRSZ, VARSIZE},
+        Datum,
+    },
+};
+
+/// A Rust struct with a flat varlena representation. Usually derived with
+/// `#[derive(FlatSerialize)]`, which also implements `FromDatum`, `ToDatum`
+/// and `TypeOid` for the struct using [`from_datum`] and [`to_datum`].
+pub trait FlatSerialize<'a>: Sized {
+    /// the version of the layout, stored along with every value
+    const VERSION: u32;
+
+    /// write each of the fields to `writer`, then each of the tails
+    fn write(&self, writer: &mut FlatWriter);
+
+    /// read each of the fields from `reader`, then each of the tails
+    fn read(reader: &mut FlatReader<'a>) -> Self;
+
+    /// Read a value stored with the layout `version`, which is older than
+    /// `VERSION`, migrating it to the current fields. Old versions are
+    /// rejected as corrupted unless this is implemented.
+    fn read_version(version: u32, reader: &mut FlatReader<'a>) -> Self {
+        corrupted(format_args!("stored {} has layout version {}, expected version {}",
+            reader.type_name, version, Self::VERSION))
+    }
+}
+
+/// Types that can be stored in, and borrowed from, a flat varlena.
+///
+/// # Safety
+/// Every bit pattern of the type's size must be a valid value, the type must
+/// have no padding, and its alignment must be at most MAXALIGN.
+pub unsafe trait FlatScalar: Copy + 'static {}
+
+unsafe impl FlatScalar for i8 {}
+unsafe impl FlatScalar for i16 {}
+unsafe impl FlatScalar for i32 {}
+unsafe impl FlatScalar for i64 {}
+unsafe impl FlatScalar for u8 {}
+unsafe impl FlatScalar for u16 {}
+unsafe impl FlatScalar for u32 {}
+unsafe impl FlatScalar for u64 {}
+unsafe impl FlatScalar for f32 {}
+unsafe impl FlatScalar for f64 {}
+unsafe impl<T: FlatScalar, const N: usize> FlatScalar for [T; N] {}
+
+const MAXALIGN: usize = pg_sys::MAXIMUM_ALIGNOF as usize;
+
+/// Builds the contents of a flat varlena, see [`FlatSerialize::write`].
+pub struct FlatWriter {
+    // everything after the varlena header
+    bytes: Vec<u8>,
+}
+
+impl FlatWriter {
+    fn new(version: u32) -> Self {
+        let mut writer = FlatWriter { bytes: vec![] };
+        writer.scalar(version);
+        writer
+    }
+
+    pub fn scalar<T: FlatScalar>(&mut self, value: T) {
+        self.tail(slice::from_ref(&value))
+    }
+
+    /// write the length of a slice or str field, whose contents are written
+    /// with `tail` or `tail_str` once all of the fields have been written
+    pub fn tail_len(&mut self, len: usize) {
+        let len: u32 = len.try_into()
+            .unwrap_or_else(|_| panic!("flat value tail of {} elements is too long", len));
+        self.scalar(len)
+    }
+
+    pub fn tail<T: FlatScalar>(&mut self, values: &[T]) {
+        self.align(align_of::<T>());
+        let bytes = unsafe {
+            slice::from_raw_parts(values.as_ptr() as *const u8, size_of_val(values))
+        };
+        self.bytes.extend_from_slice(bytes)
+    }
+
+    pub fn tail_str(&mut self, value: &str) {
+        self.tail(value.as_bytes())
+    }
+
+    fn align(&mut self, align: usize) {
+        while !(VARHDRSZ + self.bytes.len()).is_multiple_of(align) {
+            self.bytes.push(0)
+        }
+    }
+}
+
+/// Reads the contents of a flat varlena, see [`FlatSerialize::read`].
+pub struct FlatReader<'a> {
+    // the whole varlena, including its header, which is MAXALIGN'd
+    bytes: &'a [u8],
+    offset: usize,
+    version: u32,
+    type_name: &'static str,
+}
+
+impl<'a> FlatReader<'a> {
+    pub fn scalar<T: FlatScalar>(&mut self) -> T {
+        self.tail::<T>(1)[0]
+    }
+
+    /// read the length of a slice or str field
+    pub fn tail_len(&mut self) -> usize {
+        self.scalar::<u32>() as usize
+    }
+
+    pub fn tail<T: FlatScalar>(&mut self, len: usize) -> &'a [T] {
+        let start = align_to(self.offset, align_of::<T>());
+        let end = size_of::<T>().checked_mul(len)
+            .and_then(|size| start.checked_add(size))
+            .filter(|&end| end <= self.bytes.len())
+            .unwrap_or_else(|| corrupted(format_args!("stored {} is truncated", self.type_name)));
+        self.offset = end;
+        // the varlena is MAXALIGN'd, and so is every FlatScalar within it
+        unsafe { slice::from_raw_parts(self.bytes.as_ptr().add(start) as *const T, len) }
+    }
+
+    /// the layout version the value was stored with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn tail_str(&mut self, len: usize) -> &'a str {
+        let bytes = self.tail(len);
+        str::from_utf8(bytes).unwrap_or_else(|_| {
+            corrupted(format_args!("stored {} contains invalid UTF-8", self.type_name))
+        })
+    }
+}
+
+#[cfg(not(test))]
+fn corrupted(message: fmt::Arguments) -> ! {
+    use crate::elog::{Level::Error, SqlState};
+    crate::ereport!(Error, SqlState::DataCorrupted, "{}", message);
+    unreachable!()
+}
+
+// the unit tests run without postgres
+#[cfg(test)]
+fn corrupted(message: fmt::Arguments) -> ! {
+    panic!("{}", message)
+}
+
+fn align_to(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
+
+/// Store `value` as a flat varlena allocated in `CurrentMemoryContext`.
+pub fn to_datum<'a, T: FlatSerialize<'a>>(value: &T) -> Datum {
+    let mut writer = FlatWriter::new(T::VERSION);
+    value.write(&mut writer);
+    // palloc'd memory is MAXALIGN'd
+    Varlena::new(&writer.bytes).to_datum()
+}
+
+/// Read a value stored by [`to_datum`], borrowing its tails from the datum.
+///
+/// # Safety
+/// `datum` must be a varlena that outlives `'a`
+pub unsafe fn from_datum<'a, T: FlatSerialize<'a>>(datum: Datum) -> T {
+    // unlike pg_detoast_datum_packed() this also expands short headers
+    let ptr = guard_pg(|| pg_sys::pg_detoast_datum(datum as *mut pg_sys::varlena));
+    read_varlena(ptr)
+}
+
+/// read a value from a varlena with a 4-byte header, copying it first if it
+/// is not MAXALIGN'd
+unsafe fn read_varlena<'a, T: FlatSerialize<'a>>(mut ptr: *const pg_sys::varlena) -> T {
+    let len = VARSIZE(ptr);
+    if !(ptr as usize).is_multiple_of(MAXALIGN) {
+        // u64s are MAXALIGN'd, and the global allocator pallocs them in
+        // CurrentMemoryContext, so the copy is freed along with the context
+        let copy = Box::leak(vec![0u64; len.div_ceil(size_of::<u64>())].into_boxed_slice());
+        ptr::copy_nonoverlapping(ptr as *const u8, copy.as_mut_ptr() as *mut u8, len);
+        ptr = copy.as_ptr() as *const pg_sys::varlena;
+    }
+    let mut reader = FlatReader {
+        bytes: slice::from_raw_parts(ptr as *const u8, len),
+        offset: VARHDRSZ,
+        version: 0,
+        type_name: type_name::<T>(),
+    };
+    reader.version = reader.scalar::<u32>();
+    let value = match reader.version {
+        version if version == T::VERSION => T::read(&mut reader),
+        version if version < T::VERSION => T::read_version(version, &mut reader),
+        version => corrupted(format_args!(
+            "stored {} has layout version {}, newer than version {}",
+            reader.type_name, version, T::VERSION,
+        )),
+    };
+    if reader.offset != len {
+        corrupted(format_args!("stored {} has {} unexpected trailing bytes",
+            reader.type_name, len - reader.offset));
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // written out rather than derived, as the derived datum conversions
+    // need postgres
+    #[derive(Debug, PartialEq)]
+    struct Mixed<'a> {
+        byte: u8,
+        wide: u64,
+        halves: &'a [u16],
+        label: &'a str,
+        pair: [i32; 2],
+        doubles: &'a [f64],
+    }
+
+    impl<'a> FlatSerialize<'a> for Mixed<'a> {
+        const VERSION: u32 = 3;
+
+        fn write(&self, writer: &mut FlatWriter) {
+            writer.scalar(self.byte);
+            writer.scalar(self.wide);
+            writer.tail_len(self.halves.len());
+            writer.tail_len(self.label.len());
+            writer.scalar(self.pair);
+            writer.tail_len(self.doubles.len());
+            writer.tail(self.halves);
+            writer.tail_str(self.label);
+            writer.tail(self.doubles);
+        }
+
+        fn read(reader: &mut FlatReader<'a>) -> Self {
+            let byte = reader.scalar();
+            let wide = reader.scalar();
+            let halves_len = reader.tail_len();
+            let label_len = reader.tail_len();
+            let pair = reader.scalar();
+            let doubles_len = reader.tail_len();
+            Mixed {
+                byte,
+                wide,
+                pair,
+                halves: reader.tail(halves_len),
+                label: reader.tail_str(label_len),
+                doubles: reader.tail(doubles_len),
+            }
+        }
+    }
+
+    fn value() -> Mixed<'static> {
+        Mixed {
+            byte: 7,
+            wide: 0x0102_0304_0506_0708,
+            halves: &[1, 2, 3],
+            label: "abcde",
+            pair: [-1, 9],
+            doubles: &[0.5],
+        }
+    }
+
+    // the contents of the varlena after its header
+    fn write<'a, T: FlatSerialize<'a>>(value: &T) -> Vec<u8> {
+        let mut writer = FlatWriter::new(T::VERSION);
+        value.write(&mut writer);
+        writer.bytes
+    }
+
+    // a varlena containing `bytes`, `offset` bytes into a MAXALIGN'd buffer
+    fn varlena(bytes: &[u8], offset: usize) -> Vec<u64> {
+        let len = VARHDRSZ + bytes.len();
+        let mut buffer = vec![0u64; (offset + len).div_ceil(size_of::<u64>())];
+        unsafe {
+            let start = (buffer.as_mut_ptr() as *mut u8).add(offset);
+            pg_sys::macros::SET_VARSIZE(start as *mut pg_sys::varlena, len);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), start.add(VARHDRSZ), bytes.len());
+        }
+        buffer
+    }
+
+    fn read<'a, T: FlatSerialize<'a>>(buffer: &'a [u64], offset: usize) -> T {
+        unsafe { read_varlena((buffer.as_ptr() as *const u8).add(offset) as *const pg_sys::varlena) }
+    }
+
+    #[test]
+    fn layout() {
+        let mut expected = vec![];
+        // version, at 4
+        expected.extend_from_slice(&3u32.to_ne_bytes());
+        // byte, at 8, padded to wide at 16
+        expected.extend_from_slice(&[7, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&0x0102_0304_0506_0708u64.to_ne_bytes());
+        // the lengths of halves and label, at 24 and 28
+        expected.extend_from_slice(&3u32.to_ne_bytes());
+        expected.extend_from_slice(&5u32.to_ne_bytes());
+        // pair, at 32
+        expected.extend_from_slice(&(-1i32).to_ne_bytes());
+        expected.extend_from_slice(&9i32.to_ne_bytes());
+        // the length of doubles, at 40
+        expected.extend_from_slice(&1u32.to_ne_bytes());
+        // halves, at 44
+        for half in [1u16, 2, 3] {
+            expected.extend_from_slice(&half.to_ne_bytes());
+        }
+        // label, at 50, padded to doubles at 56
+        expected.extend_from_slice(b"abcde\0");
+        expected.extend_from_slice(&0.5f64.to_ne_bytes());
+        assert_eq!(write(&value()), expected);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = write(&value());
+        let buffer = varlena(&bytes, 0);
+        let read: Mixed = read(&buffer, 0);
+        assert_eq!(read, value());
+        // the tails are borrowed from the varlena
+        assert_eq!(read.label.as_ptr(), unsafe { (buffer.as_ptr() as *const u8).add(50) });
+    }
+
+    #[test]
+    fn round_trip_empty_tails() {
+        let empty = Mixed { halves: &[], label: "", doubles: &[], ..value() };
+        let bytes = write(&empty);
+        // empty tails are aligned all the same, like the reader expects
+        assert_eq!(VARHDRSZ + bytes.len(), 48);
+        assert_eq!(read::<Mixed>(&varlena(&bytes, 0), 0), empty);
+    }
+
+    #[test]
+    fn read_unaligned() {
+        let bytes = write(&value());
+        for offset in 1..MAXALIGN {
+            let buffer = varlena(&bytes, offset);
+            let read: Mixed = read(&buffer, offset);
+            assert_eq!(read, value());
+            // read from a MAXALIGN'd copy
+            assert!((read.doubles.as_ptr() as usize).is_multiple_of(align_of::<f64>()));
+        }
+    }
+
+    #[test]
+    fn align_to_multiples() {
+        assert_eq!(align_to(0, 8), 0);
+        assert_eq!(align_to(1, 8), 8);
+        assert_eq!(align_to(8, 8), 8);
+        assert_eq!(align_to(9, 4), 12);
+        assert_eq!(align_to(5, 1), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "is truncated")]
+    fn truncated() {
+        let bytes = write(&value());
+        read::<Mixed>(&varlena(&bytes[..bytes.len() - 1], 0), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "has layout version 3, expected version 4")]
+    fn version_mismatch() {
+        struct Bumped<'a>(Mixed<'a>);
+        impl<'a> FlatSerialize<'a> for Bumped<'a> {
+            const VERSION: u32 = 4;
+            fn write(&self, writer: &mut FlatWriter) {
+                self.0.write(writer)
+            }
+            fn read(reader: &mut FlatReader<'a>) -> Self {
+                Bumped(Mixed::read(reader))
+            }
+        }
+        read::<Bumped>(&varlena(&write(&value()), 0), 0);
+    }
+
+    #[test]
+    fn read_old_version() {
+        // the same fields as Mixed, with label moved last
+        #[derive(Debug, PartialEq)]
+        struct Moved<'a>(Mixed<'a>);
+        impl<'a> FlatSerialize<'a> for Moved<'a> {
+            const VERSION: u32 = 4;
+            fn write(&self, writer: &mut FlatWriter) {
+                let m = &self.0;
+                writer.scalar(m.byte);
+                writer.scalar(m.wide);
+                writer.tail_len(m.halves.len());
+                writer.scalar(m.pair);
+                writer.tail_len(m.doubles.len());
+                writer.tail_len(m.label.len());
+                writer.tail(m.halves);
+                writer.tail(m.doubles);
+                writer.tail_str(m.label);
+            }
+            fn read(reader: &mut FlatReader<'a>) -> Self {
+                let byte = reader.scalar();
+                let wide = reader.scalar();
+                let halves_len = reader.tail_len();
+                let pair = reader.scalar();
+                let doubles_len = reader.tail_len();
+                let label_len = reader.tail_len();
+                Moved(Mixed {
+                    byte,
+                    wide,
+                    pair,
+                    halves: reader.tail(halves_len),
+                    doubles: reader.tail(doubles_len),
+                    label: reader.tail_str(label_len),
+                })
+            }
+            fn read_version(version: u32, reader: &mut FlatReader<'a>) -> Self {
+                assert_eq!((version, reader.version()), (3, 3));
+                Moved(Mixed::read(reader))
+            }
+        }
+        let old = varlena(&write(&value()), 0);
+        assert_eq!(read::<Moved>(&old, 0), Moved(value()));
+        let new = varlena(&write(&Moved(value())), 0);
+        assert_eq!(read::<Moved>(&new, 0), Moved(value()));
+    }
+
+    #[test]
+    #[should_panic(expected = "has layout version 3, newer than version 2")]
+    fn newer_version() {
+        struct Old<'a>(Mixed<'a>);
+        impl<'a> FlatSerialize<'a> for Old<'a> {
+            const VERSION: u32 = 2;
+            fn write(&self, writer: &mut FlatWriter) {
+                self.0.write(writer)
+            }
+            fn read(reader: &mut FlatReader<'a>) -> Self {
+                Old(Mixed::read(reader))
+            }
+        }
+        read::<Old>(&varlena(&write(&value()), 0), 0);
+    }
+}
@@ -1,4 +1,8 @@
 
+// allow the code generated by `#[pg_extern]` to refer to this crate by name
+// from within it
+extern crate self as timescale_extension_utils;
+
 use std::{
     any::Any,
     mem,
@@ -13,147 +17,550 @@ use std::{
 };
 
 pub use postgres_headers_rs as pg_sys;
+pub use timescale_extension_utils_macros::{pg_extern, sql, FlatSerialize, PgComposite};
+pub mod aggregate;
+pub mod base_type;
+pub mod composite;
 pub mod datum;
 pub mod elog;
+pub mod fcinfo;
+pub mod flat;
+#[cfg(feature = "serde_datum")]
+pub mod serde_datum;
 pub mod palloc;
+pub mod spi;
+pub mod srf;
+pub mod window;
+
+pub use fcinfo::FcInfo;
 
-//TODO postgres version
+/// the `fcinfo` a V1 function is called with, see [`FcInfo`] for accessing it
+#[cfg(not(pg_ge_12))]
+pub type FunctionCallInfoData = pg_sys::FunctionCallInfoData;
+
+/// the `fcinfo` a V1 function is called with, see [`FcInfo`] for accessing it
+#[cfg(pg_ge_12)]
 pub type FunctionCallInfoData = pg_sys::FunctionCallInfoBaseData;
 
 // based heavily on pg-extend-rs
 
+/// Export Rust functions as Postgres V1 functions. Functions returning
+/// `impl Iterator<Item = T>` or `impl IntoIterator<Item = T>` are
+/// set-returning functions, see [`srf::return_set`](srf/fn.return_set.html).
+///
+/// Borrowed arguments such as `&str` are only valid for the call, so they
+/// cannot be `'static`:
+/// ```compile_fail,E0597
+/// timescale_extension_utils::pg_fn!{
+///     pub fn dangling(a: &'static str) -> i32 {
+///         a.len() as i32
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! pg_fn {
-    ($(pub fn $name:ident($($arg:ident : $typ:ty),* $(,)? $(; $fcinfo: ident)?) $(-> $ret:ty)? $body:block)+) => {
-        $(#[no_mangle]
-        pub extern "C" fn $name(fcinfo: $crate::pg_sys::FunctionCallInfo) -> $crate::pg_sys::Datum {
+    () => {};
+    (@export $(#[$attr:meta])* $name:ident, $fcinfo:ident => $body:tt) => {
+        $crate::pg_finfo!($name);
+
+        $(#[$attr])*
+        #[no_mangle]
+        pub extern "C" fn $name($fcinfo: $crate::pg_sys::FunctionCallInfo) -> $crate::pg_sys::Datum {
             // use a direct deref since this must always be set, and we can't risk a panic
             #[allow(unused_unsafe)]
             unsafe {
                 $crate::palloc::in_context($crate::pg_sys::CurrentMemoryContext, || {
-                    let fcinfo = &mut *fcinfo;
-                    $crate::pg_fn_body!(fcinfo; $name( $($arg:$typ,)*  $(; $fcinfo)? ) $(-> $ret)? $body );
+                    let $fcinfo = &mut *$fcinfo;
+                    $body
                 })
             }
-        })+
+        }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident($($arg:ident : $typ:ty),* $(,)? $(; $fcinfo: ident)?)
+            -> impl $iter:ident<Item = $item:ty> $body:block
+        $($rest:tt)*
+    ) => {
+        $crate::pg_fn!(@export $(#[$attr])* $name, fcinfo => {
+            $crate::pg_fn_body!(fcinfo; $name( $($arg:$typ,)*  $(; $fcinfo)? ) -> impl $iter<Item = $item> $body );
+        });
+        $crate::pg_fn!{ $($rest)* }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident($($arg:ident : $typ:ty),* $(,)? $(; $fcinfo: ident)?) $(-> $ret:ty)?
+            $body:block
+        $($rest:tt)*
+    ) => {
+        $crate::pg_fn!(@export $(#[$attr])* $name, fcinfo => {
+            $crate::pg_fn_body!(fcinfo; $name( $($arg:$typ,)*  $(; $fcinfo)? ) $(-> $ret)? $body );
+        });
+        $crate::pg_fn!{ $($rest)* }
     };
 }
 
+/// Export Rust functions as Postgres window functions. The first argument is
+/// the [`WindowObject`](window/struct.WindowObject.html) for the call, the
+/// rest are the function's arguments for the current row; the arguments for
+/// other rows can be read through the `WindowObject`.
+/// ```ignore
+/// pg_window_fn!{
+///     pub fn locf(window: WindowObject, value: Option<f64>) -> Option<f64> {
+///         window.partition_state(|| None, |last| {
+///             if value.is_some() {
+///                 *last = value;
+///             }
+///             *last
+///         })
+///     }
+/// }
+/// ```
 #[macro_export]
-macro_rules! pg_agg {
+macro_rules! pg_window_fn {
+    () => {};
     (
-        $(pub fn $name:ident($state:ident : Option<Pox<$styp:ty>> $(, $arg:ident : $typ:ty)* $(,)? $(; $fcinfo: ident)?) $(-> $ret:ty)?
-            $body:block)+
+        $(#[$attr:meta])* pub fn $name:ident($window:ident : WindowObject $(, $arg:ident : $typ:ty)* $(,)?) $(-> $ret:ty)?
+            $body:block
+        $($rest:tt)*
     ) => {
-        $(
-            #[no_mangle]
-            pub extern "C" fn $name(fcinfo: $crate::pg_sys::FunctionCallInfo) -> $crate::pg_sys::Datum {
-                use $crate::pg_sys::{AggCheckCallContext, MemoryContext};
-                // use a direct deref since this must always be set, and we can't risk a panic
-                let fcinfo = unsafe { &mut *fcinfo };
-
-                let mut agg_ctx: MemoryContext = std::ptr::null_mut();
-                if unsafe {AggCheckCallContext(fcinfo, &mut agg_ctx) == 0} {
-                    $crate::elog!($crate::elog::Level::Error, concat!("must call ", stringify!($name) ," as an aggregate"))
+        $crate::pg_fn!(@export $(#[$attr])* $name, fcinfo => {
+            $crate::pg_fn_body!(@guard fcinfo; {
+                #[allow(unused_imports)]
+                use $crate::elog::Level::Error;
+                #[allow(unused_mut)]
+                let mut $window = $crate::window::WindowObject::from_fcinfo(&*fcinfo);
+                #[allow(unused_variables)]
+                let call = ();
+                $(
+                    let $arg: $typ;
+                )*
+                {
+                    #[allow(unused_variables)]
+                    #[allow(unused_mut)]
+                    let mut args = $window.current_args($crate::FcInfo::nargs(fcinfo));
+                    $(
+                        let datum = args.next().unwrap_or_else(|| {
+                            $crate::elog!(Error,
+                                concat!("missing argument \"", stringify!($arg), "\""));
+                            unreachable!()
+                        });
+                        $arg = $crate::convert_arg::<$typ>(&call, datum)
+                            .unwrap_or_else(|| {
+                                $crate::elog!(Error,
+                                    concat!("NULL value for non-nullable argument \"",
+                                        stringify!($arg),
+                                        "\""
+                                    )
+                                );
+                                unreachable!()
+                            });
+                    )*
                 }
+                #[allow(unused_variables)]
+                #[allow(clippy::redundant_closure_call)]
+                let res = (move || { $body })();
+                $(
+                    return $crate::composite::returning::<$ret>(fcinfo, res);
+                )?
+                #[allow(unreachable_code)]
+                None
+            });
+        });
+        $crate::pg_window_fn!{ $($rest)* }
+    };
+}
 
-                unsafe {
-                    $crate::palloc::in_context(agg_ctx, || {
-                        $crate::pg_fn_body!(fcinfo; $name(@$state:Option<Pox<$styp>>, $($arg:$typ,)*  $(; $fcinfo)? ) $(-> $ret)? $body );
-                    })
-                }
+/// Define a custom base type from a type implementing
+/// [`PgType`](base_type/trait.PgType.html), exporting its input, output,
+/// receive and send functions under the given names, and implementing
+/// `FromDatum`, `ToDatum` and `TypeOid` for it so it can be used in other
+/// functions. Attributes, such as `#[sql(...)]`, apply to the type.
+///
+/// Generic types, such as `SerdeDatum<T>`, must implement the datum traits
+/// themselves, storing the `send` representation as
+/// [`base_type::to_datum`](base_type/fn.to_datum.html) does; only their
+/// functions are exported.
+/// ```ignore
+/// pg_type!{
+///     #[sql(name = "point2d")]
+///     Point2D {
+///         input = point2d_in,
+///         output = point2d_out,
+///         receive = point2d_recv,
+///         send = point2d_send,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! pg_type {
+    () => {};
+    (
+        @functions $(#[$attr:meta])* $ty:ty {
+            input = $input:ident,
+            output = $output:ident,
+            receive = $receive:ident,
+            send = $send:ident
+        }
+    ) => {
+        $crate::pg_fn!{
+            $(#[$attr])*
+            pub fn $input(input: &std::ffi::CStr) -> $ty {
+                $crate::base_type::input(input)
+            }
+
+            pub fn $output(value: $ty) -> std::ffi::CString {
+                <$ty as $crate::base_type::PgType>::output(&value)
+            }
+
+            pub fn $receive(buf: *mut $crate::pg_sys::StringInfoData) -> $ty {
+                unsafe { $crate::base_type::receive(buf) }
+            }
+
+            pub fn $send(value: $ty) -> Vec<u8> {
+                <$ty as $crate::base_type::PgType>::send(&value)
+            }
+        }
+    };
+    (
+        $(#[$attr:meta])* $ty:ident {
+            input = $input:ident,
+            output = $output:ident,
+            receive = $receive:ident,
+            send = $send:ident $(,)?
+        }
+        $($rest:tt)*
+    ) => {
+        impl $crate::datum::FromDatum<'_> for $ty {
+            unsafe fn from_datum(datum: $crate::pg_sys::Datum) -> Self {
+                $crate::base_type::from_datum(datum)
+            }
+        }
+
+        impl $crate::datum::ToDatum for $ty {
+            fn to_datum(self) -> $crate::pg_sys::Datum {
+                $crate::base_type::to_datum(&self)
             }
-        )+
+        }
+
+        // the type's oid is only known at runtime
+        impl $crate::datum::TypeOid for $ty {}
+
+        $crate::pg_type!{
+            @functions $(#[$attr])* $ty {
+                input = $input,
+                output = $output,
+                receive = $receive,
+                send = $send
+            }
+        }
+        $crate::pg_type!{ $($rest)* }
+    };
+    // generic types, such as `SerdeDatum<T>`, implement the datum traits
+    // themselves
+    (
+        $(#[$attr:meta])* $ty:ty {
+            input = $input:ident,
+            output = $output:ident,
+            receive = $receive:ident,
+            send = $send:ident $(,)?
+        }
+        $($rest:tt)*
+    ) => {
+        $crate::pg_type!{
+            @functions $(#[$attr])* $ty {
+                input = $input,
+                output = $output,
+                receive = $receive,
+                send = $send
+            }
+        }
+        $crate::pg_type!{ $($rest)* }
+    };
+}
+
+/// Export aggregate support functions. Transition and final functions take
+/// the `Option<Pox<State>>` aggregate state as their first argument, and run
+/// in the aggregate memory context.
+///
+/// A combine function takes two states, and combines the second into the
+/// first; when the first is `None` the result must be newly allocated, as the
+/// second state may not live in the aggregate context.
+///
+/// Moving-aggregate transition and inverse transition functions have the same
+/// form as transition functions. An inverse transition function returning
+/// `None` tells postgres the value cannot be removed from the state, so the
+/// aggregate is recomputed from scratch for the current frame.
+///
+/// Ordered-set and hypothetical-set aggregates are declared like any other:
+/// their transition function receives the aggregated arguments, and their
+/// final function the direct arguments, followed, for hypothetical-set
+/// aggregates, by `NULL`s in place of the aggregated arguments. The
+/// [`OrderedSetSort`](aggregate/struct.OrderedSetSort.html) state sorts the
+/// aggregated values as requested by `WITHIN GROUP (ORDER BY ...)`.
+///
+/// Serialization and deserialization functions for `internal` states are
+/// declared without a body, and are implemented using the
+/// [`SerializableState`](aggregate/trait.SerializableState.html) impl of the
+/// state.
+/// ```ignore
+/// pg_agg!{
+///     pub fn my_avg_combine(a: Option<Pox<AvgState>>, b: Option<Pox<AvgState>>)
+///     -> Option<Pox<AvgState>> { ... }
+///
+///     pub fn my_avg_serialize(state: Pox<AvgState>) -> Vec<u8>;
+///
+///     pub fn my_avg_deserialize(bytes: &[u8]) -> Pox<AvgState>;
+/// }
+/// ```
+#[macro_export]
+macro_rules! pg_agg {
+    () => {};
+    // export `$name` as an aggregate support function, running `$body` in the
+    // memory context `$context`, which may refer to the aggregate context as
+    // `$agg_ctx`
+    (@export $(#[$attr:meta])* $name:ident, $fcinfo:ident, $agg_ctx:ident, $context:expr => $body:tt) => {
+        $crate::pg_finfo!($name);
+
+        $(#[$attr])*
+        #[no_mangle]
+        pub extern "C" fn $name($fcinfo: $crate::pg_sys::FunctionCallInfo) -> $crate::pg_sys::Datum {
+            use $crate::pg_sys::{AggCheckCallContext, MemoryContext};
+            // use a direct deref since this must always be set, and we can't risk a panic
+            let $fcinfo = unsafe { &mut *$fcinfo };
+
+            let mut $agg_ctx: MemoryContext = std::ptr::null_mut();
+            if unsafe {AggCheckCallContext($fcinfo, &mut $agg_ctx) == 0} {
+                // we're outside of catch_unwind here, so we must longjmp directly
+                $crate::elog!(#unguarded $crate::elog::Level::Error, concat!("must call ", stringify!($name) ," as an aggregate"))
+            }
+
+            #[allow(unused_unsafe)]
+            unsafe {
+                $crate::palloc::in_context($context, || $body)
+            }
+        }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident($state:ident : Pox<$styp:ty> $(,)?) -> Vec<u8>;
+        $($rest:tt)*
+    ) => {
+        // the serialized state is the function's result, not part of the state
+        $crate::pg_agg!(@export $(#[$attr])* $name, fcinfo, agg_ctx, $crate::pg_sys::CurrentMemoryContext => {
+            $crate::pg_fn_body!(fcinfo; $name(@$state:Option<Pox<$styp>>,) -> Option<Vec<u8>> {
+                $state.map(|s| <$styp as $crate::aggregate::SerializableState>::serialize(&*s))
+            });
+        });
+        $crate::pg_agg!{ $($rest)* }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident($bytes:ident : &[u8] $(,)?) -> Pox<$styp:ty>;
+        $($rest:tt)*
+    ) => {
+        $crate::pg_agg!(@export $(#[$attr])* $name, fcinfo, agg_ctx, agg_ctx => {
+            $crate::pg_fn_body!(fcinfo; $name($bytes:&[u8],) -> Pox<$styp> {
+                Pox::new(<$styp as $crate::aggregate::SerializableState>::deserialize($bytes))
+            });
+        });
+        $crate::pg_agg!{ $($rest)* }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident(
+            $state:ident : Option<Pox<$styp:ty>>, $state2:ident : Option<Pox<$styp2:ty>> $(,)? $(; $fcinfo: ident)?
+        ) $(-> $ret:ty)? $body:block
+        $($rest:tt)*
+    ) => {
+        $crate::pg_agg!(@export $(#[$attr])* $name, fcinfo, agg_ctx, agg_ctx => {
+            $crate::pg_fn_body!(fcinfo; $name(@$state:Option<Pox<$styp>>, @$state2:Option<Pox<$styp2>>, $(; $fcinfo)? ) $(-> $ret)? $body );
+        });
+        $crate::pg_agg!{ $($rest)* }
+    };
+    (
+        $(#[$attr:meta])* pub fn $name:ident($state:ident : Option<Pox<$styp:ty>> $(, $arg:ident : $typ:ty)* $(,)? $(; $fcinfo: ident)?) $(-> $ret:ty)?
+            $body:block
+        $($rest:tt)*
+    ) => {
+        $crate::pg_agg!(@export $(#[$attr])* $name, fcinfo, agg_ctx, agg_ctx => {
+            $crate::pg_fn_body!(fcinfo; $name(@$state:Option<Pox<$styp>>, $($arg:$typ,)*  $(; $fcinfo)? ) $(-> $ret)? $body );
+        });
+        $crate::pg_agg!{ $($rest)* }
     };
 }
 
+/// emit the `pg_finfo_<name>` symbol postgres uses to determine that `name`
+/// uses the V1 calling convention, i.e. `PG_FUNCTION_INFO_V1(name)`
 #[macro_export]
 #[doc(hidden)]
-macro_rules! pg_fn_body {
-    ($fc:ident; $name:ident($(@$state:ident : Option<Pox<$styp:ty>>,)? $($arg:ident : $typ:ty,)* $(; $fcinfo:ident)? ) $(-> $ret:ty)? $body:block) => {
-        use std::panic::{catch_unwind, AssertUnwindSafe};
-        #[allow(unused_imports)]
-        use $crate::{
-            datum::FromOptionalDatum,
-            elog::Level::Error,
-            palloc::Pox,
-        };
-        // guard against panics in the rust code so we don't unwind into pg
-        let result: Result<Option<$crate::pg_sys::Datum>, _> = catch_unwind(AssertUnwindSafe(|| {
-            $(
-                let $state: Option<Pox<$styp>>;
-            )?
-            $(
-                let $arg: $typ;
-            )*
-            {
-                #[allow(unused_variables)]
-                #[allow(unused_mut)]
-                let mut args = $crate::get_args(&*$fc);
-                $(
-                    let datum = args.next().expect("not enough arguments for aggregate state");
-                    $state = <Option<*mut $styp> as FromOptionalDatum>::from_optional_datum(datum)
-                        .map(|p| Pox::from_raw_unchecked(p));
-                )?
-                $(
-                    let datum = args.next().unwrap_or_else(|| {
-                        $crate::elog!(Error,
-                            concat!("missing argument \"", stringify!($arg), "\""));
-                        unreachable!()
-                    });
-                    $arg = <$typ as FromOptionalDatum>::try_from_optional_datum(datum)
-                        .unwrap_or_else(|| {
-                            $crate::elog!(Error,
-                                concat!("NULL value for non-nullable argument \"",
-                                    stringify!($arg),
-                                    "\""
-                                )
-                            );
-                            unreachable!()
-                        });
-                )*
+macro_rules! pg_finfo {
+    ($name:ident) => {
+        const _: () = {
+            #[export_name = concat!("pg_finfo_", stringify!($name))]
+            pub extern "C" fn pg_finfo() -> &'static $crate::pg_sys::Pg_finfo_record {
+                &$crate::PG_FINFO_V1
             }
-            $(let $fcinfo: &mut $crate::FunctionCallInfoData = $fc;)?
+        };
+    };
+}
+
+#[doc(hidden)]
+pub static PG_FINFO_V1: pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version: 1 };
+
+/// Emit the `Pg_magic_func` postgres uses to check that a library was built
+/// against a compatible server, i.e. `PG_MODULE_MAGIC`. This must be invoked
+/// exactly once per extension library.
+#[macro_export]
+macro_rules! pg_module_magic {
+    () => {
+        #[no_mangle]
+        pub extern "C" fn Pg_magic_func() -> &'static $crate::pg_sys::Pg_magic_struct {
+            &$crate::PG_MODULE_MAGIC_DATA
+        }
+    };
+}
+
+#[doc(hidden)]
+pub static PG_MODULE_MAGIC_DATA: pg_sys::Pg_magic_struct = pg_sys::Pg_magic_struct {
+    len: mem::size_of::<pg_sys::Pg_magic_struct>() as _,
+    version: (pg_sys::PG_VERSION_NUM / 100) as _,
+    funcmaxargs: pg_sys::FUNC_MAX_ARGS as _,
+    indexmaxkeys: pg_sys::INDEX_MAX_KEYS as _,
+    namedatalen: pg_sys::NAMEDATALEN as _,
+    #[cfg(not(pg_ge_13))]
+    float4byval: pg_sys::USE_FLOAT4_BYVAL as _,
+    float8byval: pg_sys::USE_FLOAT8_BYVAL as _,
+    #[cfg(pg_ge_15)]
+    abi_extra: abi_extra(b"PostgreSQL"),
+};
+
+/// `FMGR_ABI_EXTRA` as the NUL-padded `abi_extra` field of `Pg_magic_struct`
+#[cfg(pg_ge_15)]
+const fn abi_extra(name: &[u8]) -> [std::os::raw::c_char; 32] {
+    let mut field = [0; 32];
+    let mut i = 0;
+    while i < name.len() {
+        field[i] = name[i] as _;
+        i += 1;
+    }
+    field
+}
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! pg_fn_body {
+    ($fc:ident; $name:ident($($arg:ident : $typ:ty,)* $(; $fcinfo:ident)? ) -> impl $iter:ident<Item = $item:ty> $body:block) => {
+        $crate::pg_fn_body!(@guard $fc; {
+            // the arguments only live for the first call, so the iterator
+            // return_set() keeps for the later ones cannot borrow them
+            #[allow(unused_variables)]
+            let call = ();
+            $crate::srf::return_set::<$item, _, _>($fc, |$fc: &mut $crate::FunctionCallInfoData| {
+                $crate::pg_fn_call!($fc, call; $($arg:$typ,)* $(; $fcinfo)? ; $body)
+            })
+        })
+    };
+    ($fc:ident; $name:ident($(@$state:ident : Option<Pox<$styp:ty>>,)* $($arg:ident : $typ:ty,)* $(; $fcinfo:ident)? ) $(-> $ret:ty)? $body:block) => {
+        $crate::pg_fn_body!(@guard $fc; {
+            #[allow(unused_variables)]
+            let call = ();
             #[allow(unused_variables)]
-            let res = (|| { $body })();
+            let res = $crate::pg_fn_call!($fc, call; $(@$state:Option<Pox<$styp>>,)* $($arg:$typ,)* $(; $fcinfo)? ; $body);
             $(
-                return <$ret as $crate::datum::ToOptionalDatum>::to_optional_datum(res);
+                return $crate::composite::returning::<$ret>($fc, res);
             )?
             #[allow(unreachable_code)]
             None
-        }));
+        })
+    };
+    (@guard $fc:ident; $result:block) => {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        // guard against panics in the rust code so we don't unwind into pg
+        let result: Result<Option<$crate::pg_sys::Datum>, _> = catch_unwind(AssertUnwindSafe(|| $result));
         match result {
             Ok(Some(datum)) => {
-                $fc.isnull = false;
+                $crate::FcInfo::set_return_null($fc, false);
                 return datum;
             },
             Ok(None) => {
-                $fc.isnull = true;
+                $crate::FcInfo::set_return_null($fc, true);
                 return 0;
             },
             Err(err) => {
-                $fc.isnull = true;
+                $crate::FcInfo::set_return_null($fc, true);
                 $crate::handle_unwind(err)
             },
         }
-    }
+    };
+}
+
+/// convert the arguments of a `pg_fn!` and run its body
+#[macro_export]
+#[doc(hidden)]
+macro_rules! pg_fn_call {
+    ($fc:ident, $call:ident; $(@$state:ident : Option<Pox<$styp:ty>>,)* $($arg:ident : $typ:ty,)* $(; $fcinfo:ident)? ; $body:block) => {{
+        #[allow(unused_imports)]
+        use $crate::{
+            datum::FromOptionalDatum,
+            elog::Level::Error,
+            palloc::Pox,
+        };
+        $(
+            let $state: Option<Pox<$styp>>;
+        )*
+        $(
+            let $arg: $typ;
+        )*
+        {
+            #[allow(unused_variables)]
+            #[allow(unused_mut)]
+            let mut args = $crate::get_args(&*$fc);
+            $(
+                let datum = args.next().expect("not enough arguments for aggregate state");
+                $state = <Option<*mut $styp> as FromOptionalDatum>::from_optional_datum(datum)
+                    .map(|p| Pox::from_raw_unchecked(p));
+            )*
+            $(
+                let datum = args.next().unwrap_or_else(|| {
+                    $crate::elog!(Error,
+                        concat!("missing argument \"", stringify!($arg), "\""));
+                    unreachable!()
+                });
+                $arg = $crate::convert_arg::<$typ>(&$call, datum)
+                    .unwrap_or_else(|| {
+                        $crate::elog!(Error,
+                            concat!("NULL value for non-nullable argument \"",
+                                stringify!($arg),
+                                "\""
+                            )
+                        );
+                        unreachable!()
+                    });
+            )*
+        }
+        $(let $fcinfo: &mut $crate::FunctionCallInfoData = $fc;)?
+        // move, so that set-returning functions can return iterators that
+        // own their arguments
+        #[allow(clippy::redundant_closure_call)]
+        (move || { $body })()
+    }};
+}
+
+/// Convert an argument of `pg_fn!` and friends, which may borrow from the
+/// call. `_call` borrows a local of the generated function standing in for
+/// the call, so an argument type that would outlive it, e.g. `&'static str`, is
+/// rejected by the borrow checker instead of dangling.
+///
+/// # Safety
+/// `datum` must be an argument of the current call, see `FromDatum`
+#[doc(hidden)]
+pub unsafe fn convert_arg<'call, T: datum::FromOptionalDatum<'call>>(
+    _call: &'call (),
+    datum: Option<pg_sys::Datum>,
+) -> Option<T> {
+    T::try_from_optional_datum(datum)
 }
 
 pub fn get_args<'a>(
     fcinfo: &'a FunctionCallInfoData
 ) -> impl 'a + Iterator<Item = Option<postgres_headers_rs::Datum>> {
-    let num_args = fcinfo.nargs as usize;
-
-    //TODO pg version
-    return unsafe { fcinfo.args.as_slice(num_args) }
-        .iter()
-        .map(|nullable| {
-            if nullable.isnull {
-                None
-            } else {
-                Some(nullable.value)
-            }
-        });
+    (0..fcinfo.nargs()).map(move |n| fcinfo.arg(n))
 }
 
 
@@ -299,6 +706,40 @@ pub unsafe fn pg_try_re_throw<R, F: FnOnce() -> R, G: FnOnce()>(
 #[cfg(test)]
 mod tests {
 
+    #[test]
+    fn version() {
+        use crate::pg_sys::version::{PG_MAJORVERSION, PG_MAJORVERSION_NUM, PG_VERSION_NUM};
+        let features = [
+            (11, cfg!(feature = "pg11")),
+            (12, cfg!(feature = "pg12")),
+            (13, cfg!(feature = "pg13")),
+            (14, cfg!(feature = "pg14")),
+            (15, cfg!(feature = "pg15")),
+            (16, cfg!(feature = "pg16")),
+        ];
+        let selected: Vec<u32> = features.iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(version, _)| *version)
+            .collect();
+        assert_eq!(selected, [PG_MAJORVERSION_NUM]);
+        assert_eq!(PG_MAJORVERSION, PG_MAJORVERSION_NUM.to_string());
+        assert_eq!(PG_VERSION_NUM / 10000, PG_MAJORVERSION_NUM);
+
+        let pg_ge = [
+            (11, cfg!(pg_ge_11)),
+            (12, cfg!(pg_ge_12)),
+            (13, cfg!(pg_ge_13)),
+            (14, cfg!(pg_ge_14)),
+            (15, cfg!(pg_ge_15)),
+            (16, cfg!(pg_ge_16)),
+        ];
+        for (version, set) in pg_ge {
+            assert_eq!(set, version <= PG_MAJORVERSION_NUM, "pg_ge_{}", version);
+        }
+    }
+
+    crate::pg_module_magic!();
+
     crate::pg_fn!{
         pub fn compile_test(a: i32) -> i32 {
             return a + 1
@@ -326,12 +767,40 @@ mod tests {
         }
     }
 
+    crate::pg_fn!{
+        /// doc comments are kept
+        #[crate::sql(immutable, parallel_safe, name = "compile_test_sql_name")]
+        pub fn compile_test_sql(a: i64, b: Option<f64>) -> f64 {
+            a as f64 + b.unwrap_or(0.0)
+        }
+    }
+
     crate::pg_fn!{
         pub fn compile_test_fcinfo(; fcinfo) -> i16 {
             fcinfo.nargs
         }
     }
 
+    crate::pg_fn!{
+        pub fn compile_test_version() -> String {
+            use crate::pg_sys::version::PG_MAJORVERSION;
+            if cfg!(pg_ge_13) {
+                format!("{} or later", PG_MAJORVERSION)
+            } else {
+                PG_MAJORVERSION.to_string()
+            }
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_fcinfo_args(a: Option<i32>; fcinfo) -> Option<i64> {
+            use crate::FcInfo;
+            let _ = (fcinfo.flinfo(), fcinfo.context(), fcinfo.resultinfo(), fcinfo.collation());
+            let nulls = (0..fcinfo.nargs()).filter(|&n| fcinfo.arg(n).is_none()).count();
+            a.map(|a| a as i64 + nulls as i64)
+        }
+    }
+
     crate::pg_fn!{
         pub fn compile_test_multi0(a: u32, b: u32; fcinfo) -> u32 {
             a + b + fcinfo.nargs as u32
@@ -342,6 +811,219 @@ mod tests {
         }
     }
 
+    crate::pg_fn!{
+        pub fn compile_test_text(a: &str, b: String) -> String {
+            format!("{}{}", a, b)
+        }
+
+        pub fn compile_test_bytea(a: &[u8], b: Option<Vec<u8>>) -> Vec<u8> {
+            let mut a = a.to_vec();
+            a.extend(b.unwrap_or_default());
+            a
+        }
+
+        pub fn compile_test_pg_text(a: crate::datum::PgText) -> crate::datum::PgText {
+            a
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_result(a: i32, b: i32) -> Result<i32, crate::elog::PgError> {
+            use crate::elog::{PgError, SqlState};
+            if b == 0 {
+                return Err(PgError::new(SqlState::DivisionByZero, "division by zero")
+                    .hint("b must be non-zero"))
+            }
+            Ok(a / b)
+        }
+
+        pub fn compile_test_result_option(a: Option<i32>) -> Result<Option<i32>, CustomError> {
+            match a {
+                Some(a) if a < 0 => Err(CustomError(a)),
+                a => Ok(a),
+            }
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_srf(a: i32, b: i32) -> impl Iterator<Item = i32> {
+            a..b
+        }
+
+        pub fn compile_test_srf_vec(a: &str) -> impl IntoIterator<Item = Option<String>> {
+            a.split(',')
+                .map(|s| if s.is_empty() { None } else { Some(s.to_string()) })
+                .collect::<Vec<_>>()
+        }
+
+        // the arguments can be borrowed while creating the iterator, but not
+        // by the iterator itself
+        pub fn compile_test_srf_words(a: &str; fcinfo) -> impl Iterator<Item = String> {
+            let words: Vec<String> = a.split_whitespace().map(String::from).collect();
+            let nargs = fcinfo.nargs;
+            words.into_iter().map(move |word| format!("{}/{}", word, nargs))
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_array(a: crate::datum::PgArray<f64>) -> Vec<i64> {
+            a.map(|f| f as i64).collect()
+        }
+
+        pub fn compile_test_array_nulls(a: crate::datum::PgArray<Option<&str>>) -> Vec<Option<String>> {
+            let _ = (a.dims(), a.lower_bounds());
+            a.map(|s| s.map(str::to_uppercase)).collect()
+        }
+    }
+
+    #[derive(crate::PgComposite)]
+    pub struct CompileTestRow<'a> {
+        name: &'a str,
+        value: Option<i64>,
+        nested: Option<CompileTestPair>,
+    }
+
+    #[derive(crate::PgComposite)]
+    pub struct CompileTestPair(i32, f64);
+
+    crate::pg_fn!{
+        pub fn compile_test_composite(row: CompileTestRow) -> Option<CompileTestPair> {
+            row.nested.map(|CompileTestPair(a, b)| CompileTestPair(a + 1, b))
+        }
+
+        pub fn compile_test_table(a: i32) -> impl Iterator<Item = CompileTestPair> {
+            (0..a).map(|i| CompileTestPair(i, i as f64))
+        }
+    }
+
+    #[derive(crate::FlatSerialize)]
+    #[flat_serialize(version = 2, read_version = "CompileTestSummary::read_v1")]
+    pub struct CompileTestSummary<'a> {
+        count: u64,
+        bounds: [f64; 2],
+        label: &'a str,
+        buckets: &'a [u32],
+    }
+
+    impl<'a> CompileTestSummary<'a> {
+        // version 1 had no bounds
+        fn read_v1(_version: u32, reader: &mut crate::flat::FlatReader<'a>) -> Self {
+            let count = reader.scalar();
+            let label_len = reader.tail_len();
+            let buckets_len = reader.tail_len();
+            let label = reader.tail_str(label_len);
+            let buckets = reader.tail(buckets_len);
+            CompileTestSummary { count, bounds: [0.0, buckets.len() as f64], label, buckets }
+        }
+    }
+
+    #[derive(crate::FlatSerialize)]
+    pub struct CompileTestFlatPair(i16, f32);
+
+    // the same layout as CompileTestSummary, owning its tails
+    #[derive(crate::FlatSerialize)]
+    #[flat_serialize(version = 2)]
+    pub struct CompileTestSummaryBuf {
+        count: u64,
+        bounds: [f64; 2],
+        label: String,
+        buckets: Vec<u32>,
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_summary(label: &str, buckets: crate::datum::PgArray<i32>) -> CompileTestSummaryBuf {
+            let buckets: Vec<u32> = buckets.map(|b| b as u32).collect();
+            CompileTestSummaryBuf {
+                count: buckets.iter().map(|&b| b as u64).sum(),
+                bounds: [0.0, buckets.len() as f64],
+                label: label.to_string(),
+                buckets,
+            }
+        }
+
+        pub fn compile_test_summary_label(summary: CompileTestSummary) -> &str {
+            summary.label
+        }
+
+        pub fn compile_test_summary_total(summary: CompileTestSummary) -> i64 {
+            let _ = summary.bounds;
+            (summary.count + summary.buckets.len() as u64) as i64
+        }
+
+        pub fn compile_test_flat_pair(pair: CompileTestFlatPair) -> CompileTestFlatPair {
+            CompileTestFlatPair(pair.0 + 1, pair.1)
+        }
+    }
+
+    struct CustomError(i32);
+
+    impl From<CustomError> for crate::elog::PgError {
+        fn from(err: CustomError) -> Self {
+            crate::elog::PgError::new(
+                crate::elog::SqlState::InvalidParameterValue,
+                format!("{} is negative", err.0),
+            )
+        }
+    }
+
+    /// doc comments and attributes are kept
+    #[crate::pg_extern(stable, strict)]
+    #[inline]
+    pub fn compile_test_attr(a: i32, b: Option<i32>) -> i32 {
+        a + b.unwrap_or(0)
+    }
+
+    #[crate::pg_extern]
+    fn compile_test_attr_patterns<'a>(mut a: &'a str, _: i32) -> &'a str
+    where 'a: 'a {
+        a = &a[1..];
+        a
+    }
+
+    #[crate::pg_extern]
+    pub fn compile_test_attr_fcinfo(a: i32, fcinfo: &mut crate::FunctionCallInfoData) {
+        let _ = a + fcinfo.nargs as i32;
+    }
+
+    #[crate::pg_extern(immutable)]
+    pub fn compile_test_attr_srf(a: i64) -> impl Iterator<Item = Option<i64>> {
+        (0..a).map(|i| if i % 2 == 0 { Some(i) } else { None })
+    }
+
+    // the same row type as CompileTestRow, owning its name
+    #[derive(crate::PgComposite)]
+    pub struct CompileTestRowBuf {
+        name: String,
+        value: Option<i64>,
+        nested: Option<CompileTestPair>,
+    }
+
+    #[crate::pg_extern]
+    pub fn compile_test_attr_composite(name: String) -> CompileTestRowBuf {
+        CompileTestRowBuf { name, value: None, nested: None }
+    }
+
+    use crate::palloc::Pox;
+
+    #[crate::pg_extern(aggregate)]
+    pub fn compile_test_attr_sfunc(state: Option<Pox<usize>>, val: i64) -> Option<Pox<usize>> {
+        let mut state = state.unwrap_or_else(|| Pox::new(0));
+        *state += val as usize;
+        Some(state)
+    }
+
+    #[crate::pg_extern(aggregate)]
+    pub fn compile_test_attr_combine(a: Option<Pox<usize>>, b: Option<Pox<usize>>) -> Option<Pox<usize>> {
+        match (a, b) {
+            (Some(mut a), Some(b)) => {
+                *a += *b;
+                Some(a)
+            },
+            (a, None) => a,
+            (None, Some(b)) => Some(Pox::new(*b)),
+        }
+    }
+
     crate::pg_agg!{
         pub fn compile_test_sfunc(state: Option<Pox<usize>>) -> Option<Pox<usize>> {
             state
@@ -351,4 +1033,332 @@ mod tests {
             state.map(|s| *s).unwrap_or_else(|| 0)
         }
     }
+
+    crate::pg_agg!{
+        #[crate::sql(msfunc = "compile_test_moving")]
+        pub fn compile_test_msfunc(state: Option<Pox<usize>>, val: usize) -> Option<Pox<usize>> {
+            let mut state = state.unwrap_or_else(|| Pox::new(0));
+            *state += val;
+            Some(state)
+        }
+
+        #[crate::sql(minvfunc = "compile_test_moving")]
+        pub fn compile_test_minvfunc(state: Option<Pox<usize>>, val: usize) -> Option<Pox<usize>> {
+            // returning None forces the frame to be recomputed
+            let mut state = state?;
+            *state = state.checked_sub(val)?;
+            Some(state)
+        }
+
+        #[crate::sql(mfinalfunc = "compile_test_moving")]
+        pub fn compile_test_mfinal(state: Option<Pox<usize>>) -> Option<usize> {
+            state.map(|s| *s)
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_spi(schema: &str) -> Option<i64> {
+            use crate::spi::{Spi, SpiArg};
+            let spi = Spi::connect();
+            let tables = spi.select(
+                "SELECT relname::text, relpages FROM pg_class WHERE relnamespace = $1::regnamespace",
+                &[SpiArg::new(schema)],
+            );
+            let names: Vec<String> = tables.rows()
+                .map(|row| row.get::<&str>(0).to_string())
+                .collect();
+            let _pages: Option<i32> = tables.first().and_then(|row| row.get_by_name("relpages"));
+            drop(tables);
+            spi.execute("CREATE TEMP TABLE IF NOT EXISTS compile_test (name text)", &[]);
+            let inserted = spi.execute(
+                "INSERT INTO compile_test SELECT unnest($1)",
+                &[SpiArg::new(names)],
+            ).processed();
+            let count: Option<i64> = spi.get_one("SELECT count(*) FROM compile_test", &[]);
+            count.map(|count| count + inserted as i64)
+        }
+    }
+
+    crate::pg_fn!{
+        // composites are built as the row type of the parameter
+        pub fn compile_test_spi_composite(pair: CompileTestPair, type_id: crate::pg_sys::Oid) -> Option<i32> {
+            use crate::spi::{Spi, SpiArg};
+            let spi = Spi::connect();
+            spi.get_one("SELECT ($1).f1", &[SpiArg::with_type(type_id, pair)])
+        }
+    }
+
+    static COMPILE_TEST_PLAN: crate::spi::PlanCell = crate::spi::PlanCell::new();
+
+    crate::pg_fn!{
+        pub fn compile_test_spi_plan(min_pages: i32) -> i64 {
+            use crate::spi::{Spi, SpiArg};
+            let spi = Spi::connect();
+            let plan = COMPILE_TEST_PLAN.get_or_prepare(
+                &spi,
+                "SELECT relname::text FROM pg_class WHERE relpages >= $1",
+                &[crate::pg_sys::INT4OID],
+            );
+            let mut total = spi.select_plan(plan, &[SpiArg::new(min_pages)]).len() as i64;
+
+            let local = spi.prepare("SELECT relpages FROM pg_class WHERE relpages >= $1", &[crate::pg_sys::INT4OID]);
+            let mut cursor = spi.open_plan_cursor(&local, &[SpiArg::new(min_pages)], 100);
+            cursor.move_forward(1);
+            for batch in cursor {
+                total += batch.rows().map(|row| row.get::<i32>(0) as i64).sum::<i64>();
+            }
+            for batch in spi.open_cursor("SELECT generate_series(1, $1)", &[SpiArg::new(min_pages)], 10) {
+                total += batch.len() as i64;
+            }
+            total
+        }
+    }
+
+    pub struct CompileTestPoint {
+        x: f64,
+        y: f64,
+    }
+
+    impl crate::base_type::PgType for CompileTestPoint {
+        fn input(input: &std::ffi::CStr) -> Result<Self, String> {
+            let input = input.to_str().map_err(|err| err.to_string())?;
+            let (x, y) = input.trim_matches(|c| c == '(' || c == ')')
+                .split_once(',')
+                .ok_or_else(|| format!("expected (x,y), got \"{}\"", input))?;
+            Ok(CompileTestPoint {
+                x: x.trim().parse().map_err(|_| format!("invalid x \"{}\"", x))?,
+                y: y.trim().parse().map_err(|_| format!("invalid y \"{}\"", y))?,
+            })
+        }
+
+        fn output(&self) -> std::ffi::CString {
+            std::ffi::CString::new(format!("({},{})", self.x, self.y)).unwrap()
+        }
+
+        fn send(&self) -> Vec<u8> {
+            let mut bytes = self.x.to_be_bytes().to_vec();
+            bytes.extend_from_slice(&self.y.to_be_bytes());
+            bytes
+        }
+
+        fn receive(bytes: &[u8]) -> Result<Self, String> {
+            use std::convert::TryInto;
+            if bytes.len() != 16 {
+                return Err(format!("expected 16 bytes, got {}", bytes.len()))
+            }
+            let (x, y) = bytes.split_at(8);
+            Ok(CompileTestPoint {
+                x: f64::from_be_bytes(x.try_into().unwrap()),
+                y: f64::from_be_bytes(y.try_into().unwrap()),
+            })
+        }
+    }
+
+    crate::pg_type!{
+        #[crate::sql(name = "compile_test_point")]
+        CompileTestPoint {
+            input = compile_test_point_in,
+            output = compile_test_point_out,
+            receive = compile_test_point_recv,
+            send = compile_test_point_send,
+        }
+    }
+
+    crate::pg_fn!{
+        pub fn compile_test_point_x(point: CompileTestPoint) -> f64 {
+            point.x
+        }
+
+        pub fn compile_test_point_flip(point: Option<CompileTestPoint>) -> Option<CompileTestPoint> {
+            point.map(|p| CompileTestPoint { x: p.y, y: p.x })
+        }
+    }
+
+    #[cfg(feature = "serde_datum")]
+    #[derive(serde::Serialize, serde::Deserialize)]
+    pub struct CompileTestSketch {
+        name: String,
+        counts: std::collections::BTreeMap<String, u64>,
+    }
+
+    #[cfg(feature = "serde_datum")]
+    crate::pg_type!{
+        crate::serde_datum::SerdeDatum<CompileTestSketch> {
+            input = compile_test_sketch_in,
+            output = compile_test_sketch_out,
+            receive = compile_test_sketch_recv,
+            send = compile_test_sketch_send,
+        }
+    }
+
+    #[cfg(feature = "serde_datum")]
+    crate::pg_fn!{
+        pub fn compile_test_sketch(name: String, keys: crate::datum::PgArray<&str>)
+        -> crate::serde_datum::SerdeDatum<CompileTestSketch> {
+            let mut counts = std::collections::BTreeMap::new();
+            for key in keys {
+                *counts.entry(key.to_string()).or_insert(0) += 1;
+            }
+            CompileTestSketch { name, counts }.into()
+        }
+
+        pub fn compile_test_sketch_count(
+            sketch: crate::serde_datum::SerdeDatum<CompileTestSketch>,
+            key: &str,
+        ) -> Option<i64> {
+            let _ = &sketch.name;
+            sketch.counts.get(key).map(|&count| count as i64)
+        }
+
+        pub fn compile_test_serde_vec(values: crate::serde_datum::SerdeDatum<Vec<f64>>) -> f64 {
+            values.into_inner().into_iter().sum()
+        }
+    }
+
+    crate::pg_window_fn!{
+        pub fn compile_test_locf(window: WindowObject, value: Option<f64>) -> Option<f64> {
+            window.partition_state(|| None, |last| {
+                if value.is_some() {
+                    *last = value;
+                }
+                *last
+            })
+        }
+
+        pub fn compile_test_running_values(window: WindowObject, value: Option<i32>) -> i64 {
+            window.partition_state(Vec::new, |values: &mut Vec<i32>| {
+                values.extend(value);
+                values.len() as i64
+            })
+        }
+
+        #[crate::sql(immutable)]
+        pub fn compile_test_interpolate(window: WindowObject, value: Option<f64>) -> Option<f64> {
+            use crate::window::Seek;
+            if value.is_some() {
+                return value
+            }
+            let prev = window.get_arg_in_partition::<Option<f64>>(0, -1, Seek::Current, false)??;
+            let next = window.get_arg_in_partition::<Option<f64>>(0, 1, Seek::Current, false)??;
+            Some((prev + next) / 2.0)
+        }
+
+        pub fn compile_test_position(window: WindowObject, value: Option<i32>) -> i64 {
+            let first = window.get_arg_in_frame::<Option<i32>>(0, 0, crate::window::Seek::Head, false);
+            let _ = (value, first, window.rows_are_peers(0, 0), window.partition_row_count());
+            window.current_position()
+        }
+    }
+
+    crate::pg_agg!{
+        #[crate::sql(sfunc = "compile_test_percentile")]
+        pub fn compile_test_percentile_trans(
+            state: Option<Pox<crate::aggregate::OrderedSetSort>>, value: Option<f64>; fcinfo
+        ) -> Option<Pox<crate::aggregate::OrderedSetSort>> {
+            let mut state = state.unwrap_or_else(|| unsafe {
+                crate::aggregate::OrderedSetSort::new(fcinfo)
+            });
+            if value.is_some() {
+                state.put(value);
+            }
+            Some(state)
+        }
+
+        #[crate::sql(finalfunc = "compile_test_percentile", ordered_set)]
+        pub fn compile_test_percentile_final(
+            state: Option<Pox<crate::aggregate::OrderedSetSort>>, fraction: f64
+        ) -> Option<f64> {
+            let mut state = state?;
+            let n = state.len().checked_sub(1)?;
+            state.sorted::<f64>().nth((fraction * n as f64) as usize)
+        }
+
+        #[crate::sql(finalfunc = "compile_test_rank", hypothetical)]
+        pub fn compile_test_rank_final(
+            state: Option<Pox<crate::aggregate::OrderedSetSort>>, value: Option<f64>
+        ) -> i64 {
+            let mut state = match state {
+                Some(state) => state,
+                None => return 1,
+            };
+            let before = state.sorted::<Option<f64>>()
+                .take_while(|v| v.is_some() && *v < value)
+                .count();
+            before as i64 + 1
+        }
+    }
+
+    crate::pg_agg!{
+        #[crate::sql(sfunc = "compile_test_sum", parallel_safe)]
+        pub fn compile_test_sum_trans(state: Option<Pox<i64>>, val: i64) -> Option<Pox<i64>> {
+            let mut state = state.unwrap_or_else(|| Pox::new(0));
+            *state += val;
+            Some(state)
+        }
+
+        #[crate::sql(finalfunc = "compile_test_sum", parallel_safe)]
+        pub fn compile_test_sum_final(state: Option<Pox<i64>>) -> Option<i64> {
+            state.map(|s| *s)
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct CompileTestAvg {
+        sum: f64,
+        count: u64,
+    }
+
+    impl crate::aggregate::SerializableState for CompileTestAvg {
+        fn serialize(&self) -> Vec<u8> {
+            let mut bytes = self.sum.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&self.count.to_le_bytes());
+            bytes
+        }
+
+        fn deserialize(bytes: &[u8]) -> Self {
+            use std::convert::TryInto;
+            CompileTestAvg {
+                sum: f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+                count: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            }
+        }
+    }
+
+    crate::pg_agg!{
+        #[crate::sql(sfunc = "compile_test_avg", parallel_safe)]
+        pub fn compile_test_avg_trans(state: Option<Pox<CompileTestAvg>>, val: f64)
+        -> Option<Pox<CompileTestAvg>> {
+            let mut state = state.unwrap_or_else(|| Pox::new(CompileTestAvg { sum: 0.0, count: 0 }));
+            state.sum += val;
+            state.count += 1;
+            Some(state)
+        }
+
+        #[crate::sql(finalfunc = "compile_test_avg", parallel_safe)]
+        pub fn compile_test_avg_final(state: Option<Pox<CompileTestAvg>>) -> Option<f64> {
+            state.map(|s| s.sum / s.count as f64)
+        }
+
+        #[crate::sql(combinefunc = "compile_test_avg", parallel_safe)]
+        pub fn compile_test_avg_combine(
+            a: Option<Pox<CompileTestAvg>>,
+            b: Option<Pox<CompileTestAvg>>,
+        ) -> Option<Pox<CompileTestAvg>> {
+            match (a, b) {
+                (Some(mut a), Some(b)) => {
+                    a.sum += b.sum;
+                    a.count += b.count;
+                    Some(a)
+                },
+                (Some(a), None) => Some(a),
+                (None, b) => b.map(|b| Pox::new((*b).clone())),
+            }
+        }
+
+        #[crate::sql(serialfunc = "compile_test_avg", parallel_safe)]
+        pub fn compile_test_avg_serialize(state: Pox<CompileTestAvg>) -> Vec<u8>;
+
+        #[crate::sql(deserialfunc = "compile_test_avg", parallel_safe)]
+        pub fn compile_test_avg_deserialize(bytes: &[u8]) -> Pox<CompileTestAvg>;
+    }
 }
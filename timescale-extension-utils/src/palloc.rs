@@ -1,8 +1,10 @@
 
 use std::{
     alloc::{GlobalAlloc, Layout},
-    mem::replace,
-    ptr::NonNull,
+    ffi::CStr,
+    mem::{forget, replace, size_of},
+    os::raw::c_char,
+    ptr::{self, NonNull},
     marker::PhantomData,
 };
 
@@ -10,15 +12,57 @@ pub use crate::pg_sys::CurrentMemoryContext;
 
 use crate::pg_sys::{
     MemoryContext,
-    MemoryContextAlloc,
-    MemoryContextAllocZero,
-    pfree,
-    repalloc
+    MemoryContextCounters,
+    Size,
 };
 
 extern "C" {
     pub static mut TopMemoryContext: MemoryContext;
     pub static mut TopTransactionContext: MemoryContext;
+
+    // AllocSetContextCreate() is a macro around this, which was renamed in 12
+    #[cfg(pg_ge_12)]
+    #[link_name = "AllocSetContextCreateInternal"]
+    fn AllocSetContextCreateImpl(
+        parent: MemoryContext,
+        name: *const c_char,
+        minContextSize: Size,
+        initBlockSize: Size,
+        maxBlockSize: Size,
+    ) -> MemoryContext;
+    #[cfg(not(pg_ge_12))]
+    #[link_name = "AllocSetContextCreateExtended"]
+    fn AllocSetContextCreateImpl(
+        parent: MemoryContext,
+        name: *const c_char,
+        minContextSize: Size,
+        initBlockSize: Size,
+        maxBlockSize: Size,
+    ) -> MemoryContext;
+    fn SlabContextCreate(
+        parent: MemoryContext,
+        name: *const c_char,
+        blockSize: Size,
+        chunkSize: Size,
+    ) -> MemoryContext;
+    // Generation contexts grew adjustable block sizes in 15
+    #[cfg(not(pg_ge_15))]
+    fn GenerationContextCreate(
+        parent: MemoryContext,
+        name: *const c_char,
+        blockSize: Size,
+    ) -> MemoryContext;
+    #[cfg(pg_ge_15)]
+    fn GenerationContextCreate(
+        parent: MemoryContext,
+        name: *const c_char,
+        minContextSize: Size,
+        initBlockSize: Size,
+        maxBlockSize: Size,
+    ) -> MemoryContext;
+    fn MemoryContextDelete(context: MemoryContext);
+    fn MemoryContextReset(context: MemoryContext);
+    fn MemoryContextStats(context: MemoryContext);
 }
 
 /// `Pox` offers the same API as `Box`, except that it is not freed on `drop`,
@@ -80,9 +124,47 @@ where F: FnOnce() -> T {
     // we need a variable her so the guard lives to the end of this scope
     let old = replace(&mut CurrentMemoryContext, context);
     let _guard = MemoryContextGuard(old);
+    let _enclosing = Enclosing::push(old);
     f()
 }
 
+const MAX_ENCLOSING: usize = 64;
+
+/// The contexts the `in_context()` calls on the stack will switch back to,
+/// outermost first, so that deleting a context can make sure none of them is
+/// about to become the `CurrentMemoryContext` again. Only the outermost
+/// `MAX_ENCLOSING` are tracked, `ENCLOSING_DEPTH` counts all of them.
+static mut ENCLOSING: [MemoryContext; MAX_ENCLOSING] = [ptr::null_mut(); MAX_ENCLOSING];
+static mut ENCLOSING_DEPTH: usize = 0;
+
+/// an entry in `ENCLOSING`, removed when this is dropped
+struct Enclosing;
+
+impl Enclosing {
+    unsafe fn push(context: MemoryContext) -> Self {
+        if ENCLOSING_DEPTH < MAX_ENCLOSING {
+            ENCLOSING[ENCLOSING_DEPTH] = context;
+        }
+        ENCLOSING_DEPTH += 1;
+        Enclosing
+    }
+}
+
+impl Drop for Enclosing {
+    fn drop(&mut self) {
+        unsafe { ENCLOSING_DEPTH -= 1 }
+    }
+}
+
+/// will an `in_context()` on the stack switch back to `context` or one of its
+/// descendants
+unsafe fn encloses_within(context: MemoryContext) -> bool {
+    let depth = ENCLOSING_DEPTH.min(MAX_ENCLOSING);
+    // the enclosing contexts are only compared to the descendants, not
+    // dereferenced, in case one of them was already deleted by postgres
+    (0..depth).any(|i| contains(context, ENCLOSING[i]))
+}
+
 /// this struct will swap the current memory context to the one it contains
 /// when it is dropped. it is recommended that `in_context` is used intead of
 /// using this directly
@@ -103,6 +185,217 @@ pub unsafe fn memory_context_switch_to(context: MemoryContext) -> MemoryContext
     replace(&mut CurrentMemoryContext, context)
 }
 
+/// the block sizes used to create an AllocSet context, equivalent to
+/// postgres's `ALLOCSET_*_SIZES` macros
+#[derive(Clone, Copy, Debug)]
+pub struct AllocSetSizes {
+    pub min_context_size: usize,
+    pub init_block_size: usize,
+    pub max_block_size: usize,
+}
+
+impl AllocSetSizes {
+    pub const DEFAULT: Self = AllocSetSizes {
+        min_context_size: 0,
+        init_block_size: 8 * 1024,
+        max_block_size: 8 * 1024 * 1024,
+    };
+
+    pub const SMALL: Self = AllocSetSizes {
+        min_context_size: 0,
+        init_block_size: 1024,
+        max_block_size: 8 * 1024,
+    };
+
+    pub const START_SMALL: Self = AllocSetSizes {
+        min_context_size: 1024,
+        init_block_size: 1024,
+        max_block_size: 8 * 1024 * 1024,
+    };
+}
+
+/// block size for Slab and Generation contexts (`SLAB_DEFAULT_BLOCK_SIZE`)
+pub const DEFAULT_BLOCK_SIZE: usize = 8 * 1024;
+/// block size for Slab and Generation contexts (`SLAB_LARGE_BLOCK_SIZE`)
+pub const LARGE_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+
+/// An owned child MemoryContext. The context is deleted, along with everything
+/// allocated in it and all of its children, when this value is dropped, unless
+/// it was `leak`ed first, in which case it will be deleted with its parent.
+///
+/// Postgres only stores a pointer to the name of the context, which is why the
+/// name must be `'static`.
+///
+/// # Panics
+/// Deleting the context panics if an enclosing `in_context()` would switch
+/// back to it, or to one of its children, on exit.
+pub struct PgMemoryContext(NonNull<crate::pg_sys::MemoryContextData>);
+
+impl PgMemoryContext {
+    /// create a new AllocSet context using the default block sizes
+    ///
+    /// # Safety
+    /// `parent` must be a valid MemoryContext that outlives the new context
+    pub unsafe fn new(parent: MemoryContext, name: &'static CStr) -> Self {
+        Self::alloc_set(parent, name, AllocSetSizes::DEFAULT)
+    }
+
+    /// # Safety
+    /// `parent` must be a valid MemoryContext that outlives the new context
+    pub unsafe fn alloc_set(parent: MemoryContext, name: &'static CStr, sizes: AllocSetSizes)
+    -> Self {
+        Self::create(|| AllocSetContextCreateImpl(
+            parent,
+            name.as_ptr(),
+            sizes.min_context_size as _,
+            sizes.init_block_size as _,
+            sizes.max_block_size as _,
+        ))
+    }
+
+    /// create a Slab context, which can only allocate chunks of `chunk_size`
+    ///
+    /// # Safety
+    /// `parent` must be a valid MemoryContext that outlives the new context
+    pub unsafe fn slab(
+        parent: MemoryContext,
+        name: &'static CStr,
+        block_size: usize,
+        chunk_size: usize,
+    ) -> Self {
+        Self::create(|| SlabContextCreate(parent, name.as_ptr(), block_size as _, chunk_size as _))
+    }
+
+    /// create a Generation context, suited to allocations that are freed in
+    /// roughly the order they were allocated
+    ///
+    /// # Safety
+    /// `parent` must be a valid MemoryContext that outlives the new context
+    pub unsafe fn generation(parent: MemoryContext, name: &'static CStr, block_size: usize)
+    -> Self {
+        #[cfg(not(pg_ge_15))]
+        let create = || GenerationContextCreate(parent, name.as_ptr(), block_size as _);
+        // a fixed block size, as before 15
+        #[cfg(pg_ge_15)]
+        let create = || GenerationContextCreate(
+            parent,
+            name.as_ptr(),
+            0,
+            block_size as _,
+            block_size as _,
+        );
+        Self::create(create)
+    }
+
+    unsafe fn create<F: FnOnce() -> MemoryContext>(f: F) -> Self {
+        let context = crate::guard_pg(f);
+        PgMemoryContext(NonNull::new(context).expect("could not create memory context"))
+    }
+
+    pub fn as_ptr(&self) -> MemoryContext {
+        self.0.as_ptr()
+    }
+
+    /// run code with this as the `CurrentMemoryContext`, see `in_context`
+    pub fn run<T, F>(&self, f: F) -> T
+    where F: FnOnce() -> T {
+        unsafe { in_context(self.as_ptr(), f) }
+    }
+
+    /// free everything allocated in this context, and delete all child
+    /// contexts. Any values still pointing into the context become dangling.
+    pub fn reset(&mut self) {
+        unsafe { crate::guard_pg(|| MemoryContextReset(self.as_ptr())) }
+    }
+
+    /// delete the context; this is equivalent to dropping it
+    pub fn delete(self) {}
+
+    /// give ownership of the context to its parent, it will be deleted when
+    /// the parent is
+    pub fn leak(self) -> MemoryContext {
+        let context = self.as_ptr();
+        forget(self);
+        context
+    }
+
+    /// the total memory usage of this context and all of its children
+    pub fn stats(&self) -> MemoryContextCounters {
+        let mut totals = MemoryContextCounters {
+            nblocks: 0,
+            freechunks: 0,
+            totalspace: 0,
+            freespace: 0,
+        };
+        unsafe { collect_stats(self.as_ptr(), &mut totals) };
+        totals
+    }
+
+    /// write the usage of this context and all of its children to the server
+    /// log, using `MemoryContextStats`
+    pub fn log_stats(&self) {
+        unsafe { crate::guard_pg(|| MemoryContextStats(self.as_ptr())) }
+    }
+}
+
+unsafe fn collect_stats(context: MemoryContext, totals: &mut MemoryContextCounters) {
+    let methods = &*(*context).methods;
+    // the stats method grew a print_to_stderr argument in 14
+    if let Some(stats) = methods.stats {
+        #[cfg(not(pg_ge_14))]
+        stats(context, None, ptr::null_mut(), totals);
+        #[cfg(pg_ge_14)]
+        stats(context, None, ptr::null_mut(), totals, false);
+    }
+    let mut child = (*context).firstchild;
+    while !child.is_null() {
+        collect_stats(child, totals);
+        child = (*child).nextchild;
+    }
+}
+
+/// is `context` either `ancestor` or one of its descendants
+unsafe fn is_within(mut context: MemoryContext, ancestor: MemoryContext) -> bool {
+    while !context.is_null() {
+        if context == ancestor {
+            return true
+        }
+        context = (*context).parent;
+    }
+    false
+}
+
+/// is `descendant` either `context` or one of its descendants
+unsafe fn contains(context: MemoryContext, descendant: MemoryContext) -> bool {
+    if context == descendant {
+        return true
+    }
+    let mut child = (*context).firstchild;
+    while !child.is_null() {
+        if contains(child, descendant) {
+            return true
+        }
+        child = (*child).nextchild;
+    }
+    false
+}
+
+impl Drop for PgMemoryContext {
+    fn drop(&mut self) {
+        unsafe {
+            let context = self.as_ptr();
+            assert!(!encloses_within(context),
+                "deleting a memory context an enclosing in_context() will switch back to");
+            // never leave CurrentMemoryContext dangling, it may be this context
+            // or any of its descendants
+            if is_within(CurrentMemoryContext, context) {
+                CurrentMemoryContext = (*context).parent;
+            }
+            crate::guard_pg(|| MemoryContextDelete(context))
+        }
+    }
+}
+
 #[global_allocator]
 static mut GLOBAL: PallocAllocator = PallocAllocator;
 
@@ -119,21 +412,372 @@ struct PallocAllocator;
 /// MemoryContext we allocate in, there doesn't seem to be way to do so that is
 /// safe in the context of postgres exceptions and doesn't incur the cost of
 /// setjmp
+///
+/// palloc only guarantees `MAXIMUM_ALIGNOF` alignment, so for layouts with a
+/// larger alignment we over-allocate by `align` bytes, hand out the first
+/// suitably aligned address past the start of the chunk, and store the address
+/// of the chunk itself in the word directly before the one we hand out.
 unsafe impl GlobalAlloc for PallocAllocator {
     //FIXME allow for switching the memory context allocated in
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        MemoryContextAlloc(CurrentMemoryContext, layout.size() as _)  as *mut _
+        if !is_over_aligned(layout.align()) {
+            return backing::palloc(layout.size())
+        }
+
+        let chunk = backing::palloc(over_aligned_size(layout));
+        place_in_chunk(chunk, layout.align())
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        pfree(ptr as *mut _)
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !is_over_aligned(layout.align()) {
+            return backing::pfree(ptr)
+        }
+
+        backing::pfree(chunk_start(ptr))
     }
 
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
-        MemoryContextAllocZero(CurrentMemoryContext, layout.size() as _)  as *mut _
+        if !is_over_aligned(layout.align()) {
+            return backing::palloc0(layout.size())
+        }
+
+        let chunk = backing::palloc0(over_aligned_size(layout));
+        place_in_chunk(chunk, layout.align())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let align = layout.align();
+        if !is_over_aligned(align) {
+            return backing::repalloc(ptr, new_size)
+        }
+
+        // repalloc() only preserves MAXIMUM_ALIGNOF alignment, so the data may
+        // end up at a different offset from the start of the new chunk than
+        // the aligned address requires; if so we need to shift it into place
+        let old_chunk = chunk_start(ptr);
+        let old_offset = ptr as usize - old_chunk as usize;
+        let new_layout = Layout::from_size_align_unchecked(new_size, align);
+        let new_chunk = backing::repalloc(old_chunk, over_aligned_size(new_layout));
+        replace_in_chunk(new_chunk, old_offset, align, std::cmp::min(layout.size(), new_size))
+    }
+}
+
+/// where `PallocAllocator` gets its memory from
+#[cfg(not(test))]
+mod backing {
+    use crate::pg_sys::{self, CurrentMemoryContext};
+
+    pub unsafe fn palloc(size: usize) -> *mut u8 {
+        pg_sys::MemoryContextAlloc(CurrentMemoryContext, size as _) as *mut _
+    }
+
+    pub unsafe fn palloc0(size: usize) -> *mut u8 {
+        pg_sys::MemoryContextAllocZero(CurrentMemoryContext, size as _) as *mut _
+    }
+
+    pub unsafe fn pfree(ptr: *mut u8) {
+        pg_sys::pfree(ptr as *mut _)
+    }
+
+    pub unsafe fn repalloc(ptr: *mut u8, size: usize) -> *mut u8 {
+        pg_sys::repalloc(ptr as *mut _, size as _) as *mut _
+    }
+}
+
+/// Unit tests run outside of postgres, so there the memory comes from the
+/// system allocator instead. Like palloc() it only guarantees MAXALIGN, and
+/// each chunk is preceded by a header, which here holds the chunk's size.
+#[cfg(test)]
+mod backing {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::MAX_ALIGN;
+
+    const HEADER_SIZE: usize = MAX_ALIGN;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size + HEADER_SIZE, MAX_ALIGN).unwrap()
+    }
+
+    unsafe fn with_header(block: *mut u8, size: usize) -> *mut u8 {
+        assert!(!block.is_null(), "out of memory");
+        (block as *mut usize).write(size);
+        block.add(HEADER_SIZE)
+    }
+
+    unsafe fn header(ptr: *mut u8) -> (*mut u8, usize) {
+        let block = ptr.sub(HEADER_SIZE);
+        (block, (block as *mut usize).read())
+    }
+
+    pub unsafe fn palloc(size: usize) -> *mut u8 {
+        with_header(System.alloc(layout(size)), size)
+    }
+
+    pub unsafe fn palloc0(size: usize) -> *mut u8 {
+        with_header(System.alloc_zeroed(layout(size)), size)
+    }
+
+    pub unsafe fn pfree(ptr: *mut u8) {
+        let (block, size) = header(ptr);
+        System.dealloc(block, layout(size))
+    }
+
+    pub unsafe fn repalloc(ptr: *mut u8, size: usize) -> *mut u8 {
+        let (block, old_size) = header(ptr);
+        with_header(System.realloc(block, layout(old_size), size + HEADER_SIZE), size)
     }
+}
+
+const MAX_ALIGN: usize = crate::pg_sys::MAXIMUM_ALIGNOF as usize;
+
+// we need at least one word in front of the allocation to store the chunk
+// pointer, which is only guaranteed to exist if the requested alignment is
+// larger than what palloc already provides
+const _: [(); 0 - !{ const ASSERT: bool = MAX_ALIGN >= size_of::<usize>(); ASSERT } as usize] = [];
+
+fn is_over_aligned(align: usize) -> bool {
+    align > MAX_ALIGN
+}
+
+fn over_aligned_size(layout: Layout) -> usize {
+    layout.size() + layout.align()
+}
+
+/// the first `align`-aligned address strictly after `chunk`; since `chunk` is
+/// MAXALIGNed and `align` is larger than MAXALIGN this leaves at least one
+/// word in front of the returned address, and at least `size` bytes after it
+fn aligned_in_chunk(chunk: *mut u8, align: usize) -> *mut u8 {
+    let addr = chunk as usize;
+    let aligned = (addr + align) & !(align - 1);
+    chunk.wrapping_add(aligned - addr)
+}
+
+unsafe fn place_in_chunk(chunk: *mut u8, align: usize) -> *mut u8 {
+    let ptr = aligned_in_chunk(chunk, align);
+    set_chunk_start(ptr, chunk);
+    ptr
+}
 
-    unsafe fn realloc(&self, ptr: *mut u8, _layout: Layout, new_size: usize) -> *mut u8 {
-        repalloc(ptr as *mut _, new_size as _) as *mut _
+/// place the `len` bytes that were `old_offset` bytes into a chunk which has
+/// since been moved to `chunk` by `repalloc()`
+unsafe fn replace_in_chunk(chunk: *mut u8, old_offset: usize, align: usize, len: usize)
+-> *mut u8 {
+    let ptr = aligned_in_chunk(chunk, align);
+    if ptr as usize - chunk as usize != old_offset {
+        ptr::copy(chunk.add(old_offset), ptr, len);
+    }
+    set_chunk_start(ptr, chunk);
+    ptr
+}
+
+unsafe fn set_chunk_start(ptr: *mut u8, chunk: *mut u8) {
+    (ptr as *mut *mut u8).sub(1).write(chunk)
+}
+
+unsafe fn chunk_start(ptr: *mut u8) -> *mut u8 {
+    (ptr as *mut *mut u8).sub(1).read()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::System;
+
+    /// a MAXALIGNed block of `size` bytes starting `offset` bytes past an
+    /// address aligned to 4096, like palloc() could return
+    struct Chunk {
+        block: *mut u8,
+        offset: usize,
+    }
+
+    impl Chunk {
+        fn new(size: usize, offset: usize) -> Self {
+            assert_eq!(offset % MAX_ALIGN, 0);
+            let block = unsafe { System.alloc(Self::layout(size, offset)) };
+            assert!(!block.is_null());
+            Chunk { block, offset }
+        }
+
+        fn layout(size: usize, offset: usize) -> Layout {
+            Layout::from_size_align(size + offset, 4096).unwrap()
+        }
+
+        fn start(&self) -> *mut u8 {
+            self.block.wrapping_add(self.offset)
+        }
+
+        fn free(self, size: usize) {
+            unsafe { System.dealloc(self.block, Self::layout(size, self.offset)) }
+        }
+    }
+
+    #[test]
+    fn aligned_in_chunk_leaves_room_for_header() {
+        for align in [16, 32, 64, 4096].iter().copied() {
+            for offset in (0..align * 2).step_by(MAX_ALIGN) {
+                let chunk = (align * 4 + offset) as *mut u8;
+                let ptr = aligned_in_chunk(chunk, align);
+                assert_eq!(ptr as usize % align, 0);
+                assert!(ptr as usize - chunk as usize >= size_of::<usize>());
+                assert!(ptr as usize - chunk as usize <= align);
+            }
+        }
+    }
+
+    #[test]
+    fn place_in_chunk_fits() {
+        for &(size, align) in [(64, 64), (32, 32), (1, 16), (100, 4096)].iter() {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            assert!(is_over_aligned(align));
+            for offset in (0..align * 2).step_by(MAX_ALIGN) {
+                let chunk = Chunk::new(over_aligned_size(layout), offset);
+                unsafe {
+                    let ptr = place_in_chunk(chunk.start(), align);
+                    assert_eq!(ptr as usize % align, 0);
+                    assert_eq!(chunk_start(ptr), chunk.start());
+                    let end = chunk.start() as usize + over_aligned_size(layout);
+                    assert!(ptr as usize + size <= end);
+                    ptr::write_bytes(ptr, 0xab, size);
+                    assert_eq!(chunk_start(ptr), chunk.start());
+                }
+                chunk.free(over_aligned_size(layout));
+            }
+        }
+    }
+
+    #[test]
+    fn replace_in_chunk_after_move() {
+        let align = 64;
+        let len = 100;
+        let layout = Layout::from_size_align(len, align).unwrap();
+        let size = over_aligned_size(layout);
+        for old in (0..align).step_by(MAX_ALIGN) {
+            for new in (0..align).step_by(MAX_ALIGN) {
+                let old_chunk = Chunk::new(size, old);
+                let new_chunk = Chunk::new(size, new);
+                unsafe {
+                    let old_ptr = place_in_chunk(old_chunk.start(), align);
+                    for i in 0..len {
+                        *old_ptr.add(i) = i as u8;
+                    }
+                    let old_offset = old_ptr as usize - old_chunk.start() as usize;
+                    // repalloc() copies the chunk as is
+                    ptr::copy_nonoverlapping(old_chunk.start(), new_chunk.start(), size);
+                    let new_ptr = replace_in_chunk(new_chunk.start(), old_offset, align, len);
+                    assert_eq!(new_ptr as usize % align, 0);
+                    assert_eq!(chunk_start(new_ptr), new_chunk.start());
+                    for i in 0..len {
+                        assert_eq!(*new_ptr.add(i), i as u8);
+                    }
+                }
+                old_chunk.free(size);
+                new_chunk.free(size);
+            }
+        }
+    }
+
+    #[repr(align(64))]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Aligned64([u64; 3]);
+
+    fn is_aligned<T>(ptr: *const T) -> bool {
+        (ptr as usize).is_multiple_of(std::mem::align_of::<T>())
+    }
+
+    // the global allocator is a PallocAllocator, so these go through it
+    #[test]
+    fn over_aligned_box() {
+        let boxes: Vec<Box<Aligned64>> = (0..100).map(|i| Box::new(Aligned64([i; 3]))).collect();
+        for (i, b) in boxes.iter().enumerate() {
+            assert!(is_aligned(&**b));
+            assert_eq!(**b, Aligned64([i as u64; 3]));
+        }
+    }
+
+    #[test]
+    fn over_aligned_vec_grows_and_shrinks() {
+        let mut vec: Vec<Aligned64> = Vec::new();
+        for i in 0..1000 {
+            // grows through realloc()
+            vec.push(Aligned64([i, i + 1, i + 2]));
+            assert!(is_aligned(vec.as_ptr()));
+            let mid = i / 2;
+            assert_eq!(vec[mid as usize], Aligned64([mid, mid + 1, mid + 2]));
+        }
+        vec.truncate(10);
+        vec.shrink_to_fit();
+        assert!(is_aligned(vec.as_ptr()));
+        for (i, value) in vec.iter().enumerate() {
+            let i = i as u64;
+            assert_eq!(*value, Aligned64([i, i + 1, i + 2]));
+        }
+    }
+
+    #[test]
+    fn over_aligned_zeroed() {
+        let layout = Layout::new::<Aligned64>();
+        unsafe {
+            let ptr = std::alloc::alloc_zeroed(layout) as *mut Aligned64;
+            assert!(is_aligned(ptr));
+            assert_eq!(*ptr, Aligned64([0; 3]));
+            std::alloc::dealloc(ptr as *mut u8, layout);
+        }
+    }
+
+    #[test]
+    fn enclosing_contexts() {
+        unsafe {
+            // parent -> (first -> grandchild), second
+            let mut contexts = [std::mem::zeroed::<crate::pg_sys::MemoryContextData>(); 4];
+            let [parent, first, second, grandchild] =
+                [0, 1, 2, 3].map(|i| &mut contexts[i] as MemoryContext);
+            (*parent).firstchild = first;
+            (*first).nextchild = second;
+            (*first).firstchild = grandchild;
+            for (child, of) in [(first, parent), (second, parent), (grandchild, first)] {
+                (*child).parent = of;
+            }
+
+            assert!(contains(parent, grandchild));
+            assert!(contains(first, first));
+            assert!(!contains(second, grandchild));
+            assert!(!contains(first, parent));
+
+            assert!(!encloses_within(parent));
+            {
+                let _outer = Enclosing::push(second);
+                let _inner = Enclosing::push(grandchild);
+                assert!(encloses_within(parent));
+                assert!(encloses_within(first));
+                assert!(encloses_within(second));
+            }
+            assert!(!encloses_within(parent));
+        }
+    }
+
+    // needs a running backend, so it is only compiled
+    #[allow(dead_code)]
+    fn compile_test_owned_context() {
+        let name = CStr::from_bytes_with_nul(b"test context\0").unwrap();
+        let mut context = unsafe { PgMemoryContext::new(TopMemoryContext, name) };
+        let (sum, child) = context.run(|| {
+            let vec: Vec<u64> = (0..1000).collect();
+            let child = unsafe {
+                PgMemoryContext::alloc_set(CurrentMemoryContext, name, AllocSetSizes::SMALL)
+            };
+            (vec.iter().sum::<u64>(), child.leak())
+        });
+        assert_eq!(sum, 499500);
+        let _ = child;
+        let _ = context.stats();
+        context.reset();
+        unsafe {
+            PgMemoryContext::slab(context.as_ptr(), name, DEFAULT_BLOCK_SIZE, 64).delete();
+            let generation = PgMemoryContext::generation(context.as_ptr(), name, LARGE_BLOCK_SIZE);
+            generation.run(|| context.log_stats());
+        }
+        context.delete();
     }
 }
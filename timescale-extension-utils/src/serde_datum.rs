@@ -0,0 +1,115 @@
+//! storing any serde type as a datum, see [`SerdeDatum`]; requires the
+//! `serde_datum` feature
+//!
+//! Values are stored as a varlena containing their `bincode` encoding, so
+//! they can be stored in `bytea` columns as is. They can also be used as a
+//! custom base type, whose text representation is JSON:
+//! ```ignore
+//! pg_type!{
+//!     #[sql(name = "summary")]
+//!     SerdeDatum<Summary> {
+//!         input = summary_in,
+//!         output = summary_out,
+//!         receive = summary_recv,
+//!         send = summary_send,
+//!     }
+//! }
+//! ```
+//! Neither encoding is versioned, so changing the type invalidates stored
+//! values; see the `flat` module for a stable layout.
+
+use std::{
+    ffi::{CStr, CString},
+    ops::{Deref, DerefMut},
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    base_type::PgType,
+    datum::{FromDatum, ToDatum, TypeOid},
+    elog::{Level::Error, SqlState},
+    pg_sys::Datum,
+};
+
+/// A value stored using its `Serialize` and `Deserialize` impls.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SerdeDatum<T>(pub T);
+
+impl<T> SerdeDatum<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for SerdeDatum<T> {
+    fn from(value: T) -> Self {
+        SerdeDatum(value)
+    }
+}
+
+impl<T> Deref for SerdeDatum<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for SerdeDatum<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+// varint integers, and trailing bytes are rejected
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+impl<T: Serialize + DeserializeOwned> PgType for SerdeDatum<T> {
+    fn input(input: &CStr) -> Result<Self, String> {
+        serde_json::from_slice(input.to_bytes())
+            .map(SerdeDatum)
+            .map_err(|e| e.to_string())
+    }
+
+    fn output(&self) -> CString {
+        let json = serde_json::to_string(&self.0).unwrap_or_else(|e| {
+            crate::ereport!(Error, SqlState::DataException,
+                "cannot convert {} to JSON: {}", std::any::type_name::<T>(), e);
+            unreachable!()
+        });
+        // JSON escapes any NULs within strings
+        CString::new(json).expect("NUL in JSON")
+    }
+
+    fn send(&self) -> Vec<u8> {
+        bincode_options().serialize(&self.0).unwrap_or_else(|e| {
+            crate::ereport!(Error, SqlState::DataException,
+                "cannot serialize {}: {}", std::any::type_name::<T>(), e);
+            unreachable!()
+        })
+    }
+
+    fn receive(bytes: &[u8]) -> Result<Self, String> {
+        bincode_options().deserialize(bytes)
+            .map(SerdeDatum)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> FromDatum<'_> for SerdeDatum<T> {
+    unsafe fn from_datum(datum: Datum) -> Self {
+        crate::base_type::from_datum(datum)
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> ToDatum for SerdeDatum<T> {
+    fn to_datum(self) -> Datum {
+        crate::base_type::to_datum(&self)
+    }
+}
+
+// stored either as bytea or as a custom type
+impl<T> TypeOid for SerdeDatum<T> {}
@@ -0,0 +1,553 @@
+//! running SQL from Rust through the Server Programming Interface, see
+//! `executor/spi.h`
+//!
+//! An [`Spi`] session is connected on creation and finished on drop. When it
+//! is dropped while unwinding, it is left to the abort of the transaction the
+//! panic ends in, which finishes it along with the rest of SPI. Values read
+//! from a query result are not copied out of it, so borrowed ones, such as
+//! `&str`, are only valid as long as the [`SpiTupleTable`] is; read owned
+//! ones, such as `String`, to keep them.
+//! ```compile_fail,E0597
+//! # use timescale_extension_utils::spi::Spi;
+//! let spi = Spi::connect();
+//! let name: &str = {
+//!     let table = spi.select("SELECT relname::text FROM pg_class", &[]);
+//!     let row = table.first().unwrap();
+//!     row.get(0)
+//! };
+//! ```
+//!
+//! Queries that are run repeatedly can be prepared once as an [`SpiPlan`],
+//! which can be kept across calls, e.g. in a [`PlanCell`], and large results
+//! can be read in batches through an [`SpiCursor`].
+//! ```ignore
+//! let spi = Spi::connect();
+//! let count: Option<i64> = spi.get_one(
+//!     "SELECT count(*) FROM pg_class WHERE relnamespace = $1::regnamespace::oid",
+//!     &[SpiArg::new("public")],
+//! );
+//! ```
+
+use std::{
+    cell::UnsafeCell,
+    ffi::{CStr, CString},
+    marker::PhantomData,
+    os::raw::{c_char, c_int, c_long},
+    ptr::{self, addr_of_mut},
+};
+
+use crate::{
+    composite::RowType,
+    datum::{format_type, FromOptionalDatum, ToOptionalDatum, TypeOid},
+    elog::{Level::Error, SqlState},
+    guard_pg,
+    palloc::in_context,
+    pg_sys::{self, Datum, MemoryContext, Oid},
+};
+
+/// A connection to SPI, which is finished when dropped.
+pub struct Spi {
+    /// the context to copy results into; SPI leaves its own procedure context
+    /// current after every call, which is freed by `SPI_finish()`
+    outer: MemoryContext,
+    // SPI connections are a stack, and must be finished in the order they
+    // were connected, so they must not leave the function that created them
+    _not_send: PhantomData<*mut ()>,
+}
+
+/// A parameter of a query, `$1`, `$2`, etc.
+pub struct SpiArg {
+    type_id: Oid,
+    value: Option<Datum>,
+}
+
+impl SpiArg {
+    /// a parameter of the SQL type of `value`
+    pub fn new<T: ToOptionalDatum + TypeOid>(value: T) -> Self {
+        match T::type_oid() {
+            Some(type_id) => SpiArg::with_type(type_id, value),
+            None => {
+                crate::ereport!(Error, SqlState::IndeterminateDatatype,
+                    "SPI parameter has no SQL type, use SpiArg::with_type()");
+                unreachable!()
+            },
+        }
+    }
+
+    /// a parameter of the SQL type `type_id`, which `value` must be a valid
+    /// datum of; composites are built as rows of that type
+    pub fn with_type<T: ToOptionalDatum>(type_id: Oid, value: T) -> Self {
+        let value = value.to_optional_datum_as(&mut RowType::of_type(type_id, -1));
+        SpiArg { type_id, value }
+    }
+}
+
+impl Spi {
+    /// connect to SPI
+    pub fn connect() -> Self {
+        unsafe {
+            let outer = pg_sys::CurrentMemoryContext;
+            let code = in_context(outer, || guard_pg(|| pg_sys::SPI_connect()));
+            check_result("SPI_connect", code);
+            Spi { outer, _not_send: PhantomData }
+        }
+    }
+
+    /// Run `query`, returning all the rows it produces.
+    pub fn execute(&self, query: &str, args: &[SpiArg]) -> SpiTupleTable<'_> {
+        self.run(query, args, false, 0)
+    }
+
+    /// Run the read-only `query`, returning all the rows it produces.
+    pub fn select(&self, query: &str, args: &[SpiArg]) -> SpiTupleTable<'_> {
+        self.run(query, args, true, 0)
+    }
+
+    /// The first column of the first row returned by the read-only `query`,
+    /// or `None` if there are no rows.
+    pub fn get_one<T>(&self, query: &str, args: &[SpiArg]) -> Option<T>
+    where T: for<'t> FromOptionalDatum<'t> + TypeOid {
+        let table = self.run(query, args, true, 1);
+        if table.is_empty() { None } else { Some(table.get(0, 0)) }
+    }
+
+    fn run(&self, query: &str, args: &[SpiArg], read_only: bool, limit: c_long) -> SpiTupleTable<'_> {
+        let query = query_string(query);
+        let (mut types, mut values, nulls) = parameters(args);
+        unsafe {
+            let code = self.call(|| pg_sys::SPI_execute_with_args(
+                query.as_ptr(),
+                args.len() as c_int,
+                types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                read_only,
+                limit,
+            ));
+            check_result("SPI_execute_with_args", code);
+            self.take_result()
+        }
+    }
+
+    /// Prepare `query`, whose parameters have the types `types`. The plan is
+    /// freed with the session unless it is `keep()`-ed.
+    pub fn prepare(&self, query: &str, types: &[Oid]) -> SpiPlan<'_> {
+        let query = query_string(query);
+        let mut types = types.to_vec();
+        unsafe {
+            let plan = self.call(|| pg_sys::SPI_prepare(
+                query.as_ptr(),
+                types.len() as c_int,
+                types.as_mut_ptr(),
+            ));
+            if plan.is_null() {
+                check_result("SPI_prepare", pg_sys::SPI_result)
+            }
+            SpiPlan { plan, _spi: PhantomData }
+        }
+    }
+
+    /// Run the prepared `plan`, returning all the rows it produces.
+    pub fn execute_plan(&self, plan: &SpiPlan, args: &[SpiArg]) -> SpiTupleTable<'_> {
+        self.run_plan(plan, args, false, 0)
+    }
+
+    /// Run the prepared read-only `plan`, returning all the rows it produces.
+    pub fn select_plan(&self, plan: &SpiPlan, args: &[SpiArg]) -> SpiTupleTable<'_> {
+        self.run_plan(plan, args, true, 0)
+    }
+
+    fn run_plan(&self, plan: &SpiPlan, args: &[SpiArg], read_only: bool, limit: c_long)
+    -> SpiTupleTable<'_> {
+        plan.check_args(args);
+        let (_, mut values, nulls) = parameters(args);
+        unsafe {
+            let code = self.call(|| pg_sys::SPI_execute_plan(
+                plan.plan,
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                read_only,
+                limit,
+            ));
+            check_result("SPI_execute_plan", code);
+            self.take_result()
+        }
+    }
+
+    /// Open a cursor over the rows of the read-only `query`, fetching
+    /// `batch_size` rows at a time.
+    pub fn open_cursor(&self, query: &str, args: &[SpiArg], batch_size: usize) -> SpiCursor<'_> {
+        let query = query_string(query);
+        let (mut types, mut values, nulls) = parameters(args);
+        let portal = unsafe {
+            self.call(|| pg_sys::SPI_cursor_open_with_args(
+                ptr::null(),
+                query.as_ptr(),
+                args.len() as c_int,
+                types.as_mut_ptr(),
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                true,
+                0,
+            ))
+        };
+        self.cursor(portal, batch_size)
+    }
+
+    /// Open a cursor over the rows of the read-only prepared `plan`, fetching
+    /// `batch_size` rows at a time.
+    pub fn open_plan_cursor(&self, plan: &SpiPlan, args: &[SpiArg], batch_size: usize)
+    -> SpiCursor<'_> {
+        plan.check_args(args);
+        let (_, mut values, nulls) = parameters(args);
+        let portal = unsafe {
+            self.call(|| pg_sys::SPI_cursor_open(
+                ptr::null(),
+                plan.plan,
+                values.as_mut_ptr(),
+                nulls.as_ptr(),
+                true,
+            ))
+        };
+        self.cursor(portal, batch_size)
+    }
+
+    fn cursor(&self, portal: pg_sys::Portal, batch_size: usize) -> SpiCursor<'_> {
+        if portal.is_null() {
+            check_result("SPI_cursor_open", unsafe { pg_sys::SPI_result })
+        }
+        SpiCursor { spi: self, portal, batch_size: batch_size.max(1) }
+    }
+
+    /// run the SPI function `f`, leaving the outer context current afterwards
+    pub(crate) unsafe fn call<T>(&self, f: impl FnOnce() -> T) -> T {
+        in_context(self.outer, || guard_pg(f))
+    }
+
+    /// run the SPI cleanup function `f` for a `drop()`, unless unwinding: an
+    /// ERROR would then abort, and the transaction abort the panic ends in
+    /// cleans up after SPI anyway
+    unsafe fn cleanup(&self, f: impl FnOnce()) {
+        if !std::thread::panicking() {
+            self.call(f)
+        }
+    }
+
+    /// take ownership of the result of the last SPI call
+    pub(crate) unsafe fn take_result(&self) -> SpiTupleTable<'_> {
+        let table = ptr::replace(addr_of_mut!(pg_sys::SPI_tuptable), ptr::null_mut());
+        let processed = pg_sys::SPI_processed;
+        SpiTupleTable {
+            spi: self,
+            table,
+            processed,
+            len: if table.is_null() { 0 } else { processed as usize },
+        }
+    }
+}
+
+impl Drop for Spi {
+    fn drop(&mut self) {
+        unsafe {
+            self.cleanup(|| {
+                pg_sys::SPI_finish();
+            })
+        }
+    }
+}
+
+fn query_string(query: &str) -> CString {
+    CString::new(query).unwrap_or_else(|_| {
+        crate::ereport!(Error, SqlState::SyntaxError, "SPI query contains a NUL byte");
+        unreachable!()
+    })
+}
+
+/// the argument types, values and nulls `SPI_execute_with_args()` and friends
+/// expect
+pub(crate) fn parameters(args: &[SpiArg]) -> (Vec<Oid>, Vec<Datum>, Vec<c_char>) {
+    let types = args.iter().map(|arg| arg.type_id).collect();
+    let values = args.iter().map(|arg| arg.value.unwrap_or(0)).collect();
+    let nulls = args.iter()
+        .map(|arg| if arg.value.is_some() { b' ' } else { b'n' } as c_char)
+        .collect();
+    (types, values, nulls)
+}
+
+/// ERROR if `code` is an SPI error code
+pub(crate) fn check_result(function: &str, code: c_int) {
+    if code < 0 {
+        let message = unsafe { CStr::from_ptr(pg_sys::SPI_result_code_string(code)) };
+        crate::ereport!(Error, SqlState::InternalError,
+            "{} failed: {}", function, message.to_string_lossy());
+    }
+}
+
+/// The rows returned by a query. They are freed when the table is dropped,
+/// which the values borrowed from them cannot outlive; for the batches of an
+/// [`SpiCursor`] read in a `for` loop, that is at the end of each iteration.
+pub struct SpiTupleTable<'a> {
+    spi: &'a Spi,
+    table: *mut pg_sys::SPITupleTable,
+    processed: u64,
+    len: usize,
+}
+
+impl<'a> SpiTupleTable<'a> {
+    /// the number of rows processed, which for `INSERT`, `UPDATE` and `DELETE`
+    /// statements without `RETURNING` is the number of rows affected
+    pub fn processed(&self) -> u64 {
+        self.processed
+    }
+
+    /// the number of rows
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// the number of columns, `0` for statements that return no rows
+    pub fn columns(&self) -> usize {
+        if self.table.is_null() {
+            return 0
+        }
+        unsafe { (*(*self.table).tupdesc).natts as usize }
+    }
+
+    /// the name of the column `column`, counting from 0
+    pub fn column_name(&self, column: usize) -> String {
+        let att = self.attribute(column);
+        unsafe { CStr::from_ptr(att.attname.data.as_ptr()).to_string_lossy().into_owned() }
+    }
+
+    pub fn row(&self, row: usize) -> Option<SpiRow<'_>> {
+        if row < self.len {
+            Some(SpiRow { table: self, row })
+        } else {
+            None
+        }
+    }
+
+    pub fn first(&self) -> Option<SpiRow<'_>> {
+        self.row(0)
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = SpiRow<'_>> {
+        (0..self.len).map(move |row| SpiRow { table: self, row })
+    }
+
+    fn attribute(&self, column: usize) -> &pg_sys::FormData_pg_attribute {
+        if column >= self.columns() {
+            crate::ereport!(Error, SqlState::InvalidColumnReference,
+                "SPI result has {} columns, column {} requested", self.columns(), column);
+        }
+        unsafe {
+            let desc = (*self.table).tupdesc;
+            &(*desc).attrs.as_slice((*desc).natts as usize)[column]
+        }
+    }
+
+    /// the value of `column` in `row`, borrowed from the table
+    fn get<'t, T: FromOptionalDatum<'t> + TypeOid>(&'t self, row: usize, column: usize) -> T {
+        let att = self.attribute(column);
+        match T::type_oid() {
+            Some(type_id) if type_id != att.atttypid => {
+                let (expected, found) = (format_type(att.atttypid), format_type(type_id));
+                crate::ereport!(Error, SqlState::DatatypeMismatch,
+                    "SPI column {} has type {} but was read as type {}", column, expected, found);
+            },
+            _ => {},
+        }
+        let datum = unsafe {
+            let mut is_null = false;
+            let tuple = *(*self.table).vals.add(row);
+            let datum = self.spi.call(|| pg_sys::SPI_getbinval(
+                tuple,
+                (*self.table).tupdesc,
+                column as c_int + 1,
+                &mut is_null,
+            ));
+            if is_null { None } else { Some(datum) }
+        };
+        unsafe { T::try_from_optional_datum(datum) }.unwrap_or_else(|| {
+            crate::ereport!(Error, SqlState::NullValueNotAllowed,
+                "NULL value in SPI column {} read as non-nullable type", column);
+            unreachable!()
+        })
+    }
+}
+
+impl<'a> Drop for SpiTupleTable<'a> {
+    fn drop(&mut self) {
+        if !self.table.is_null() {
+            unsafe { self.spi.cleanup(|| pg_sys::SPI_freetuptable(self.table)) }
+        }
+    }
+}
+
+/// A row of an [`SpiTupleTable`].
+pub struct SpiRow<'a> {
+    table: &'a SpiTupleTable<'a>,
+    row: usize,
+}
+
+impl<'a> SpiRow<'a> {
+    /// the value of the column `column`, counting from 0
+    pub fn get<T: FromOptionalDatum<'a> + TypeOid>(&self, column: usize) -> T {
+        self.table.get(self.row, column)
+    }
+
+    /// the value of the column named `name`
+    pub fn get_by_name<T: FromOptionalDatum<'a> + TypeOid>(&self, name: &str) -> T {
+        let column = (0..self.table.columns())
+            .find(|&column| self.table.column_name(column) == name)
+            .unwrap_or_else(|| {
+                crate::ereport!(Error, SqlState::UndefinedColumn,
+                    "SPI result has no column \"{}\"", name);
+                unreachable!()
+            });
+        self.get(column)
+    }
+}
+
+/// A prepared statement. Plans are only valid for the [`Spi`] session that
+/// prepared them unless they are kept with [`SpiPlan::keep`], after which
+/// they can be used from any session until dropped.
+pub struct SpiPlan<'a> {
+    plan: pg_sys::SPIPlanPtr,
+    _spi: PhantomData<&'a Spi>,
+}
+
+impl<'a> SpiPlan<'a> {
+    /// Keep the plan beyond the end of the session, for reuse across calls.
+    pub fn keep(self) -> SpiPlan<'static> {
+        let plan = self.plan;
+        std::mem::forget(self);
+        unsafe {
+            check_result("SPI_keepplan", guard_pg(|| pg_sys::SPI_keepplan(plan)));
+        }
+        SpiPlan { plan, _spi: PhantomData }
+    }
+
+    pub fn as_ptr(&self) -> pg_sys::SPIPlanPtr {
+        self.plan
+    }
+
+    /// the number of parameters of the plan
+    pub fn arg_count(&self) -> usize {
+        unsafe { guard_pg(|| pg_sys::SPI_getargcount(self.plan)) as usize }
+    }
+
+    /// the type of the parameter `arg`, counting from 0
+    pub fn arg_type(&self, arg: usize) -> Oid {
+        unsafe { guard_pg(|| pg_sys::SPI_getargtypeid(self.plan, arg as c_int)) }
+    }
+
+    /// check that `args` match the parameters of the plan
+    fn check_args(&self, args: &[SpiArg]) {
+        if args.len() != self.arg_count() {
+            crate::ereport!(Error, SqlState::UndefinedParameter,
+                "SPI plan has {} parameters but {} were given", self.arg_count(), args.len());
+        }
+        for (i, arg) in args.iter().enumerate() {
+            let type_id = self.arg_type(i);
+            if arg.type_id != type_id {
+                let (expected, found) = (format_type(type_id), format_type(arg.type_id));
+                crate::ereport!(Error, SqlState::DatatypeMismatch,
+                    "SPI plan parameter ${} has type {} but was given type {}", i + 1, expected, found);
+            }
+        }
+    }
+}
+
+impl<'a> Drop for SpiPlan<'a> {
+    fn drop(&mut self) {
+        // see Spi::cleanup()
+        if !std::thread::panicking() {
+            unsafe { guard_pg(|| pg_sys::SPI_freeplan(self.plan)); }
+        }
+    }
+}
+
+/// A kept plan, prepared on first use, for storing in a `static`.
+/// ```ignore
+/// static CHUNKS: PlanCell = PlanCell::new();
+///
+/// let spi = Spi::connect();
+/// let plan = CHUNKS.get_or_prepare(&spi, "SELECT ... WHERE id = $1", &[pg_sys::INT4OID]);
+/// let rows = spi.select_plan(plan, &[SpiArg::new(id)]);
+/// ```
+pub struct PlanCell(UnsafeCell<Option<SpiPlan<'static>>>);
+
+// postgres backends are single threaded
+unsafe impl Sync for PlanCell {}
+
+impl PlanCell {
+    pub const fn new() -> Self {
+        PlanCell(UnsafeCell::new(None))
+    }
+
+    /// the plan in the cell, preparing and keeping `query` if it is empty
+    pub fn get_or_prepare(&self, spi: &Spi, query: &str, types: &[Oid]) -> &SpiPlan<'static> {
+        unsafe {
+            let plan = &mut *self.0.get();
+            if plan.is_none() {
+                *plan = Some(spi.prepare(query, types).keep());
+            }
+            plan.as_ref().unwrap()
+        }
+    }
+}
+
+impl Default for PlanCell {
+    fn default() -> Self {
+        PlanCell::new()
+    }
+}
+
+/// A cursor over the rows of a query, which is an iterator over batches of
+/// rows; each batch is fetched as it is needed.
+pub struct SpiCursor<'a> {
+    spi: &'a Spi,
+    portal: pg_sys::Portal,
+    batch_size: usize,
+}
+
+impl<'a> SpiCursor<'a> {
+    /// fetch up to the next `count` rows
+    pub fn fetch(&mut self, count: usize) -> SpiTupleTable<'a> {
+        unsafe {
+            self.spi.call(|| pg_sys::SPI_cursor_fetch(self.portal, true, count as c_long));
+            self.spi.take_result()
+        }
+    }
+
+    /// skip the next `count` rows
+    pub fn move_forward(&mut self, count: usize) {
+        unsafe {
+            self.spi.call(|| pg_sys::SPI_cursor_move(self.portal, true, count as c_long))
+        }
+    }
+}
+
+impl<'a> Iterator for SpiCursor<'a> {
+    type Item = SpiTupleTable<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = self.fetch(self.batch_size);
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
+impl<'a> Drop for SpiCursor<'a> {
+    fn drop(&mut self) {
+        unsafe { self.spi.cleanup(|| pg_sys::SPI_cursor_close(self.portal)) }
+    }
+}
@@ -0,0 +1,245 @@
+//! support for set-returning functions, see `funcapi.h`
+//!
+//! `pg_fn!` and `#[pg_extern]` functions returning
+//! `impl Iterator<Item = T>` or `impl IntoIterator<Item = T>` are driven
+//! through [`return_set`]. Where postgres allows it the iterator is run lazily,
+//! one value per call, with its state living in the `multi_call_memory_ctx`
+//! of the SRF's `FuncCallContext`. Otherwise the whole set is materialized into
+//! a tuplestore on the first call. Sets of `PgComposite` rows implement
+//! `RETURNS TABLE` functions.
+//!
+//! The iterator outlives the call that created it, so it must be `'static`:
+//! it may own values, including copies of its arguments, but not borrow its
+//! arguments or the `fcinfo` of the first call, which the later calls do not
+//! share:
+//! ```compile_fail,E0597
+//! timescale_extension_utils::pg_fn!{
+//!     pub fn words(a: &str) -> impl Iterator<Item = String> {
+//!         a.split_whitespace().map(String::from)
+//!     }
+//! }
+//! ```
+//! ```compile_fail,E0521
+//! timescale_extension_utils::pg_fn!{
+//!     pub fn flinfo(; fcinfo) -> impl Iterator<Item = i64> {
+//!         std::iter::once(fcinfo).map(|fcinfo| fcinfo.nargs as i64)
+//!     }
+//! }
+//! ```
+//!
+//! The iterator, and everything `next()` allocates, lives in the
+//! `multi_call_memory_ctx`, or in the context of the first call when the set
+//! is materialized. The datum each value is converted to lives in the
+//! per-call context of the call that returns it, or is copied into the
+//! tuplestore.
+
+use std::{
+    os::raw::{c_int, c_void},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use crate::{
+    composite::{self, RowType},
+    datum::ToOptionalDatum,
+    elog::{Level::Error, SqlState},
+    palloc::in_context,
+    pg_sys::{self, Datum, MemoryContext},
+    FcInfo,
+    FunctionCallInfoData,
+};
+
+/// `FuncCallContext` from `funcapi.h`, which the bindings do not include
+#[repr(C)]
+pub struct FuncCallContext {
+    pub call_cntr: u64,
+    pub max_calls: u64,
+    pub user_fctx: *mut c_void,
+    pub attinmeta: *mut c_void,
+    pub multi_call_memory_ctx: MemoryContext,
+    pub tuple_desc: pg_sys::TupleDesc,
+}
+
+extern "C" {
+    fn init_MultiFuncCall(fcinfo: pg_sys::FunctionCallInfo) -> *mut FuncCallContext;
+    fn per_MultiFuncCall(fcinfo: pg_sys::FunctionCallInfo) -> *mut FuncCallContext;
+    fn end_MultiFuncCall(fcinfo: pg_sys::FunctionCallInfo, funcctx: *mut FuncCallContext);
+    fn RegisterExprContextCallback(
+        econtext: *mut pg_sys::ExprContext,
+        function: pg_sys::ExprContextCallbackFunction,
+        arg: Datum,
+    );
+    fn UnregisterExprContextCallback(
+        econtext: *mut pg_sys::ExprContext,
+        function: pg_sys::ExprContextCallbackFunction,
+        arg: Datum,
+    );
+    pub(crate) static mut work_mem: c_int;
+}
+
+/// Return the values produced by `first_call` as a set. `first_call` is only
+/// run once per set, and runs in a memory context that lives until the set
+/// is done, so the iterator may keep pointers into memory allocated there.
+/// It must not borrow anything else, see the [module docs](index.html).
+/// Returns the datum for the current call, `None` being either `NULL` or the
+/// end of the set, which postgres distinguishes using `isDone`.
+///
+/// If the query stops reading from the set early the iterator is dropped when
+/// the executor shuts the function down; on an ERROR it is not dropped, its
+/// memory is simply freed along with the context.
+///
+/// # Safety
+/// `fcinfo` must be the `fcinfo` of the current function call
+pub unsafe fn return_set<T, I, F>(fcinfo: &mut FunctionCallInfoData, first_call: F) -> Option<Datum>
+where
+    T: ToOptionalDatum,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: 'static,
+    F: FnOnce(&mut FunctionCallInfoData) -> I,
+{
+    let rsinfo = fcinfo.resultinfo() as *mut pg_sys::ReturnSetInfo;
+    if rsinfo.is_null() || (*rsinfo).type_ != pg_sys::NodeTag_T_ReturnSetInfo {
+        set_not_allowed()
+    }
+    let allowed = (*rsinfo).allowedModes as pg_sys::SetFunctionReturnMode;
+    if allowed & pg_sys::SetFunctionReturnMode_SFRM_ValuePerCall != 0 {
+        value_per_call(fcinfo, &mut *rsinfo, first_call)
+    } else if allowed & pg_sys::SetFunctionReturnMode_SFRM_Materialize != 0
+        && !(*rsinfo).expectedDesc.is_null() {
+        materialize(fcinfo, &mut *rsinfo, first_call);
+        None
+    } else {
+        set_not_allowed()
+    }
+}
+
+fn set_not_allowed() -> ! {
+    crate::ereport!(Error, SqlState::FeatureNotSupported,
+        "set-valued function called in context that cannot accept a set");
+    unreachable!()
+}
+
+unsafe fn value_per_call<T, I, F>(
+    fcinfo: &mut FunctionCallInfoData,
+    rsinfo: &mut pg_sys::ReturnSetInfo,
+    first_call: F,
+) -> Option<Datum>
+where
+    T: ToOptionalDatum,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: 'static,
+    F: FnOnce(&mut FunctionCallInfoData) -> I,
+{
+    if (*fcinfo.flinfo()).fn_extra.is_null() {
+        let funcctx = crate::guard_pg(|| init_MultiFuncCall(fcinfo));
+        let iter = in_context((*funcctx).multi_call_memory_ctx, || {
+            Box::new(first_call(fcinfo).into_iter())
+        });
+        (*funcctx).user_fctx = Box::into_raw(iter) as *mut c_void;
+        // callbacks are run most-recently-registered first, so this runs
+        // before funcapi's own shutdown callback deletes the memory context
+        crate::guard_pg(|| RegisterExprContextCallback(
+            rsinfo.econtext,
+            Some(drop_iterator::<I::IntoIter>),
+            funcctx as Datum,
+        ));
+    }
+
+    let funcctx = per_MultiFuncCall(fcinfo);
+    let iter = &mut *((*funcctx).user_fctx as *mut I::IntoIter);
+    // next() may allocate state the iterator keeps across calls, so it runs
+    // in the multi-call context too; only the datum the value converts to is
+    // allocated in the per-call context, as it only needs to live until the
+    // next call
+    let next = in_context((*funcctx).multi_call_memory_ctx, || iter.next());
+    match next {
+        Some(value) => {
+            (*funcctx).call_cntr += 1;
+            rsinfo.isDone = pg_sys::ExprDoneCond_ExprMultipleResult;
+            value.to_optional_datum_as(&mut RowType::result(
+                fcinfo,
+                &mut (*funcctx).tuple_desc,
+                (*funcctx).multi_call_memory_ctx,
+            ))
+        },
+        None => {
+            crate::guard_pg(|| UnregisterExprContextCallback(
+                rsinfo.econtext,
+                Some(drop_iterator::<I::IntoIter>),
+                funcctx as Datum,
+            ));
+            drop_iterator::<I::IntoIter>(funcctx as Datum);
+            crate::guard_pg(|| end_MultiFuncCall(fcinfo, funcctx));
+            rsinfo.isDone = pg_sys::ExprDoneCond_ExprEndResult;
+            None
+        },
+    }
+}
+
+/// drop the iterator stored in the `FuncCallContext` passed as `arg`
+unsafe extern "C" fn drop_iterator<I>(arg: Datum) {
+    let funcctx = arg as *mut FuncCallContext;
+    let iter = std::mem::replace(&mut (*funcctx).user_fctx, ptr::null_mut()) as *mut I;
+    if iter.is_null() {
+        return
+    }
+    // this may be called directly by postgres, so we must not unwind out
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        in_context((*funcctx).multi_call_memory_ctx, || drop(Box::from_raw(iter)))
+    }));
+    if let Err(err) = result {
+        crate::handle_unwind(err)
+    }
+}
+
+unsafe fn materialize<T, I, F>(
+    fcinfo: &mut FunctionCallInfoData,
+    rsinfo: &mut pg_sys::ReturnSetInfo,
+    first_call: F,
+)
+where
+    T: ToOptionalDatum,
+    I: IntoIterator<Item = T>,
+    I::IntoIter: 'static,
+    F: FnOnce(&mut FunctionCallInfoData) -> I,
+{
+    let desc = rsinfo.expectedDesc;
+    let natts = (*desc).natts as usize;
+    let rows = composite::returns_composite(fcinfo);
+    if !rows && natts != 1 {
+        crate::ereport!(Error, SqlState::DatatypeMismatch,
+            "function returning a set of scalars called in a context expecting {} columns",
+            natts);
+    }
+
+    let per_query = (*rsinfo.econtext).ecxt_per_query_memory;
+    let store = in_context(per_query, || {
+        crate::guard_pg(|| pg_sys::tuplestore_begin_heap(
+            rsinfo.allowedModes & pg_sys::SetFunctionReturnMode_SFRM_Materialize_Random as c_int != 0,
+            false,
+            work_mem,
+        ))
+    });
+    // rows are stored as their columns, without building them first
+    let mut row_type = RowType::of_desc(desc);
+    let (mut values, mut nulls) = (vec![0; natts], vec![true; natts]);
+    for value in first_call(fcinfo) {
+        if rows {
+            value.to_optional_columns(&mut row_type, &mut values, &mut nulls);
+        } else {
+            let datum = value.to_optional_datum();
+            values[0] = datum.unwrap_or(0);
+            nulls[0] = datum.is_none();
+        }
+        crate::guard_pg(|| pg_sys::tuplestore_putvalues(
+            store,
+            desc,
+            values.as_mut_ptr(),
+            nulls.as_mut_ptr(),
+        ));
+    }
+
+    rsinfo.returnMode = pg_sys::SetFunctionReturnMode_SFRM_Materialize;
+    rsinfo.setResult = store;
+    rsinfo.setDesc = desc;
+}
@@ -0,0 +1,259 @@
+//! support for window functions, see `pg_window_fn!` and `windowapi.h`
+//!
+//! Window functions are not passed their arguments through the `fcinfo`;
+//! instead they read them through the [`WindowObject`], either for the current
+//! row or for any other row of the partition or frame.
+
+use std::{
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    os::raw::c_int,
+};
+
+use crate::{
+    datum::FromOptionalDatum,
+    elog::{Level::Error, SqlState},
+    guard_pg,
+    palloc::in_context,
+    pg_sys::{self, Datum, Size},
+    FcInfo,
+    FunctionCallInfoData,
+};
+
+// WindowObject from windowapi.h, which the bindings do not include
+type RawWindowObject = *mut pg_sys::WindowObjectData;
+
+const WINDOW_SEEK_CURRENT: c_int = 0;
+const WINDOW_SEEK_HEAD: c_int = 1;
+const WINDOW_SEEK_TAIL: c_int = 2;
+
+extern "C" {
+    fn WinGetPartitionLocalMemory(winobj: RawWindowObject, sz: Size) -> *mut u8;
+    fn WinGetCurrentPosition(winobj: RawWindowObject) -> i64;
+    fn WinGetPartitionRowCount(winobj: RawWindowObject) -> i64;
+    fn WinSetMarkPosition(winobj: RawWindowObject, markpos: i64);
+    fn WinRowsArePeers(winobj: RawWindowObject, pos1: i64, pos2: i64) -> bool;
+    fn WinGetFuncArgInPartition(
+        winobj: RawWindowObject,
+        argno: c_int,
+        relpos: c_int,
+        seektype: c_int,
+        set_mark: bool,
+        isnull: *mut bool,
+        isout: *mut bool,
+    ) -> Datum;
+    fn WinGetFuncArgInFrame(
+        winobj: RawWindowObject,
+        argno: c_int,
+        relpos: c_int,
+        seektype: c_int,
+        set_mark: bool,
+        isnull: *mut bool,
+        isout: *mut bool,
+    ) -> Datum;
+    fn WinGetFuncArgCurrent(winobj: RawWindowObject, argno: c_int, isnull: *mut bool) -> Datum;
+}
+
+/// where a relative position in a partition or frame is counted from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Seek {
+    /// the current row
+    Current,
+    /// the first row
+    Head,
+    /// the last row
+    Tail,
+}
+
+impl Seek {
+    fn seek_type(self) -> c_int {
+        match self {
+            Seek::Current => WINDOW_SEEK_CURRENT,
+            Seek::Head => WINDOW_SEEK_HEAD,
+            Seek::Tail => WINDOW_SEEK_TAIL,
+        }
+    }
+}
+
+/// The window a window function is being evaluated over, i.e.
+/// `PG_WINDOW_OBJECT()`.
+///
+/// Argument getters return `None` for rows outside of the partition or frame;
+/// a `NULL` argument must be read as an `Option`, like the arguments of any
+/// other function.
+pub struct WindowObject<'a> {
+    ptr: RawWindowObject,
+    _fcinfo: PhantomData<&'a FunctionCallInfoData>,
+}
+
+/// the memory returned by `WinGetPartitionLocalMemory()`, which is zeroed
+/// when allocated, so `initialized` starts out `false`
+#[repr(C)]
+struct PartitionState<S> {
+    initialized: bool,
+    value: MaybeUninit<S>,
+}
+
+impl<'a> WindowObject<'a> {
+    /// The window of the window function call `fcinfo`.
+    ///
+    /// # Safety
+    /// `fcinfo` must be the `fcinfo` of the current function call
+    pub unsafe fn from_fcinfo(fcinfo: &'a FunctionCallInfoData) -> Self {
+        let ptr = fcinfo.context() as RawWindowObject;
+        if ptr.is_null() || (*fcinfo.context()).type_ != pg_sys::NodeTag_T_WindowObjectData {
+            crate::ereport!(Error, SqlState::FeatureNotSupported,
+                "window function called in non-window context");
+        }
+        WindowObject { ptr, _fcinfo: PhantomData }
+    }
+
+    pub fn as_ptr(&self) -> *mut pg_sys::WindowObjectData {
+        self.ptr
+    }
+
+    /// the position of the current row within the partition, counting from 0
+    pub fn current_position(&self) -> i64 {
+        unsafe { guard_pg(|| WinGetCurrentPosition(self.ptr)) }
+    }
+
+    /// the number of rows in the partition; this requires reading the whole
+    /// partition
+    pub fn partition_row_count(&self) -> i64 {
+        unsafe { guard_pg(|| WinGetPartitionRowCount(self.ptr)) }
+    }
+
+    /// Allow the rows before `position` to be discarded; they may not be read
+    /// afterwards.
+    pub fn set_mark_position(&self, position: i64) {
+        unsafe { guard_pg(|| WinSetMarkPosition(self.ptr, position)) }
+    }
+
+    /// whether the rows at `position1` and `position2` sort equal according to
+    /// the window's `ORDER BY`
+    pub fn rows_are_peers(&self, position1: i64, position2: i64) -> bool {
+        unsafe { guard_pg(|| WinRowsArePeers(self.ptr, position1, position2)) }
+    }
+
+    /// The argument `argno` for the current row, which remains valid for the
+    /// rest of the call.
+    pub fn get_arg_current<T: FromOptionalDatum<'a>>(&self, argno: usize) -> T {
+        let datum = self.current_arg(argno);
+        unsafe { convert(argno, datum) }
+    }
+
+    /// The argument `argno` for the row `offset` rows after `seek` within the
+    /// partition, or `None` if there is no such row. With `set_mark` the rows
+    /// before it may be discarded, see `set_mark_position`. By-reference
+    /// values may point into the row, which is replaced by the next read, so
+    /// they borrow the `WindowObject` until then.
+    pub fn get_arg_in_partition<'s, T: FromOptionalDatum<'s>>(
+        &'s mut self,
+        argno: usize,
+        offset: i32,
+        seek: Seek,
+        set_mark: bool,
+    ) -> Option<T> {
+        let mut is_null = false;
+        let mut is_out = false;
+        let datum = unsafe {
+            guard_pg(|| WinGetFuncArgInPartition(
+                self.ptr,
+                argno as c_int,
+                offset,
+                seek.seek_type(),
+                set_mark,
+                &mut is_null,
+                &mut is_out,
+            ))
+        };
+        if is_out {
+            return None
+        }
+        Some(unsafe { convert(argno, if is_null { None } else { Some(datum) }) })
+    }
+
+    /// The argument `argno` for the row `offset` rows after `seek` within the
+    /// window frame of the current row, or `None` if there is no such row.
+    /// `Seek::Current` is not allowed, as the current row need not be in its
+    /// frame. Like `get_arg_in_partition` the value borrows the
+    /// `WindowObject` until the next read.
+    pub fn get_arg_in_frame<'s, T: FromOptionalDatum<'s>>(
+        &'s mut self,
+        argno: usize,
+        offset: i32,
+        seek: Seek,
+        set_mark: bool,
+    ) -> Option<T> {
+        let mut is_null = false;
+        let mut is_out = false;
+        let datum = unsafe {
+            guard_pg(|| WinGetFuncArgInFrame(
+                self.ptr,
+                argno as c_int,
+                offset,
+                seek.seek_type(),
+                set_mark,
+                &mut is_null,
+                &mut is_out,
+            ))
+        };
+        if is_out {
+            return None
+        }
+        Some(unsafe { convert(argno, if is_null { None } else { Some(datum) }) })
+    }
+
+    /// Run `update` on the state of the current partition, which is created
+    /// by `init` the first time it is requested within each partition. Both
+    /// run in the partition's memory context, rather than the per-row one, so
+    /// anything the state allocates lives as long as the partition; the state
+    /// is freed along with the partition without running its destructor.
+    pub fn partition_state<S, T>(
+        &mut self,
+        init: impl FnOnce() -> S,
+        update: impl FnOnce(&mut S) -> T,
+    ) -> T {
+        // palloc only guarantees MAXALIGN
+        assert!(mem::align_of::<S>() <= mem::align_of::<u64>(),
+            "partition state is over-aligned");
+        unsafe {
+            let state = guard_pg(|| WinGetPartitionLocalMemory(
+                self.ptr,
+                mem::size_of::<PartitionState<S>>() as Size,
+            )) as *mut PartitionState<S>;
+            in_context(pg_sys::macros::GetMemoryChunkContext(state.cast()), || {
+                let state = &mut *state;
+                if !state.initialized {
+                    state.value = MaybeUninit::new(init());
+                    state.initialized = true;
+                }
+                update(&mut *state.value.as_mut_ptr())
+            })
+        }
+    }
+
+    /// the arguments of the current row, in order
+    #[doc(hidden)]
+    pub fn current_args(&self, nargs: usize) -> impl '_ + Iterator<Item = Option<Datum>> {
+        (0..nargs).map(move |argno| self.current_arg(argno))
+    }
+
+    fn current_arg(&self, argno: usize) -> Option<Datum> {
+        let mut is_null = false;
+        let datum = unsafe {
+            guard_pg(|| WinGetFuncArgCurrent(self.ptr, argno as c_int, &mut is_null))
+        };
+        if is_null { None } else { Some(datum) }
+    }
+}
+
+/// # Safety
+/// `datum` must be valid for the lifetime `T` borrows for
+unsafe fn convert<'a, T: FromOptionalDatum<'a>>(argno: usize, datum: Option<Datum>) -> T {
+    T::try_from_optional_datum(datum).unwrap_or_else(|| {
+        crate::ereport!(Error, SqlState::NullValueNotAllowed,
+            "NULL value for non-nullable window function argument {}", argno);
+        unreachable!()
+    })
+}